@@ -0,0 +1,109 @@
+/// Bloom filter over the keys of an sstable.
+///
+/// Each key sets `hashes` bits, derived from a single 64-bit FNV-1a hash of
+/// the key by mixing it with each hash's index. The hash is fixed, since
+/// filters are stored in files.
+pub(crate) struct BloomFilter {
+    bits: Vec<u8>,
+    hashes: u8,
+}
+
+fn fnv1a(key: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &byte in key {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// SplitMix64 finalizer, deriving independent-looking values from the hash
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+impl BloomFilter {
+    /// Build a filter for the keys with the given false-positive rate, or
+    /// `None` if the rate doesn't require any bits.
+    pub(crate) fn build<'a, I: ExactSizeIterator<Item = &'a [u8]>>(keys: I, false_positive_rate: f64) -> Option<BloomFilter> {
+        let (len, hashes) = Self::dimensions(keys.len(), false_positive_rate)?;
+        let mut filter = BloomFilter {
+            bits: vec![0; len],
+            hashes,
+        };
+        for key in keys {
+            for bit in filter.bit_indices(key) {
+                filter.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        Some(filter)
+    }
+
+    /// Size in bytes and number of hashes of the filter for `count` keys.
+    pub(crate) fn dimensions(count: usize, false_positive_rate: f64) -> Option<(usize, u8)> {
+        if count == 0 || false_positive_rate >= 1.0 {
+            return None;
+        }
+        let ln2 = std::f64::consts::LN_2;
+        let bits_per_key = -false_positive_rate.ln() / (ln2 * ln2);
+        let len = (count as f64 * bits_per_key / 8.0).ceil() as usize;
+        let hashes = (bits_per_key * ln2).round().clamp(1.0, 30.0) as u8;
+        Some((len.max(1), hashes))
+    }
+
+    pub(crate) fn from_parts(bits: Vec<u8>, hashes: u8) -> BloomFilter {
+        BloomFilter { bits, hashes }
+    }
+
+    pub(crate) fn bits(&self) -> &[u8] {
+        &self.bits
+    }
+
+    pub(crate) fn hashes(&self) -> u8 {
+        self.hashes
+    }
+
+    /// Expected false-positive rate of the filter holding `count` keys.
+    pub(crate) fn false_positive_rate(&self, count: usize) -> f64 {
+        let bits = self.bits.len() as f64 * 8.0;
+        let hashes = self.hashes as f64;
+        (1.0 - (-hashes * count as f64 / bits).exp()).powf(hashes)
+    }
+
+    /// Returns `false` if the key is definitely not in the set.
+    pub(crate) fn may_contain(&self, key: &[u8]) -> bool {
+        self.bit_indices(key).all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    fn bit_indices(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let hash = fnv1a(key);
+        let bits = self.bits.len() as u64 * 8;
+        (0..self.hashes as u64).map(move |i| (mix(hash.wrapping_add(i.wrapping_mul(0x9e3779b97f4a7c15))) % bits) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BloomFilter;
+
+    #[test]
+    fn test_bloom_filter() {
+        let keys: Vec<Vec<u8>> = (0..1000u32).map(|i| format!("key{}", i).into_bytes()).collect();
+        let filter = BloomFilter::build(keys.iter().map(|k| k as &[u8]), 0.01).unwrap();
+        assert_eq!(filter.hashes(), 7);
+        assert_eq!(filter.bits().len(), 1199);
+        assert!((filter.false_positive_rate(keys.len()) - 0.01).abs() < 0.001);
+        for key in &keys {
+            assert!(filter.may_contain(key));
+        }
+        let false_positives = (0..10000u32)
+            .filter(|i| filter.may_contain(format!("other{}", i).as_bytes()))
+            .count();
+        assert!(false_positives < 200, "{} false positives", false_positives);
+
+        assert!(BloomFilter::build(keys.iter().map(|k| k as &[u8]), 1.0).is_none());
+        assert!(BloomFilter::build([].into_iter(), 0.01).is_none());
+    }
+}
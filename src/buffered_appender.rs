@@ -0,0 +1,148 @@
+use std::io::Error as IoError;
+use crate::Append;
+
+/// Wraps an appender, coalescing small appends into larger writes.
+///
+/// Data is written when the buffer is full, on [`Append::flush`] and
+/// [`Append::sync`], and when the appender is dropped. Like unsynced writes
+/// to a file, buffered data is lost if the process crashes.
+pub struct BufferedAppender<A: Append> {
+    inner: A,
+    buffer: Vec<u8>,
+    capacity: usize,
+}
+
+impl<A: Append> BufferedAppender<A> {
+    pub fn new(inner: A, capacity: usize) -> BufferedAppender<A> {
+        BufferedAppender {
+            inner,
+            buffer: Vec::with_capacity(capacity),
+            capacity,
+        }
+    }
+}
+
+impl<A: Append> Append for BufferedAppender<A> {
+    fn append(&mut self, buffer: &[u8]) -> Result<(), IoError> {
+        if self.buffer.len() + buffer.len() > self.capacity {
+            self.flush()?;
+        }
+        if buffer.len() >= self.capacity {
+            self.inner.append(buffer)
+        } else {
+            self.buffer.extend_from_slice(buffer);
+            Ok(())
+        }
+    }
+
+    fn truncate(&mut self) -> Result<(), IoError> {
+        self.buffer.clear();
+        self.inner.truncate()
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        if !self.buffer.is_empty() {
+            self.inner.append(&self.buffer)?;
+            self.buffer.clear();
+        }
+        self.inner.flush()
+    }
+
+    fn sync(&mut self) -> Result<(), IoError> {
+        self.flush()?;
+        self.inner.sync()
+    }
+}
+
+impl<A: Append> Drop for BufferedAppender<A> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Error as IoError;
+
+    use crate::Append;
+    use super::BufferedAppender;
+
+    #[derive(Default)]
+    struct VecAppender {
+        data: Vec<u8>,
+        writes: usize,
+        syncs: usize,
+    }
+
+    impl Append for VecAppender {
+        fn append(&mut self, buffer: &[u8]) -> Result<(), IoError> {
+            self.data.extend_from_slice(buffer);
+            self.writes += 1;
+            Ok(())
+        }
+
+        fn truncate(&mut self) -> Result<(), IoError> {
+            self.data.clear();
+            Ok(())
+        }
+
+        fn sync(&mut self) -> Result<(), IoError> {
+            self.syncs += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_buffered_appender() {
+        let mut inner = VecAppender::default();
+        let mut expected = Vec::new();
+        {
+            let mut appender = BufferedAppender::new(&mut inner, 4096);
+            for i in 0..1000u32 {
+                let record = format!("record{:04}", i);
+                appender.append(record.as_bytes()).unwrap();
+                expected.extend_from_slice(record.as_bytes());
+            }
+            // Larger than the buffer
+            appender.append(&[b'x'; 5000]).unwrap();
+            expected.extend_from_slice(&[b'x'; 5000]);
+            appender.append(b"end").unwrap();
+            expected.extend_from_slice(b"end");
+        }
+        assert_eq!(inner.data, expected);
+        assert!(inner.writes <= 6);
+
+        // Truncation discards the buffer
+        let mut appender = BufferedAppender::new(&mut inner, 4096);
+        appender.append(b"lost").unwrap();
+        appender.truncate().unwrap();
+        appender.append(b"kept").unwrap();
+        drop(appender);
+        assert_eq!(inner.data, b"kept");
+    }
+
+    #[test]
+    fn test_sync() {
+        let mut inner = VecAppender::default();
+        let mut appender = BufferedAppender::new(&mut inner, 4096);
+        appender.append(b"abc").unwrap();
+        appender.flush().unwrap();
+        appender.append(b"def").unwrap();
+        appender.sync().unwrap();
+        appender.append(b"ghi").unwrap();
+        std::mem::forget(appender);
+        assert_eq!(inner.data, b"abcdef");
+        assert_eq!(inner.syncs, 1);
+    }
+
+    #[test]
+    fn test_unbuffered() {
+        let mut inner = VecAppender::default();
+        let mut appender = BufferedAppender::new(&mut inner, 0);
+        appender.append(b"abc").unwrap();
+        appender.append(b"def").unwrap();
+        drop(appender);
+        assert_eq!(inner.data, b"abcdef");
+        assert_eq!(inner.writes, 2);
+    }
+}
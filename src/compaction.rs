@@ -0,0 +1,275 @@
+use std::ops::Range;
+
+use crate::SSTableInfo;
+
+/// Chooses which sstables to merge, see
+/// [`Database::compact_with`](crate::Database::compact_with).
+pub trait CompactionStrategy {
+    /// Returns the runs of tables to merge, each into a single table.
+    ///
+    /// `tables` are ordered from most to least recent, and each run is a
+    /// range of indices in it. Only adjacent tables can be merged, so that
+    /// the merged table can take their place in that order. Runs must not
+    /// overlap and must be in increasing order.
+    fn select(&self, tables: &[SSTableInfo]) -> Vec<Range<usize>>;
+}
+
+/// Size-tiered compaction, merging tables of similar sizes.
+///
+/// Adjacent tables are grouped into buckets, a table joining the bucket if
+/// its size is within `bucket_ratio` of the bucket's average size. Buckets
+/// of at least `min_bucket_size` tables are merged, in runs of at most
+/// `max_bucket_size` tables. Unlike leveled compaction with
+/// [`Database::compact`](crate::Database::compact), this doesn't rewrite
+/// large tables for every small one, which suits write-heavy workloads.
+///
+/// Tables are only grouped with their neighbors in recency order, not
+/// sorted by size first, since a table can't be merged with an older one
+/// past a table in between.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SizeTieredStrategy {
+    pub min_bucket_size: usize,
+    pub max_bucket_size: usize,
+    pub bucket_ratio: f64,
+}
+
+impl Default for SizeTieredStrategy {
+    fn default() -> SizeTieredStrategy {
+        SizeTieredStrategy {
+            min_bucket_size: 4,
+            max_bucket_size: 32,
+            bucket_ratio: 1.5,
+        }
+    }
+}
+
+impl SizeTieredStrategy {
+    fn fits(&self, bucket_bytes: u64, bucket_len: usize, bytes: u64) -> bool {
+        let average = bucket_bytes as f64 / bucket_len as f64;
+        let bytes = bytes as f64;
+        bytes <= average * self.bucket_ratio && bytes * self.bucket_ratio >= average
+    }
+}
+
+impl CompactionStrategy for SizeTieredStrategy {
+    fn select(&self, tables: &[SSTableInfo]) -> Vec<Range<usize>> {
+        let mut runs = Vec::new();
+        let mut add_bucket = |bucket: Range<usize>| {
+            let mut start = bucket.start;
+            while start < bucket.end {
+                let end = (start + self.max_bucket_size.max(1)).min(bucket.end);
+                // A single table is not worth rewriting
+                if end - start >= self.min_bucket_size.max(2) {
+                    runs.push(start..end);
+                }
+                start = end;
+            }
+        };
+
+        let mut start = 0;
+        let mut bucket_bytes = 0;
+        for (i, table) in tables.iter().enumerate() {
+            if i > start && !self.fits(bucket_bytes, i - start, table.bytes) {
+                add_bucket(start..i);
+                start = i;
+                bucket_bytes = 0;
+            }
+            bucket_bytes += table.bytes;
+        }
+        add_bucket(start..tables.len());
+        runs
+    }
+}
+
+/// Compaction of the table with the most tombstones, to reclaim the space
+/// of deleted entries quickly.
+///
+/// Tables are scored by the fraction of their entries that are tombstones,
+/// from the statistics recorded when they were written. The table with the
+/// highest fraction, if at least `min_tombstone_ratio`, is merged with the
+/// next older table, dropping the entries its tombstones delete. If it is
+/// the least recent table, it is rewritten alone, dropping the tombstones.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TombstoneStrategy {
+    pub min_tombstone_ratio: f64,
+}
+
+impl Default for TombstoneStrategy {
+    fn default() -> TombstoneStrategy {
+        TombstoneStrategy {
+            min_tombstone_ratio: 0.2,
+        }
+    }
+}
+
+impl CompactionStrategy for TombstoneStrategy {
+    fn select(&self, tables: &[SSTableInfo]) -> Vec<Range<usize>> {
+        let ratio = |table: &SSTableInfo| match table.stats {
+            Some(stats) if table.entries > 0 => stats.tombstones as f64 / table.entries as f64,
+            _ => 0.0,
+        };
+        let densest = tables.iter()
+            .enumerate()
+            .map(|(i, table)| (i, ratio(table)))
+            .filter(|&(_, ratio)| ratio > 0.0 && ratio >= self.min_tombstone_ratio)
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        densest.map(|(i, _)| i..(i + 2).min(tables.len())).into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use crate::{CompactionStrategy, Database, DirectoryStorage, SizeTieredStrategy, TombstoneStrategy};
+    use crate::tests::FailDelete;
+
+    #[test]
+    fn test_size_tiered_compaction() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+
+        // Four large tables, a small one, a large one, four small ones, and
+        // one holding a deletion
+        fn flush(db: &mut Database<DirectoryStorage>, table: u32, count: u32) {
+            for i in 0..count {
+                db.put(format!("key{:04}", i * 11 + table).as_bytes(), format!("value{}", table).as_bytes()).unwrap();
+            }
+            db.flush().unwrap();
+        }
+        for table in 0..4 {
+            flush(&mut db, table, 100);
+        }
+        flush(&mut db, 4, 10);
+        flush(&mut db, 5, 100);
+        for table in 6..10 {
+            flush(&mut db, table, 10);
+        }
+        db.delete(b"key0000").unwrap();
+        db.flush().unwrap();
+        assert_eq!(db.sstable_info().len(), 11);
+
+        let strategy = SizeTieredStrategy {
+            min_bucket_size: 3,
+            ..Default::default()
+        };
+        assert_eq!(db.compact_with(&strategy).unwrap(), 2);
+
+        // The small tables and the large ones were merged separately, each
+        // bucket staying in level 1 in place of its most recent table
+        let tables = db.sstable_info();
+        let ids: Vec<_> = tables.iter().map(|table| table.id).collect();
+        assert_eq!(ids, vec![(1, 10), (1, 9), (1, 5), (1, 4), (1, 3)]);
+        assert_eq!(tables[1].entries, 40);
+        assert_eq!(tables[4].entries, 400);
+
+        // The most recent values are kept
+        for table in 0..10 {
+            let count = if table == 4 || table >= 6 { 10 } else { 100 };
+            for i in [1, count - 1] {
+                let key = format!("key{:04}", i * 11 + table);
+                assert_eq!(db.get(key.as_bytes()).unwrap(), Some(format!("value{}", table).into_bytes()));
+            }
+        }
+        assert_eq!(db.get(b"key0000").unwrap(), None);
+
+        // Nothing left to merge
+        assert_eq!(db.compact_with(&strategy).unwrap(), 0);
+        assert_eq!(db.sstable_info().len(), 5);
+    }
+
+    #[test]
+    fn test_tombstone_compaction() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        for i in 0..100 {
+            db.put(format!("key{:03}", i).as_bytes(), b"value").unwrap();
+        }
+        db.flush().unwrap();
+
+        // Tables deleting 10%, 60% and 30% of their keys
+        for (table, deleted) in [(1, 1), (2, 6), (3, 3)] {
+            for i in 0..10 {
+                let key = format!("key{:03}", table * 20 + i);
+                if i < deleted {
+                    db.delete(key.as_bytes()).unwrap();
+                } else {
+                    db.put(key.as_bytes(), format!("value{}", table).as_bytes()).unwrap();
+                }
+            }
+            db.flush().unwrap();
+        }
+        let tables = db.sstable_info();
+        let ids: Vec<_> = tables.iter().map(|table| table.id).collect();
+        assert_eq!(ids, vec![(1, 3), (1, 2), (1, 1), (1, 0)]);
+
+        // The densest table is merged with the next older one
+        let runs = TombstoneStrategy::default().select(&tables);
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0], 1..3);
+        assert_eq!(db.compact_with(&TombstoneStrategy::default()).unwrap(), 1);
+        let tables = db.sstable_info();
+        let ids: Vec<_> = tables.iter().map(|table| table.id).collect();
+        assert_eq!(ids, vec![(1, 3), (1, 2), (1, 0)]);
+        assert_eq!(tables[1].entries, 20);
+        assert_eq!(tables[1].stats.unwrap().tombstones, 7);
+
+        // Then the next densest, down to the least recent table, whose
+        // tombstones can be dropped
+        assert_eq!(db.compact_with(&TombstoneStrategy::default()).unwrap(), 1);
+        assert_eq!(db.compact_with(&TombstoneStrategy::default()).unwrap(), 1);
+        let tables = db.sstable_info();
+        assert_eq!(tables.len(), 1);
+        assert_eq!(tables[0].stats.unwrap().tombstones, 0);
+        assert_eq!(tables[0].entries, 90);
+        assert_eq!(db.compact_with(&TombstoneStrategy::default()).unwrap(), 0);
+
+        for (table, deleted) in [(1, 1), (2, 6), (3, 3)] {
+            for i in 0..10 {
+                let key = format!("key{:03}", table * 20 + i);
+                let expected = if i < deleted { None } else { Some(format!("value{}", table).into_bytes()) };
+                assert_eq!(db.get(key.as_bytes()).unwrap(), expected);
+            }
+        }
+        assert_eq!(db.get(b"key000").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_tombstone_compaction_crash() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = FailDelete(DirectoryStorage::new(dir.path()).unwrap(), "1-0.sst");
+        let mut db = Database::open(storage).unwrap();
+        for i in 0..10 {
+            db.put(format!("key{}", i).as_bytes(), b"value").unwrap();
+        }
+        db.flush().unwrap();
+        for i in 0..6 {
+            db.delete(format!("key{}", i).as_bytes()).unwrap();
+        }
+        db.flush().unwrap();
+
+        // The crash happens after the merged table replaced the tombstones,
+        // before the older table is deleted
+        assert!(db.compact_with(&TombstoneStrategy::default()).is_err());
+        drop(db);
+
+        let check = |db: &mut Database<DirectoryStorage>| {
+            for i in 0..10 {
+                let expected = if i < 6 { None } else { Some(b"value".to_vec()) };
+                assert_eq!(db.get(format!("key{}", i).as_bytes()).unwrap(), expected);
+            }
+        };
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        check(&mut db);
+
+        // Compacting again completes it
+        assert_eq!(db.compact_with(&TombstoneStrategy::default()).unwrap(), 1);
+        let tables = db.sstable_info();
+        assert_eq!(tables.len(), 1);
+        assert_eq!(tables[0].entries, 4);
+        check(&mut db);
+    }
+}
@@ -1,10 +1,11 @@
 use std::fs::File;
 use std::io::{Error as IoError, ErrorKind as IoErrorKind, Seek, SeekFrom, Write};
 use std::path::PathBuf;
-use crate::{Append, ReadAt, Storage};
+use crate::{Append, BufferedAppender, ReadAt, Storage};
 
 pub struct DirectoryStorage {
     path: PathBuf,
+    append_buffer_size: usize,
 }
 
 pub struct DirectoryFileAppender(File);
@@ -18,6 +19,10 @@ impl Append for DirectoryFileAppender {
         self.0.seek(SeekFrom::Start(0))?;
         self.0.set_len(0)
     }
+
+    fn sync(&mut self) -> Result<(), IoError> {
+        self.0.sync_data()
+    }
 }
 
 impl DirectoryStorage {
@@ -29,7 +34,18 @@ impl DirectoryStorage {
                 "Not a directory",
             ));
         }
-        Ok(DirectoryStorage { path })
+        Ok(DirectoryStorage {
+            path,
+            append_buffer_size: 0,
+        })
+    }
+
+    /// Buffer appends to the WAL, writing them out in chunks of this size.
+    ///
+    /// Buffered writes are lost if the process crashes.
+    pub fn with_append_buffer(mut self, size: usize) -> DirectoryStorage {
+        self.append_buffer_size = size;
+        self
     }
 }
 
@@ -39,11 +55,15 @@ impl ReadAt for FileReader {
     fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<(), IoError> {
         std::os::unix::fs::FileExt::read_exact_at(&self.0, buf, offset)
     }
+
+    fn size(&self) -> Result<u64, IoError> {
+        Ok(self.0.metadata()?.len())
+    }
 }
 
 impl Storage for DirectoryStorage {
     type Reader = FileReader;
-    type Appender = DirectoryFileAppender;
+    type Appender = BufferedAppender<DirectoryFileAppender>;
 
     fn read(&self, key: &str) -> Result<FileReader, IoError> {
         Ok(FileReader(File::open(self.path.join(key))?))
@@ -54,8 +74,8 @@ impl Storage for DirectoryStorage {
     }
 
     fn append(&self, key: &str) -> Result<Self::Appender, IoError> {
-        let file = File::options().create(true).write(true).open(self.path.join(key))?;
-        Ok(DirectoryFileAppender(file))
+        let file = File::options().create(true).append(true).open(self.path.join(key))?;
+        Ok(BufferedAppender::new(DirectoryFileAppender(file), self.append_buffer_size))
     }
 
     fn delete(&self, key: &str) -> Result<(), IoError> {
@@ -85,4 +105,8 @@ impl Storage for DirectoryStorage {
         }
         Ok(result)
     }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), IoError> {
+        std::fs::rename(self.path.join(from), self.path.join(to))
+    }
 }
@@ -0,0 +1,153 @@
+use std::io::Error as IoError;
+use std::iter::Peekable;
+
+/// Which keys [`merge_join`] emits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JoinKind {
+    /// Keys present in either input.
+    Union,
+    /// Keys present in both inputs.
+    Intersection,
+}
+
+/// A key, and its value in the left and right inputs of a [`merge_join`].
+pub type JoinedEntry = (Vec<u8>, Option<Vec<u8>>, Option<Vec<u8>>);
+
+/// Joins two sorted streams of entries by key, such as two
+/// [`TryRangeIterator`](crate::TryRangeIterator)s.
+///
+/// Entries are emitted in key order, with the value from each side if it has
+/// the key. With [`JoinKind::Intersection`], both values are always present.
+///
+/// ```
+/// # use lsmtree::{merge_join, Database, DirectoryStorage, JoinKind};
+/// # let dir1 = tempdir::TempDir::new("lsmtree-doc").unwrap();
+/// # let dir2 = tempdir::TempDir::new("lsmtree-doc").unwrap();
+/// let mut users = Database::open(DirectoryStorage::new(dir1.path()).unwrap()).unwrap();
+/// let mut active = Database::open(DirectoryStorage::new(dir2.path()).unwrap()).unwrap();
+/// users.put(b"alice", b"Alice").unwrap();
+/// users.put(b"bob", b"Bob").unwrap();
+/// active.put(b"bob", b"").unwrap();
+/// let joined = merge_join(users.try_iter_range(b"a", b"z"), active.try_iter_range(b"a", b"z"), JoinKind::Intersection)
+///     .collect::<Result<Vec<_>, _>>()
+///     .unwrap();
+/// assert_eq!(joined, vec![(b"bob".to_vec(), Some(b"Bob".to_vec()), Some(vec![]))]);
+/// ```
+pub fn merge_join<L, R>(left: L, right: R, kind: JoinKind) -> MergeJoin<L, R>
+where
+    L: Iterator<Item = Result<(Vec<u8>, Vec<u8>), IoError>>,
+    R: Iterator<Item = Result<(Vec<u8>, Vec<u8>), IoError>>,
+{
+    MergeJoin {
+        left: left.peekable(),
+        right: right.peekable(),
+        kind,
+    }
+}
+
+/// Iterator returned by [`merge_join`].
+pub struct MergeJoin<L, R>
+where
+    L: Iterator<Item = Result<(Vec<u8>, Vec<u8>), IoError>>,
+    R: Iterator<Item = Result<(Vec<u8>, Vec<u8>), IoError>>,
+{
+    left: Peekable<L>,
+    right: Peekable<R>,
+    kind: JoinKind,
+}
+
+impl<L, R> Iterator for MergeJoin<L, R>
+where
+    L: Iterator<Item = Result<(Vec<u8>, Vec<u8>), IoError>>,
+    R: Iterator<Item = Result<(Vec<u8>, Vec<u8>), IoError>>,
+{
+    type Item = Result<JoinedEntry, IoError>;
+
+    fn next(&mut self) -> Option<Result<JoinedEntry, IoError>> {
+        loop {
+            // Surface errors from either side first
+            if matches!(self.left.peek(), Some(Err(_))) {
+                return Some(Err(self.left.next().unwrap().unwrap_err()));
+            }
+            if matches!(self.right.peek(), Some(Err(_))) {
+                return Some(Err(self.right.next().unwrap().unwrap_err()));
+            }
+
+            let order = match (self.left.peek(), self.right.peek()) {
+                (None, None) => return None,
+                (Some(_), None) => std::cmp::Ordering::Less,
+                (None, Some(_)) => std::cmp::Ordering::Greater,
+                (Some(Ok((l, _))), Some(Ok((r, _)))) => l.cmp(r),
+                _ => unreachable!(),
+            };
+            let entry = match order {
+                std::cmp::Ordering::Less => {
+                    let (key, value) = self.left.next().unwrap().unwrap();
+                    (key, Some(value), None)
+                }
+                std::cmp::Ordering::Greater => {
+                    let (key, value) = self.right.next().unwrap().unwrap();
+                    (key, None, Some(value))
+                }
+                std::cmp::Ordering::Equal => {
+                    let (key, left) = self.left.next().unwrap().unwrap();
+                    let (_, right) = self.right.next().unwrap().unwrap();
+                    (key, Some(left), Some(right))
+                }
+            };
+            if self.kind == JoinKind::Intersection && (entry.1.is_none() || entry.2.is_none()) {
+                // Stop early once either side is exhausted
+                if self.left.peek().is_none() || self.right.peek().is_none() {
+                    return None;
+                }
+                continue;
+            }
+            return Some(Ok(entry));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Error as IoError;
+
+    use super::{merge_join, JoinKind, JoinedEntry};
+
+    fn input(keys: &[&[u8]], value: &[u8]) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>), IoError>> {
+        keys.iter()
+            .map(|k| Ok((k.to_vec(), value.to_vec())))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    fn e(k: &[u8], l: Option<&[u8]>, r: Option<&[u8]>) -> JoinedEntry {
+        (k.into(), l.map(|v| v.into()), r.map(|v| v.into()))
+    }
+
+    #[test]
+    fn test_merge_join() {
+        let left = || input(&[b"a", b"c", b"d", b"f"], b"l");
+        let right = || input(&[b"b", b"c", b"f", b"g"], b"r");
+
+        assert_eq!(
+            merge_join(left(), right(), JoinKind::Intersection).collect::<Result<Vec<_>, _>>().unwrap(),
+            vec![
+                e(b"c", Some(b"l"), Some(b"r")),
+                e(b"f", Some(b"l"), Some(b"r")),
+            ],
+        );
+        assert_eq!(
+            merge_join(left(), right(), JoinKind::Union).collect::<Result<Vec<_>, _>>().unwrap(),
+            vec![
+                e(b"a", Some(b"l"), None),
+                e(b"b", None, Some(b"r")),
+                e(b"c", Some(b"l"), Some(b"r")),
+                e(b"d", Some(b"l"), None),
+                e(b"f", Some(b"l"), Some(b"r")),
+                e(b"g", None, Some(b"r")),
+            ],
+        );
+        assert_eq!(merge_join(left(), input(&[], b"r"), JoinKind::Intersection).count(), 0);
+        assert_eq!(merge_join(input(&[], b"l"), right(), JoinKind::Union).count(), 4);
+    }
+}
@@ -0,0 +1,207 @@
+use std::io::Error as IoError;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use crate::{Append, ReadAt, Storage};
+
+/// Wraps a storage, adding a delay to every read, write and append.
+///
+/// This simulates a high-latency backend, to test features that are meant to
+/// hide latency. The delay is fixed, plus an optional random jitter from a
+/// seeded generator, so runs are reproducible.
+pub struct LatencyStorage<S: Storage> {
+    inner: S,
+    latency: Arc<Latency>,
+}
+
+struct Latency {
+    delay: Duration,
+    jitter: Duration,
+    // Xorshift state
+    state: AtomicU64,
+}
+
+impl Latency {
+    fn sleep(&self) {
+        let mut delay = self.delay;
+        if !self.jitter.is_zero() {
+            let mut x = self.state.load(Ordering::Relaxed);
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            self.state.store(x, Ordering::Relaxed);
+            delay += Duration::from_nanos(x % self.jitter.as_nanos() as u64);
+        }
+        std::thread::sleep(delay);
+    }
+}
+
+impl<S: Storage> LatencyStorage<S> {
+    pub fn new(inner: S, delay: Duration) -> LatencyStorage<S> {
+        LatencyStorage {
+            inner,
+            latency: Arc::new(Latency {
+                delay,
+                jitter: Duration::ZERO,
+                state: AtomicU64::new(1),
+            }),
+        }
+    }
+
+    /// Add a random delay of up to `jitter` to each call.
+    ///
+    /// The delays are drawn from a generator initialized from `seed`.
+    pub fn with_jitter(self, jitter: Duration, seed: u64) -> LatencyStorage<S> {
+        LatencyStorage {
+            inner: self.inner,
+            latency: Arc::new(Latency {
+                delay: self.latency.delay,
+                jitter,
+                // Xorshift gets stuck on 0
+                state: AtomicU64::new(seed.max(1)),
+            }),
+        }
+    }
+}
+
+pub struct LatencyReader<R: ReadAt> {
+    inner: R,
+    latency: Arc<Latency>,
+}
+
+impl<R: ReadAt> ReadAt for LatencyReader<R> {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<(), IoError> {
+        self.latency.sleep();
+        self.inner.read_exact_at(buf, offset)
+    }
+
+    fn size(&self) -> Result<u64, IoError> {
+        self.inner.size()
+    }
+}
+
+pub struct LatencyAppender<A: Append> {
+    inner: A,
+    latency: Arc<Latency>,
+}
+
+impl<A: Append> Append for LatencyAppender<A> {
+    fn append(&mut self, buffer: &[u8]) -> Result<(), IoError> {
+        self.latency.sleep();
+        self.inner.append(buffer)
+    }
+
+    fn truncate(&mut self) -> Result<(), IoError> {
+        self.inner.truncate()
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.inner.flush()
+    }
+
+    fn sync(&mut self) -> Result<(), IoError> {
+        self.latency.sleep();
+        self.inner.sync()
+    }
+}
+
+impl<S: Storage> Storage for LatencyStorage<S> {
+    type Reader = LatencyReader<S::Reader>;
+    type Appender = LatencyAppender<S::Appender>;
+
+    fn read(&self, key: &str) -> Result<Self::Reader, IoError> {
+        Ok(LatencyReader {
+            inner: self.inner.read(key)?,
+            latency: self.latency.clone(),
+        })
+    }
+
+    fn write(&self, key: &str, value: &[u8]) -> Result<(), IoError> {
+        self.latency.sleep();
+        self.inner.write(key, value)
+    }
+
+    fn append(&self, key: &str) -> Result<Self::Appender, IoError> {
+        Ok(LatencyAppender {
+            inner: self.inner.append(key)?,
+            latency: self.latency.clone(),
+        })
+    }
+
+    fn delete(&self, key: &str) -> Result<(), IoError> {
+        self.inner.delete(key)
+    }
+
+    fn list(&self) -> Result<Vec<String>, IoError> {
+        self.inner.list()
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), IoError> {
+        self.inner.rename(from, to)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+    use std::time::{Duration, Instant};
+    use tempdir::TempDir;
+
+    use crate::{Database, DatabaseOptions, DirectoryStorage};
+    use super::LatencyStorage;
+
+    #[test]
+    fn test_latency_storage() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        // Without bloom filters, so lookups read every table
+        let options = DatabaseOptions::builder().bloom_false_positive_rate(1.0).build();
+        let mut db = Database::open_with_options(storage, options).unwrap();
+        for table in 0..5u32 {
+            db.put(format!("key{}", table).as_bytes(), b"value").unwrap();
+            db.maintain().unwrap();
+        }
+        drop(db);
+
+        let delay = Duration::from_millis(5);
+        let storage = LatencyStorage::new(DirectoryStorage::new(dir.path()).unwrap(), delay)
+            .with_jitter(Duration::from_millis(1), 42);
+        let mut db = Database::open(storage).unwrap();
+
+        let start = Instant::now();
+        assert_eq!(db.get(b"key0").unwrap(), Some(b"value".to_vec()));
+        let sequential = start.elapsed();
+        assert!(sequential >= delay);
+
+        let start = Instant::now();
+        assert_eq!(db.get_parallel(b"key0").unwrap(), Some(b"value".to_vec()));
+        let parallel = start.elapsed();
+        assert!(parallel >= delay);
+        assert!(parallel < sequential);
+    }
+
+    #[test]
+    fn test_get_with_deadline() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        // Without bloom filters, so lookups read every table
+        let options = DatabaseOptions::builder().bloom_false_positive_rate(1.0).build();
+        let mut db = Database::open_with_options(storage, options).unwrap();
+        for table in 0..5u32 {
+            db.put(format!("key{}", table).as_bytes(), b"value").unwrap();
+            db.maintain().unwrap();
+        }
+        drop(db);
+
+        let storage = LatencyStorage::new(DirectoryStorage::new(dir.path()).unwrap(), Duration::from_millis(20));
+        let mut db = Database::open(storage).unwrap();
+
+        // The oldest key needs reads from all the tables
+        let err = db.get_with_deadline(b"key0", Instant::now() + Duration::from_millis(30)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+
+        let deadline = Instant::now() + Duration::from_secs(60);
+        assert_eq!(db.get_with_deadline(b"key0", deadline).unwrap(), Some(b"value".to_vec()));
+        assert_eq!(db.get_with_deadline(b"nonexistent", deadline).unwrap(), None);
+    }
+}
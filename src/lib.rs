@@ -1,26 +1,57 @@
+mod bloom;
+mod buffered_appender;
+mod compaction;
 mod directory_storage;
+mod join;
+mod latency_storage;
 mod mem_table;
+mod memory_storage;
+mod merge;
+mod options;
+mod single_file_storage;
+mod snapshot;
+mod split_storage;
 
 use byteorder::{BigEndian, WriteBytesExt};
-use std::collections::HashSet;
-use std::io::{Cursor, Error as IoError, ErrorKind as IoErrorKind, Write};
-use tracing::info;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Seek, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::OnceLock;
+use std::time::Instant;
+use tracing::{info, warn};
 
+pub use buffered_appender::BufferedAppender;
+pub use compaction::{CompactionStrategy, SizeTieredStrategy, TombstoneStrategy};
 pub use directory_storage::DirectoryStorage;
-use mem_table::MemTable;
-// TODO: SingleFileStorage
+pub use join::{merge_join, JoinKind, JoinedEntry, MergeJoin};
+pub use latency_storage::LatencyStorage;
+pub use memory_storage::MemoryStorage;
+pub use options::{CorruptionPolicy, DatabaseOptions, DatabaseOptionsBuilder, PrefixExtractor, ReplayProgress};
+pub use single_file_storage::{SingleFileStorage, SpaceReport};
+pub use snapshot::Snapshot;
+pub use split_storage::SplitStorage;
+use bloom::BloomFilter;
+use mem_table::{MemTable, MemTableRangeIterator};
+use merge::{merge_sorted_runs, Entry, MergedRuns};
 
 #[derive(Debug)]
 pub enum Error {
     IoError(IoError),
-    InvalidDatabase(String),
+    Corruption(Corruption),
+    ReplayAborted,
+    KeyNotFound,
 }
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Error::IoError(err) => write!(f, "I/O error: {}", err),
-            Error::InvalidDatabase(msg) => write!(f, "{}", msg),
+            Error::Corruption(corruption) => write!(f, "{}", corruption),
+            Error::ReplayAborted => write!(f, "WAL replay aborted"),
+            Error::KeyNotFound => write!(f, "Key not found"),
         }
     }
 }
@@ -29,7 +60,49 @@ impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
             Error::IoError(err) => Some(err),
-            Error::InvalidDatabase(_) => None,
+            Error::Corruption(_) => None,
+            Error::ReplayAborted => None,
+            Error::KeyNotFound => None,
+        }
+    }
+}
+
+/// The ways in which the files in storage can be invalid, see
+/// [`Error::Corruption`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Corruption {
+    /// There are sstables but no WAL.
+    MissingWal,
+    /// A file that is not part of a database was found in storage.
+    UnexpectedFile(String),
+    /// The WAL contains a record that matches its checksum but can't be
+    /// decoded, for example of an unknown type.
+    InvalidWalEntry,
+    /// An sstable name, in storage or in the WAL, is not valid.
+    InvalidTableName(String),
+    /// An sstable can't be opened.
+    BadSstableHeader { name: String, reason: String },
+    /// The WAL was being truncated after writing an sstable which is not
+    /// complete.
+    TruncateWithoutSstable(String),
+    /// The WAL marks the end of an sstable write that was never started.
+    UnmatchedSstableEnd(String),
+    /// The WAL marks the start of an sstable write that is already in
+    /// progress.
+    DuplicateSstableStart(String),
+}
+
+impl std::fmt::Display for Corruption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Corruption::MissingWal => write!(f, "Missing wal"),
+            Corruption::UnexpectedFile(name) => write!(f, "Unexpected file in storage: '{}'", name),
+            Corruption::InvalidWalEntry => write!(f, "Invalid WAL entry"),
+            Corruption::InvalidTableName(name) => write!(f, "Invalid table name '{}'", name),
+            Corruption::BadSstableHeader { name, reason } => write!(f, "Invalid sstable '{}': {}", name, reason),
+            Corruption::TruncateWithoutSstable(name) => write!(f, "WAL truncated after incomplete sstable '{}'", name),
+            Corruption::UnmatchedSstableEnd(name) => write!(f, "End of sstable '{}' without a start in WAL", name),
+            Corruption::DuplicateSstableStart(name) => write!(f, "Duplicate start of sstable '{}' in WAL", name),
         }
     }
 }
@@ -44,6 +117,18 @@ impl From<IoError> for Error {
 pub trait Append {
     fn append(&mut self, buffer: &[u8]) -> Result<(), IoError>;
     fn truncate(&mut self) -> Result<(), IoError>;
+
+    /// Write out any data buffered by the appender.
+    fn flush(&mut self) -> Result<(), IoError> {
+        Ok(())
+    }
+
+    /// Write out buffered data and wait for it to reach durable storage.
+    ///
+    /// This only flushes by default, for storage that is not persisted.
+    fn sync(&mut self) -> Result<(), IoError> {
+        self.flush()
+    }
 }
 
 impl<A: Append> Append for &mut A {
@@ -54,16 +139,29 @@ impl<A: Append> Append for &mut A {
     fn truncate(&mut self) -> Result<(), IoError> {
         (*self).truncate()
     }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        (*self).flush()
+    }
+
+    fn sync(&mut self) -> Result<(), IoError> {
+        (*self).sync()
+    }
 }
 
 pub trait ReadAt {
     fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<(), IoError>;
+    fn size(&self) -> Result<u64, IoError>;
 }
 
 impl<R: ReadAt> ReadAt for &R {
     fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<(), IoError> {
         (*self).read_exact_at(buf, offset)
     }
+
+    fn size(&self) -> Result<u64, IoError> {
+        (*self).size()
+    }
 }
 
 fn read_u64(buf: &[u8]) -> u64 {
@@ -78,42 +176,237 @@ fn read_u32(buf: &[u8]) -> u32 {
     BigEndian::read_u32(buf)
 }
 
-pub trait Storage {
-    type Reader: ReadAt;
-    type Appender: Append;
+/// Backend holding the WAL and the sstables.
+///
+/// Storages and their readers can be shared across threads, so that a
+/// [`Database`] can be too.
+pub trait Storage: Send + Sync {
+    type Reader: ReadAt + Send + Sync;
+    type Appender: Append + Send + Sync;
 
     fn read(&self, key: &str) -> Result<Self::Reader, IoError>;
     fn write(&self, key: &str, value: &[u8]) -> Result<(), IoError>;
     fn append(&self, key: &str) -> Result<Self::Appender, IoError>;
     fn delete(&self, key: &str) -> Result<(), IoError>;
     fn list(&self) -> Result<Vec<String>, IoError>;
+
+    /// Atomically replace `to` with `from`.
+    ///
+    /// The default implementation returns an
+    /// [`Unsupported`](IoErrorKind::Unsupported) error. The database renames
+    /// sstables when rewriting them, moving them to the next level, and
+    /// dropping a partial record from the WAL, so those fail without it.
+    fn rename(&self, from: &str, to: &str) -> Result<(), IoError> {
+        let _ = (from, to);
+        Err(IoError::new(IoErrorKind::Unsupported, "This storage doesn't support renaming"))
+    }
+}
+
+// SSTable header: entry count (u32) then flags (u8)
+const SSTABLE_HEADER_LEN: u64 = 5;
+
+// The offset table uses u32 instead of u64
+const SSTABLE_FLAG_OFFSETS_32: u8 = 1;
+
+// The header is followed by an alignment (u32), and the entries section and
+// each entry start on a multiple of it
+const SSTABLE_FLAG_ALIGNED: u8 = 2;
+
+// Some entries are deletions, marked by a value length of TOMBSTONE_LEN with
+// no value following
+const SSTABLE_FLAG_TOMBSTONES: u8 = 4;
+
+const TOMBSTONE_LEN: u32 = u32::MAX;
+
+// The header is followed by a bloom filter of the keys: number of hashes
+// (u8), length (u32) and the bits. It comes after the alignment if both are
+// present
+const SSTABLE_FLAG_BLOOM: u8 = 8;
+
+const DEFAULT_BLOOM_FALSE_POSITIVE_RATE: f64 = 0.01;
+
+// All the entries have the same key and value lengths, given in the header
+// (two u32) after the bloom filter. There is no offset table, the position
+// of each entry is computed from them
+const SSTABLE_FLAG_FIXED: u8 = 16;
+
+// The bloom filter is followed by a second one, in the same format, of the
+// key prefixes given by the prefix extractor
+const SSTABLE_FLAG_PREFIX_BLOOM: u8 = 32;
+
+// The filters are followed by statistics on the entries: the number of
+// tombstones, the total size of the keys and that of the values (three u64)
+const SSTABLE_FLAG_STATS: u8 = 64;
+
+const SSTABLE_STATS_LEN: u64 = 24;
+
+fn align_up(offset: u64, alignment: u64) -> u64 {
+    offset.div_ceil(alignment) * alignment
 }
 
 struct SSTableReader<R: ReadAt> {
     file: R,
     size: usize,
+    offset_width: u64,
+    // Start of the offset table, after the header
+    index_start: u64,
+    alignment: u64,
+    // Whether the table may hold deletions
+    tombstones: bool,
+    bloom: Option<BloomFilter>,
+    prefix_bloom: Option<BloomFilter>,
+    // Tombstone count, key bytes and value bytes, if recorded
+    stats: Option<(u64, u64, u64)>,
+    // If the entries have a fixed size, the key length and the distance
+    // between entries, replacing the offset table
+    fixed: Option<(u32, u64)>,
+    // Size of the file, which lengths read from it are checked against
+    file_size: u64,
+    // The offset table, if it was loaded in memory
+    offsets: Option<Vec<u64>>,
+    // First and last keys, read on first use
+    key_range: OnceLock<Option<KeyRange>>,
 }
 
 impl<R: ReadAt> SSTableReader<R> {
     fn open(file: R) -> Result<SSTableReader<R>, IoError> {
-        let mut size_buf = [0u8; 4];
-        file.read_exact_at(&mut size_buf, 0)?;
-        let size = read_u32(&size_buf) as usize;
+        let mut header_buf = [0u8; SSTABLE_HEADER_LEN as usize];
+        file.read_exact_at(&mut header_buf, 0)?;
+        let size = read_u32(&header_buf[0..4]) as usize;
+        let flags = header_buf[4];
+        let known_flags = SSTABLE_FLAG_OFFSETS_32 | SSTABLE_FLAG_ALIGNED | SSTABLE_FLAG_TOMBSTONES | SSTABLE_FLAG_BLOOM | SSTABLE_FLAG_FIXED | SSTABLE_FLAG_PREFIX_BLOOM | SSTABLE_FLAG_STATS;
+        if flags & !known_flags != 0 {
+            return Err(IoError::new(IoErrorKind::InvalidData, "Unknown sstable flags"));
+        }
+        let mut offset_width = if flags & SSTABLE_FLAG_OFFSETS_32 != 0 { 4 } else { 8 };
+        let mut index_start = SSTABLE_HEADER_LEN;
+        let mut alignment = 1;
+        if flags & SSTABLE_FLAG_ALIGNED != 0 {
+            let mut alignment_buf = [0u8; 4];
+            file.read_exact_at(&mut alignment_buf, SSTABLE_HEADER_LEN)?;
+            alignment = read_u32(&alignment_buf) as u64;
+            if alignment == 0 {
+                return Err(IoError::new(IoErrorKind::InvalidData, "Invalid sstable alignment"));
+            }
+            index_start += 4;
+        }
+        let file_size = file.size()?;
+        let mut bloom = None;
+        if flags & SSTABLE_FLAG_BLOOM != 0 {
+            bloom = Some(read_bloom_filter(&file, &mut index_start, file_size)?);
+        }
+        let mut prefix_bloom = None;
+        if flags & SSTABLE_FLAG_PREFIX_BLOOM != 0 {
+            prefix_bloom = Some(read_bloom_filter(&file, &mut index_start, file_size)?);
+        }
+        let mut stats = None;
+        if flags & SSTABLE_FLAG_STATS != 0 {
+            if file_size < index_start + SSTABLE_STATS_LEN {
+                return Err(IoError::new(IoErrorKind::InvalidData, "SSTable is shorter than its header"));
+            }
+            let mut stats_buf = [0u8; SSTABLE_STATS_LEN as usize];
+            file.read_exact_at(&mut stats_buf, index_start)?;
+            stats = Some((read_u64(&stats_buf[0..8]), read_u64(&stats_buf[8..16]), read_u64(&stats_buf[16..24])));
+            index_start += SSTABLE_STATS_LEN;
+        }
+        let mut fixed = None;
+        if flags & SSTABLE_FLAG_FIXED != 0 {
+            if file_size < index_start + 8 {
+                return Err(IoError::new(IoErrorKind::InvalidData, "SSTable is shorter than its header"));
+            }
+            let mut sizes_buf = [0u8; 8];
+            file.read_exact_at(&mut sizes_buf, index_start)?;
+            let key_len = read_u32(&sizes_buf[0..4]);
+            let value_len = read_u32(&sizes_buf[4..8]);
+            let entry_len = 8 + key_len as u64 + value_len as u64;
+            let stride = align_up(entry_len, alignment);
+            // The last entry is not padded
+            let entries_len = if size == 0 { 0 } else { (size as u64 - 1) * stride + entry_len };
+            if file_size < align_up(index_start + 8, alignment) + entries_len {
+                return Err(IoError::new(IoErrorKind::InvalidData, "SSTable is shorter than its entries"));
+            }
+            fixed = Some((key_len, stride));
+            offset_width = 0;
+            index_start += 8;
+        }
+        if file_size < index_start + size as u64 * offset_width {
+            return Err(IoError::new(IoErrorKind::InvalidData, "SSTable is shorter than its index"));
+        }
         Ok(SSTableReader {
             file,
             size,
+            offset_width,
+            index_start,
+            alignment,
+            tombstones: flags & SSTABLE_FLAG_TOMBSTONES != 0,
+            bloom,
+            prefix_bloom,
+            stats,
+            fixed,
+            file_size,
+            offsets: None,
+            key_range: OnceLock::new(),
         })
     }
 
+    fn read_key(&self, entry_index: usize) -> Result<Vec<u8>, IoError> {
+        let mut offset = self.section_entries() + self.get_offset(entry_index)?;
+        read_vec(&self.file, &mut offset, self.file_size)
+    }
+
+    // Returns the first and last keys, or None if the table is empty
+    fn key_range(&self) -> Result<Option<&KeyRange>, IoError> {
+        if self.key_range.get().is_none() {
+            let range = if self.size == 0 {
+                None
+            } else {
+                Some((self.read_key(0)?, self.read_key(self.size - 1)?))
+            };
+            let _ = self.key_range.set(range);
+        }
+        Ok(self.key_range.get().unwrap().as_ref())
+    }
+
+    // Read the whole offset table in memory
+    fn load_offsets(&mut self) -> Result<(), IoError> {
+        if self.offsets.is_some() || self.fixed.is_some() {
+            return Ok(());
+        }
+        let width = self.offset_width as usize;
+        let mut buf = vec![0u8; self.size * width];
+        self.file.read_exact_at(&mut buf, self.index_start)?;
+        let offsets = buf.chunks_exact(width)
+            .map(|b| if width == 4 { read_u32(b) as u64 } else { read_u64(b) })
+            .collect();
+        self.offsets = Some(offsets);
+        Ok(())
+    }
+
+    fn section_entries(&self) -> u64 {
+        align_up(self.index_start + self.size as u64 * self.offset_width, self.alignment)
+    }
+
     fn get_offset(&self, entry_index: usize) -> Result<u64, IoError> {
-        let section_index = 4;
+        if let Some((_, stride)) = self.fixed {
+            return Ok(entry_index as u64 * stride);
+        }
+        if let Some(offsets) = &self.offsets {
+            return Ok(offsets[entry_index]);
+        }
+
+        let section_index = self.index_start;
 
         let mut buf = [0u8; 8];
+        let buf = &mut buf[0..self.offset_width as usize];
         self.file.read_exact_at(
-            &mut buf,
-            section_index + entry_index as u64 * 8,
+            buf,
+            section_index + entry_index as u64 * self.offset_width,
         )?;
-        Ok(read_u64(&buf))
+        if self.offset_width == 4 {
+            Ok(read_u32(buf) as u64)
+        } else {
+            Ok(read_u64(buf))
+        }
     }
 
     // Binary search for a given key.
@@ -128,7 +421,7 @@ impl<R: ReadAt> SSTableReader<R> {
         }
         let mut base = 0;
 
-        let section_entries = 4 + self.size as u64 * 8;
+        let section_entries = self.section_entries();
 
         loop {
             let half = size / 2;
@@ -143,7 +436,7 @@ impl<R: ReadAt> SSTableReader<R> {
             )?;
             let mid_key_len = read_u32(&mid_key_len_buf);
 
-            let mut mid = vec![0u8; mid_key_len as usize];
+            let mut mid = vec![0u8; check_len(section_entries + mid_offset + 4, mid_key_len, self.file_size)?];
             self.file.read_exact_at(
                 &mut mid,
                 section_entries + mid_offset + 4,
@@ -163,366 +456,5621 @@ impl<R: ReadAt> SSTableReader<R> {
         }
     }
 
-    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, IoError> {
-        let section_entries = 4 + self.size as u64 * 8;
-
-        if let (_, Some(value_offset)) = self.binary_search(key)? {
-            let mut value_len_buf = [0u8; 4];
-            self.file.read_exact_at(
-                &mut value_len_buf,
-                section_entries + value_offset,
-            )?;
-            let value_len = read_u32(&value_len_buf);
-
-            let mut value = vec![0u8; value_len as usize];
-            self.file.read_exact_at(
-                &mut value,
-                section_entries + value_offset + 4,
-            )?;
-            return Ok(Some(value));
-        } else {
+    // Read the length of the value at the given offset in the file, None for
+    // a deletion
+    fn read_value_len(&self, offset: u64) -> Result<Option<u32>, IoError> {
+        let mut value_len_buf = [0u8; 4];
+        self.file.read_exact_at(&mut value_len_buf, offset)?;
+        let value_len = read_u32(&value_len_buf);
+        if self.tombstones && value_len == TOMBSTONE_LEN {
             Ok(None)
+        } else {
+            Ok(Some(value_len))
         }
     }
-}
 
-fn write_sstable(entries: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
-    let mut result = Cursor::new(Vec::new());
-    result.write_u32::<BigEndian>(entries.len() as u32).unwrap();
-    let mut offset = 0;
-    for entry in entries {
-        result.write_u64::<BigEndian>(offset).unwrap();
-        offset += 4 + entry.0.len() as u64 + 4 + entry.1.len() as u64;
-    }
-    for entry in entries {
-        result.write_u32::<BigEndian>(entry.0.len() as u32).unwrap();
-        result.write_all(&entry.0).unwrap();
-        result.write_u32::<BigEndian>(entry.1.len() as u32).unwrap();
-        result.write_all(&entry.1).unwrap();
+    // Read the value at the given offset in the file, None for a deletion
+    fn read_value(&self, offset: &mut u64) -> Result<Option<Vec<u8>>, IoError> {
+        let Some(value_len) = self.read_value_len(*offset)? else {
+            return Ok(None);
+        };
+        *offset += 4;
+
+        let mut value = vec![0u8; check_len(*offset, value_len, self.file_size)?];
+        self.file.read_exact_at(&mut value, *offset)?;
+        *offset += value_len as u64;
+        Ok(Some(value))
     }
-    result.into_inner()
-}
 
-pub struct Database<S: Storage> {
-    storage: S,
-    sstables: Vec<((u32, u32), SSTableReader<S::Reader>)>,
-    mem_table: MemTable,
-    wal: S::Appender,
-}
+    // Whether the key can be in the table, according to the fixed key
+    // length and the bloom filter
+    fn may_contain(&self, key: &[u8]) -> bool {
+        if self.fixed.is_some_and(|(key_len, _)| key.len() != key_len as usize) {
+            return false;
+        }
+        self.bloom.as_ref().is_none_or(|bloom| bloom.may_contain(key))
+    }
 
-fn read_vec<R: ReadAt>(file: R, offset: &mut u64) -> Result<Vec<u8>, IoError> {
-    let mut len_buf = [0u8; 4];
-    file.read_exact_at(&mut len_buf, *offset)?;
-    *offset += 4;
-    let len = read_u32(&len_buf);
-    let mut vec = vec![0u8; len as usize];
-    file.read_exact_at(&mut vec, *offset)?;
-    *offset += len as u64;
-    Ok(vec)
-}
+    // Whether keys starting with the prefix can be in the table, according
+    // to the prefix bloom filter. The prefix must be one the extractor
+    // returns
+    fn may_contain_prefix(&self, prefix: &[u8]) -> bool {
+        self.prefix_bloom.as_ref().is_none_or(|bloom| bloom.may_contain(prefix))
+    }
 
-fn write_vec<A: Append>(mut file: A, buf: &[u8]) -> Result<(), IoError> {
-    let mut len = [0u8; 4];
-    Cursor::new(&mut len as &mut [u8]).write_u32::<BigEndian>(buf.len() as u32)?;
-    file.append(&len)?;
-    file.append(buf)?;
-    Ok(())
-}
+    // Whether the key can be in the table without reading it, also checking
+    // the key range if it was already read
+    fn may_hold(&self, key: &[u8]) -> bool {
+        if !self.may_contain(key) {
+            return false;
+        }
+        match self.key_range.get() {
+            Some(Some((first, last))) => first.as_slice() <= key && key <= last.as_slice(),
+            Some(None) => false,
+            None => true,
+        }
+    }
 
-impl<S: Storage> Database<S> {
-    pub fn open(storage: S) -> Result<Database<S>, Error> {
-        let mut wal_found = false;
-        let mut sstable_names = Vec::new();
-        for entry in storage.list()? {
-            if &entry == "wal" {
-                wal_found = true;
-            } else if entry.ends_with(".sst") {
-                sstable_names.push(entry);
-            } else {
-                return Err(Error::InvalidDatabase("Unexpected file in storage".into()));
-            }
+    // Look up a key, first checking it against the key range
+    fn get_in_range(&self, key: &[u8]) -> Result<Option<Option<Vec<u8>>>, IoError> {
+        match self.key_range()? {
+            Some((first, last)) if first.as_slice() <= key && key <= last.as_slice() => self.get(key),
+            _ => Ok(None),
         }
+    }
 
-        let mut mem_table: MemTable = Default::default();
-        let mut sstables = Vec::new();
+    // Look up a key, returning Some(None) if the table records its deletion
+    fn get(&self, key: &[u8]) -> Result<Option<Option<Vec<u8>>>, IoError> {
+        if !self.may_contain(key) {
+            return Ok(None);
+        }
+        if let (_, Some(value_offset)) = self.binary_search(key)? {
+            let mut offset = self.section_entries() + value_offset;
+            Ok(Some(self.read_value(&mut offset)?))
+        } else {
+            Ok(None)
+        }
+    }
 
-        if !wal_found && sstable_names.len() > 0 {
-            return Err(Error::InvalidDatabase("Missing wal".into()));
-        } else if !wal_found {
-            // Initialize new empty database
-            info!("Opening empty database");
+    // Look up the length of a key's value, without reading the value
+    fn value_len(&self, key: &[u8]) -> Result<Option<Option<u32>>, IoError> {
+        if !self.may_contain(key) {
+            return Ok(None);
+        }
+        if let (_, Some(value_offset)) = self.binary_search(key)? {
+            Ok(Some(self.read_value_len(self.section_entries() + value_offset)?))
         } else {
-            // Open existing database
-            info!("Opening existing database, replaying WAL");
-            let mut entries = 0;
-            let mut incomplete_sstables = HashSet::new();
-            let wal = storage.read("wal")?;
-            let mut offset = 0;
-            loop {
-                let mut op_buf = [0u8];
-                let op = match wal.read_exact_at(&mut op_buf, offset) {
-                    Err(e) if e.kind() == IoErrorKind::UnexpectedEof => break,
-                    Err(e) => return Err(e.into()),
-                    Ok(()) => match op_buf[0] {
-                        0 => Operation::Put,
-                        1 => Operation::Delete,
-                        2 => Operation::WriteSstableStart,
-                        3 => Operation::WriteSstableEnd,
-                        _ => return Err(Error::InvalidDatabase("Invalid WAL entry type".into())),
-                    }
-                };
-                offset += 1;
-                let key = read_vec(&wal, &mut offset)?;
-                match op {
-                    Operation::Put => {
-                        let value = read_vec(&wal, &mut offset)?;
-                        mem_table.put(&key, value);
-                    }
-                    Operation::Delete => {
-                        mem_table.delete(&key);
-                    }
-                    Operation::WriteSstableStart => {
-                        let table_name = read_vec(&wal, &mut offset)?;
-                        let table_name = String::from_utf8(table_name)
-                            .map_err(|_| ())
-                            .and_then(|n| if n.is_ascii() { Ok(n) } else { Err(()) })
-                            .map_err(|_| Error::InvalidDatabase("Invalid table name in WAL".into()))?;
-                        incomplete_sstables.insert(table_name);
-                    }
-                    Operation::WriteSstableEnd => {
-                        let table_name = read_vec(&wal, &mut offset)?;
-                        let table_name = String::from_utf8(table_name)
-                            .map_err(|_| ())
-                            .and_then(|n| if n.is_ascii() { Ok(n) } else { Err(()) })
-                            .map_err(|_| Error::InvalidDatabase("Invalid table name in WAL".into()))?;
-                        incomplete_sstables.remove(&table_name);
-                    }
-                }
-                entries += 1;
+            Ok(None)
+        }
+    }
+
+    // Look up sorted keys, starting the search for each key from where the
+    // previous one was found, so that clustered keys take few reads
+    fn get_sorted(&self, keys: &[&[u8]]) -> Result<Vec<Option<Option<Vec<u8>>>>, IoError> {
+        let mut results = Vec::with_capacity(keys.len());
+        let mut low = 0;
+        for &key in keys {
+            if !self.may_contain(key) {
+                results.push(None);
+                continue;
             }
 
-            // Remove incomplete sstables
-            info!("{} incomplete sstables to delete", incomplete_sstables.len());
-            for sstable in &incomplete_sstables {
-                storage.delete(&sstable)?;
+            // Gallop forward until reaching an entry that is not lower than
+            // the key, all entries before `low` being lower
+            let mut high = low;
+            let mut step = 1;
+            while high < self.size && &self.read_key(high)? as &[u8] < key {
+                low = high + 1;
+                high = low + step;
+                step *= 2;
+            }
+            let mut high = high.min(self.size);
+            while low < high {
+                let mid = low + (high - low) / 2;
+                if &self.read_key(mid)? as &[u8] < key {
+                    low = mid + 1;
+                } else {
+                    high = mid;
+                }
             }
 
-            // Open remaining sstables
-            for name in sstable_names {
-                if !incomplete_sstables.contains(&name) {
-                    let reader = storage.read(&name)?;
-                    let table = SSTableReader::open(reader)?;
-                    let id = parse_sstable_name(&name).map_err(|_| Error::InvalidDatabase("Invalid sstable name".into()))?;
-                    sstables.push((id, table));
+            let mut value = None;
+            if low < self.size {
+                let mut offset = self.section_entries() + self.get_offset(low)?;
+                if read_vec(&self.file, &mut offset, self.file_size)? == key {
+                    value = Some(self.read_value(&mut offset)?);
                 }
             }
+            results.push(value);
+        }
+        Ok(results)
+    }
 
-            info!("Replayed {} WAL entries", entries);
+    /// Returns the index of the first entry whose key is not less than `key`.
+    fn lower_bound(&self, key: &[u8]) -> Result<usize, IoError> {
+        let mut low = 0;
+        let mut high = self.size;
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let mid_key = self.read_key(mid)?;
+            if &mid_key as &[u8] < key {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
         }
-        let wal = storage.append("wal")?;
-        Ok(Database {
-            storage,
-            sstables,
-            mem_table,
-            wal,
-        })
+        Ok(low)
     }
 
-    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), IoError> {
-        // Write to WAL
-        self.wal.append(&[0u8])?;
-        write_vec(&mut self.wal, key)?;
-        write_vec(&mut self.wal, value)?;
+    // Iterate on entries from `key_start` up to `key_end` (excluded), or to
+    // the end of the table if `key_end` is `None`.
+    fn iter_range<'a>(&'a self, key_start: &'a [u8], key_end: Option<&'a [u8]>) -> SSTableRangeIterator<'a, R> {
+        SSTableRangeIterator {
+            sstable: self,
+            key_start: Some(key_start),
+            key_end,
+            read_values: true,
+            filter: None,
+            next_index: 0,
+            offset: 0,
+            read_ahead: 0,
+            buffer: Vec::new(),
+            buffer_start: 0,
+        }
+    }
+}
 
-        // Update memtable
-        self.mem_table.put(key, value.into());
+struct SSTableRangeIterator<'a, R: ReadAt> {
+    sstable: &'a SSTableReader<R>,
+    // Set until the first entry is read, at which point we binary search
+    key_start: Option<&'a [u8]>,
+    key_end: Option<&'a [u8]>,
+    // If unset, values are skipped and empty values are returned
+    read_values: bool,
+    // Entries whose key doesn't match are skipped without reading the value
+    filter: Option<KeyFilter<'a>>,
+    next_index: usize,
+    offset: u64,
+    // Number of bytes to read at once, buffering entries for the next calls
+    read_ahead: u64,
+    buffer: Vec<u8>,
+    buffer_start: u64,
+}
 
+impl<'a, R: ReadAt> SSTableRangeIterator<'a, R> {
+    // Read from the table, through the read-ahead buffer if enabled
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> Result<(), IoError> {
+        if self.read_ahead == 0 {
+            return self.sstable.file.read_exact_at(buf, offset);
+        }
+        let len = buf.len() as u64;
+        if offset < self.buffer_start || offset + len > self.buffer_start + self.buffer.len() as u64 {
+            // Don't read past the end of the file, unless the caller asks to
+            let fill = self.read_ahead.min(self.sstable.file_size.saturating_sub(offset)).max(len);
+            self.buffer.resize(fill as usize, 0);
+            self.sstable.file.read_exact_at(&mut self.buffer, offset)?;
+            self.buffer_start = offset;
+        }
+        let start = (offset - self.buffer_start) as usize;
+        buf.copy_from_slice(&self.buffer[start..start + buf.len()]);
         Ok(())
     }
 
-    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, IoError> {
-        // Read from mem table
-        if let Some(value) = self.mem_table.get(key) {
-            return Ok(Some(value.into()));
-        }
+    fn read_vec(&mut self, offset: &mut u64) -> Result<Vec<u8>, IoError> {
+        let mut len_buf = [0u8; 4];
+        self.read_exact_at(&mut len_buf, *offset)?;
+        *offset += 4;
+        let mut vec = vec![0u8; check_len(*offset, read_u32(&len_buf), self.sstable.file_size)?];
+        self.read_exact_at(&mut vec, *offset)?;
+        *offset += vec.len() as u64;
+        Ok(vec)
+    }
 
-        // Read from sstables
-        for (_, sstable) in self.sstables.iter().rev() {
-            if let Some(value) = sstable.get(key)? {
-                return Ok(Some(value));
+    fn read_next(&mut self) -> Result<Option<Entry>, IoError> {
+        if let Some(key_start) = self.key_start.take() {
+            self.next_index = self.sstable.lower_bound(key_start)?;
+            if self.next_index < self.sstable.size {
+                self.offset = self.sstable.section_entries() + self.sstable.get_offset(self.next_index)?;
             }
         }
 
-        Ok(None)
-    }
-
-    pub fn delete(&mut self, key: &[u8]) -> Result<(), IoError> {
-        // Write to WAL
-        self.wal.append(&[1u8])?;
-        write_vec(&mut self.wal, key)?;
-
-        // Update memtable
-        self.mem_table.delete(key);
-
-        Ok(())
-    }
+        loop {
+            if self.next_index >= self.sstable.size {
+                return Ok(None);
+            }
 
-    pub fn iter_range(&mut self, key_start: &[u8], key_end: &[u8]) -> RangeIterator<S> {
-        RangeIterator {
-            database: self,
+            // Entries are contiguous, read them sequentially
+            let mut offset = self.offset;
+            let key = self.read_vec(&mut offset)?;
+            if self.key_end.is_some_and(|key_end| &key as &[u8] >= key_end) {
+                self.next_index = self.sstable.size;
+                return Ok(None);
+            }
+            let keep = self.filter.as_ref().is_none_or(|filter| filter(&key));
+            let mut len_buf = [0u8; 4];
+            self.read_exact_at(&mut len_buf, offset)?;
+            offset += 4;
+            let value_len = read_u32(&len_buf);
+            let value = if self.sstable.tombstones && value_len == TOMBSTONE_LEN {
+                None
+            } else if self.read_values && keep {
+                let mut value = vec![0u8; check_len(offset, value_len, self.sstable.file_size)?];
+                self.read_exact_at(&mut value, offset)?;
+                offset += value_len as u64;
+                Some(value)
+            } else {
+                offset += value_len as u64;
+                Some(Vec::new())
+            };
+            self.offset = align_up(offset, self.sstable.alignment);
+            self.next_index += 1;
+            if keep {
+                return Ok(Some((key, value)));
+            }
         }
     }
+}
 
-    pub fn maintain(&mut self) -> Result<(), IoError> {
-        // TODO: Merge tables
+impl<'a, R: ReadAt> Iterator for SSTableRangeIterator<'a, R> {
+    type Item = Result<Entry, IoError>;
 
-        // Write memtable to disk
-        let mut new_id = 0;
-        for &((level, id), _) in &self.sstables {
-            if level == 0 {
-                if id >= new_id {
-                    new_id = id + 1;
-                }
+    fn next(&mut self) -> Option<Result<Entry, IoError>> {
+        match self.read_next() {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => None,
+            Err(e) => {
+                self.next_index = self.sstable.size;
+                Some(Err(e))
             }
         }
-        let new_name = format!("1-{}.sst", new_id);
-        info!("Writing memtable to new sstable '{}'", new_name);
-
-        self.wal.append(&[2])?;
-        write_vec(&mut self.wal, new_name.as_bytes())?;
+    }
+}
 
-        let buf = write_sstable(&self.mem_table.entries);
-        self.storage.write(&new_name, &buf)?;
+// Read a bloom filter stored at `offset` in an sstable header, moving the
+// offset past it
+fn read_bloom_filter<R: ReadAt>(file: R, offset: &mut u64, file_size: u64) -> Result<BloomFilter, IoError> {
+    if file_size < *offset + 5 {
+        return Err(IoError::new(IoErrorKind::InvalidData, "SSTable is shorter than its bloom filter"));
+    }
+    let mut bloom_header = [0u8; 5];
+    file.read_exact_at(&mut bloom_header, *offset)?;
+    let len = read_u32(&bloom_header[1..5]) as u64;
+    if bloom_header[0] == 0 || len == 0 {
+        return Err(IoError::new(IoErrorKind::InvalidData, "Invalid sstable bloom filter"));
+    }
+    if file_size < *offset + 5 + len {
+        return Err(IoError::new(IoErrorKind::InvalidData, "SSTable is shorter than its bloom filter"));
+    }
+    let mut bits = vec![0u8; len as usize];
+    file.read_exact_at(&mut bits, *offset + 5)?;
+    *offset += 5 + len;
+    Ok(BloomFilter::from_parts(bits, bloom_header[0]))
+}
 
-        self.wal.append(&[3])?;
-        write_vec(&mut self.wal, new_name.as_bytes())?;
-        info!("New sstable write complete");
+fn write_bloom_filter(result: &mut std::io::Cursor<Vec<u8>>, bloom: &BloomFilter) {
+    result.write_u8(bloom.hashes()).unwrap();
+    result.write_u32::<BigEndian>(bloom.bits().len() as u32).unwrap();
+    result.write_all(bloom.bits()).unwrap();
+}
 
-        // Open new memtable
-        let reader = self.storage.read(&new_name)?;
-        let table = SSTableReader::open(reader)?;
-        let index = self.sstables.partition_point(|&(k, _)| k > (1, new_id));
-        self.sstables.insert(index, ((1, new_id), table));
+// The prefixes of the keys to put in the prefix filter. Keys for which the
+// extractor returns an empty prefix are left out
+fn entry_prefixes<'a>(entries: &'a [Entry], extractor: &PrefixExtractor) -> Vec<&'a [u8]> {
+    let mut prefixes: Vec<&[u8]> = entries.iter()
+        .map(|(key, _)| extractor(key))
+        .filter(|prefix| !prefix.is_empty())
+        .collect();
+    // Keys with the same prefix are adjacent
+    prefixes.dedup();
+    prefixes
+}
 
-        // Truncate WAL
-        info!("Truncating WAL");
-        self.wal.truncate()?;
+fn entry_len(entry: &Entry) -> u64 {
+    4 + entry.0.len() as u64 + 4 + entry.1.as_ref().map_or(0, |v| v.len() as u64)
+}
 
-        Ok(())
-    }
+// Offset of the last entry in the entries section. Since the section itself
+// is aligned, aligning offsets in it aligns them in the file
+fn last_entry_offset(entries: &[Entry], alignment: u64) -> u64 {
+    entries[..entries.len().saturating_sub(1)].iter()
+        .fold(0, |offset, entry| align_up(offset + entry_len(entry), alignment))
 }
 
-pub struct RangeIterator<'a, S: Storage> {
-    database: &'a mut Database<S>,
+// How the entries of an sstable are located
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum EntryIndex {
+    Offsets32,
+    Offsets64,
+    // No offset table, every entry has these key and value lengths
+    Fixed { key_len: u32, value_len: u32 },
 }
 
-impl<'a, S: Storage> Iterator for RangeIterator<'a, S> {
-    type Item = (Vec<u8>, Vec<u8>);
+// The most compact index for these entries: fixed-size if the keys and
+// values all have the same length, else an offset table as narrow as fits
+fn choose_entry_index(entries: &[Entry], alignment: u64) -> EntryIndex {
+    if let Some((key, Some(value))) = entries.first() {
+        let fixed = entries.iter().all(|(k, v)| {
+            k.len() == key.len() && v.as_ref().is_some_and(|v| v.len() == value.len())
+        });
+        if fixed {
+            return EntryIndex::Fixed { key_len: key.len() as u32, value_len: value.len() as u32 };
+        }
+    }
+    if last_entry_offset(entries, alignment) <= u32::MAX as u64 {
+        EntryIndex::Offsets32
+    } else {
+        EntryIndex::Offsets64
+    }
+}
 
-    fn next(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
-        todo!()
+// Size of the sstable write_sstable() would create
+fn sstable_len(entries: &[Entry], alignment: u64, bloom_rate: f64, prefix_extractor: Option<&PrefixExtractor>) -> u64 {
+    let index_len = match choose_entry_index(entries, alignment) {
+        EntryIndex::Offsets32 => entries.len() as u64 * 4,
+        EntryIndex::Offsets64 => entries.len() as u64 * 8,
+        EntryIndex::Fixed { .. } => 8,
+    };
+    let mut index_end = SSTABLE_HEADER_LEN + index_len;
+    if alignment > 1 {
+        index_end += 4;
     }
+    if let Some((len, _)) = BloomFilter::dimensions(entries.len(), bloom_rate) {
+        index_end += 5 + len as u64;
+    }
+    if let Some(extractor) = prefix_extractor {
+        if let Some((len, _)) = BloomFilter::dimensions(entry_prefixes(entries, extractor).len(), bloom_rate) {
+            index_end += 5 + len as u64;
+        }
+    }
+    index_end += SSTABLE_STATS_LEN;
+    let data_len = match entries.last() {
+        Some(last) => last_entry_offset(entries, alignment) + entry_len(last),
+        None => 0,
+    };
+    align_up(index_end, alignment) + data_len
 }
 
-enum Operation {
-    Put,
-    Delete,
-    WriteSstableStart,
-    WriteSstableEnd,
+// Write an sstable where each entry starts on a multiple of `alignment`,
+// with a bloom filter of the keys for the given false-positive rate, and one
+// of their prefixes if there is a prefix extractor
+fn write_sstable(entries: &[Entry], alignment: u64, bloom_rate: f64, prefix_extractor: Option<&PrefixExtractor>) -> Vec<u8> {
+    debug_assert!(check_sorted(entries).is_ok(), "Writing sstable with unsorted entries");
+    write_sstable_with_format(entries, choose_entry_index(entries, alignment), alignment, bloom_rate, prefix_extractor)
 }
 
-fn parse_sstable_name(name: &str) -> Result<(u32, u32), ()> {
-    let Some(dash) = name.find('-') else {
-        return Err(());
-    };
-    let level = name[0..dash].parse().map_err(|_| ())?;
-    let dot = match name[dash+1..].find('.') {
-        Some(i) => dash + 1 + i,
-        None => return Err(()),
-    };
-    let id = name[dash+1..dot].parse().map_err(|_| ())?;
-    if &name[dot..] != ".sst" {
-        return Err(());
+// Entries of an sstable must have strictly increasing keys, or lookups fail
+fn check_sorted(entries: &[Entry]) -> Result<(), IoError> {
+    if entries.windows(2).all(|w| w[0].0 < w[1].0) {
+        Ok(())
+    } else {
+        Err(IoError::new(IoErrorKind::InvalidData, "Entries of sstable are not sorted"))
     }
-    Ok((level, id))
 }
 
-#[test]
-fn test_parse_sstable_name() {
-    assert_eq!(parse_sstable_name("1-0.sst"), Ok((1, 0)));
-    assert_eq!(parse_sstable_name("123-456.sst"), Ok((123, 456)));
-    assert_eq!(parse_sstable_name(""), Err(()));
-    assert_eq!(parse_sstable_name("-0.sst"), Err(()));
-    assert_eq!(parse_sstable_name("1-.sst"), Err(()));
-    assert_eq!(parse_sstable_name("1-0."), Err(()));
-    assert_eq!(parse_sstable_name("1-0"), Err(()));
+fn write_sstable_with_format(entries: &[Entry], index: EntryIndex, alignment: u64, bloom_rate: f64, prefix_extractor: Option<&PrefixExtractor>) -> Vec<u8> {
+    let bloom = BloomFilter::build(entries.iter().map(|(key, _)| key as &[u8]), bloom_rate);
+    let prefix_bloom = prefix_extractor.and_then(|extractor| {
+        BloomFilter::build(entry_prefixes(entries, extractor).into_iter(), bloom_rate)
+    });
+    let mut result = std::io::Cursor::new(Vec::new());
+    let mut flags = SSTABLE_FLAG_STATS;
+    match index {
+        EntryIndex::Offsets32 => flags |= SSTABLE_FLAG_OFFSETS_32,
+        EntryIndex::Offsets64 => {}
+        EntryIndex::Fixed { .. } => flags |= SSTABLE_FLAG_FIXED,
+    }
+    if alignment > 1 {
+        flags |= SSTABLE_FLAG_ALIGNED;
+    }
+    if entries.iter().any(|(_, value)| value.is_none()) {
+        flags |= SSTABLE_FLAG_TOMBSTONES;
+    }
+    if bloom.is_some() {
+        flags |= SSTABLE_FLAG_BLOOM;
+    }
+    if prefix_bloom.is_some() {
+        flags |= SSTABLE_FLAG_PREFIX_BLOOM;
+    }
+    result.write_u32::<BigEndian>(entries.len() as u32).unwrap();
+    result.write_u8(flags).unwrap();
+    if alignment > 1 {
+        result.write_u32::<BigEndian>(alignment as u32).unwrap();
+    }
+    if let Some(bloom) = &bloom {
+        write_bloom_filter(&mut result, bloom);
+    }
+    if let Some(prefix_bloom) = &prefix_bloom {
+        write_bloom_filter(&mut result, prefix_bloom);
+    }
+    let tombstones = entries.iter().filter(|(_, value)| value.is_none()).count();
+    let key_bytes: usize = entries.iter().map(|(key, _)| key.len()).sum();
+    let value_bytes: usize = entries.iter().filter_map(|(_, value)| value.as_ref()).map(|value| value.len()).sum();
+    result.write_u64::<BigEndian>(tombstones as u64).unwrap();
+    result.write_u64::<BigEndian>(key_bytes as u64).unwrap();
+    result.write_u64::<BigEndian>(value_bytes as u64).unwrap();
+    if let EntryIndex::Fixed { key_len, value_len } = index {
+        debug_assert!(entries.iter().all(|(k, v)| {
+            k.len() == key_len as usize && v.as_ref().is_some_and(|v| v.len() == value_len as usize)
+        }));
+        result.write_u32::<BigEndian>(key_len).unwrap();
+        result.write_u32::<BigEndian>(value_len).unwrap();
+    }
+    let pad = |result: &mut std::io::Cursor<Vec<u8>>| {
+        let position = result.position();
+        let padding = align_up(position, alignment) - position;
+        result.write_all(&vec![0; padding as usize]).unwrap();
+    };
+    let mut offset = 0;
+    for entry in entries {
+        match index {
+            EntryIndex::Offsets32 => result.write_u32::<BigEndian>(offset as u32).unwrap(),
+            EntryIndex::Offsets64 => result.write_u64::<BigEndian>(offset).unwrap(),
+            EntryIndex::Fixed { .. } => break,
+        }
+        offset = align_up(offset + entry_len(entry), alignment);
+    }
+    for entry in entries {
+        pad(&mut result);
+        result.write_u32::<BigEndian>(entry.0.len() as u32).unwrap();
+        result.write_all(&entry.0).unwrap();
+        match &entry.1 {
+            Some(value) => {
+                result.write_u32::<BigEndian>(value.len() as u32).unwrap();
+                result.write_all(value).unwrap();
+            }
+            None => result.write_u32::<BigEndian>(TOMBSTONE_LEN).unwrap(),
+        }
+    }
+    result.into_inner()
 }
 
-#[cfg(test)]
-mod tests {
-    use tempdir::TempDir;
+/// The first and last keys of a set of entries, both included.
+pub type KeyRange = (Vec<u8>, Vec<u8>);
+
+// Predicate on keys, shared by the sources of a range scan
+type KeyFilter<'a> = Rc<dyn Fn(&[u8]) -> bool + 'a>;
+
+/// Identifies an sstable by (level, id).
+///
+/// Lower levels hold more recent data, and within a level higher ids are more
+/// recent.
+type SSTableId = (u32, u32);
+
+// Sort key putting the most recent sstables first
+fn recency(id: SSTableId) -> (u32, Reverse<u32>) {
+    (id.0, Reverse(id.1))
+}
+
+fn sstable_name(id: SSTableId) -> String {
+    format!("{}-{}.sst", id.0, id.1)
+}
+
+/// Statistics about a memtable flush, see [`Database::last_flush_stats`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FlushStats {
+    /// Number of entries written to the sstable.
+    pub entries: u64,
+    /// Size of the sstable.
+    pub bytes: u64,
+    /// Number of writes to a key already in the memtable, which replaced its
+    /// value instead of adding an entry.
+    pub overwrites: u64,
+}
+
+/// Description of an sstable, see [`Database::sstable_info`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct SSTableInfo {
+    /// The `(level, id)` of the table.
+    pub id: (u32, u32),
+    /// Number of entries, including deletions.
+    pub entries: u64,
+    /// Size of the table.
+    pub bytes: u64,
+    /// Size of the bloom filter of the keys, 0 if there is none.
+    pub bloom_bytes: u64,
+    /// False-positive rate of the bloom filter, estimated from its size and
+    /// the number of keys, or 1 if there is none.
+    pub bloom_false_positive_rate: f64,
+    /// Statistics on the entries recorded when the table was written, or
+    /// `None` for tables written without them by an older version.
+    pub stats: Option<SSTableStats>,
+}
+
+/// Statistics on the entries of an sstable, see [`SSTableInfo`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SSTableStats {
+    /// Number of entries that are deletions.
+    pub tombstones: u64,
+    /// Average size of the keys.
+    pub average_key_len: f64,
+    /// Average size of the values, not counting deletions.
+    pub average_value_len: f64,
+}
+
+/// Counters of the database's activity since it was opened, see
+/// [`Database::stats`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DatabaseStats {
+    /// Bytes written to the WAL and to new sstables.
+    pub bytes_written: u64,
+    /// Number of keys looked up with [`Database::get`] and its variants.
+    pub reads: u64,
+    /// Number of lookups that found a value.
+    pub hits: u64,
+    /// Number of memtable flushes that wrote an sstable.
+    pub flushes: u64,
+    /// Number of compactions, including those that only moved tables.
+    pub compactions: u64,
+    /// Current number of sstables.
+    pub sstables: u64,
+    /// Current total size of the sstables.
+    pub sstable_bytes: u64,
+}
+
+impl DatabaseStats {
+    fn record_read(&mut self, hit: bool) {
+        self.reads += 1;
+        if hit {
+            self.hits += 1;
+        }
+    }
+}
+
+/// Estimated cost of a lookup, see [`Database::estimate_get_cost`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GetCost {
+    /// Whether the key is in the memtable, in which case no sstable is read.
+    pub in_memtable: bool,
+    /// Number of sstables whose key range contains the key, which the lookup
+    /// might have to read.
+    pub sstables: usize,
+}
+
+/// What happened while opening the database, see [`Database::open_report`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OpenReport {
+    /// Number of WAL records replayed into the memtable.
+    pub records_replayed: u64,
+    /// Number of sstables deleted because their write didn't complete.
+    pub incomplete_sstables_deleted: u64,
+    /// Number of bytes of the WAL read during replay.
+    pub wal_bytes_scanned: u64,
+    /// Whether the WAL ended with a partial record, from an interrupted
+    /// write, which was discarded. A record that doesn't match its checksum
+    /// is taken to be partial, and discarded along with the rest of the WAL.
+    pub trailing_discarded: bool,
+    /// Number of replayed entries dropped because the sstables already held
+    /// them, see [`DatabaseOptions::strict_replay`].
+    pub duplicates_dropped: u64,
+}
+
+/// Which database's value is kept for keys present in both, when using
+/// [`Database::merge_databases`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MergePreference {
+    Source,
+    Destination,
+}
+
+pub struct Database<S: Storage> {
+    storage: S,
+    // Ordered from most to least recent
+    sstables: Vec<(SSTableId, SSTableReader<S::Reader>)>,
+    mem_table: MemTable,
+    wal: S::Appender,
+    // Whether the clean shutdown marker is present
+    clean: bool,
+    max_levels: Option<u32>,
+    check_sstable_order: bool,
+    // Alignment of the entries in new sstables, 1 for none
+    sstable_alignment: u64,
+    // Bytes to read ahead when iterating on sstables, 0 for none
+    scan_read_ahead: u64,
+    // False-positive rate of the bloom filters of new sstables
+    bloom_false_positive_rate: f64,
+    prefix_extractor: Option<Box<PrefixExtractor>>,
+    corruption_policy: CorruptionPolicy,
+    last_flush_stats: Option<FlushStats>,
+    // Updated as operations happen, so reading them is cheap
+    stats: DatabaseStats,
+    open_report: OpenReport,
+    // Ids handed out by allocate_sstable_id and not registered yet
+    reserved_sstable_ids: HashSet<SSTableId>,
+    max_memtable_bytes: u64,
+    max_wal_bytes: Option<u64>,
+    // Size of the WAL, not counting the sstable markers
+    wal_bytes: u64,
+    sync_on_write: bool,
+}
+
+// Check that data of the given length starting at `offset` is within the
+// file, before allocating a buffer for it, so corrupt lengths don't cause
+// huge allocations
+fn check_len(offset: u64, len: u32, file_size: u64) -> Result<usize, IoError> {
+    if offset + len as u64 > file_size {
+        return Err(IoError::new(IoErrorKind::UnexpectedEof, "Length goes past the end of the file"));
+    }
+    Ok(len as usize)
+}
+
+fn read_vec<R: ReadAt>(file: R, offset: &mut u64, file_size: u64) -> Result<Vec<u8>, IoError> {
+    let mut len_buf = [0u8; 4];
+    file.read_exact_at(&mut len_buf, *offset)?;
+    *offset += 4;
+    let len = read_u32(&len_buf);
+    let mut vec = vec![0u8; check_len(*offset, len, file_size)?];
+    file.read_exact_at(&mut vec, *offset)?;
+    *offset += len as u64;
+    Ok(vec)
+}
+
+// Read a WAL record, returning its content, or None if it is cut short or
+// doesn't match its checksum
+fn read_wal_record<R: ReadAt>(wal: R, offset: &mut u64, wal_size: u64) -> Result<Option<Vec<u8>>, IoError> {
+    let mut header = [0u8; 8];
+    match wal.read_exact_at(&mut header, *offset) {
+        Err(e) if e.kind() == IoErrorKind::UnexpectedEof => return Ok(None),
+        result => result?,
+    }
+    let Ok(len) = check_len(*offset + 8, read_u32(&header[0..4]), wal_size) else {
+        return Ok(None);
+    };
+    let mut content = vec![0u8; len];
+    wal.read_exact_at(&mut content, *offset + 8)?;
+    if crc32(&content) != read_u32(&header[4..8]) {
+        return Ok(None);
+    }
+    *offset += 8 + len as u64;
+    Ok(Some(content))
+}
+
+// Find the end of the last truncate record, before which all the entries
+// are in sstables, the end of the valid records, and the names of the
+// sstables that are started, reserved or moved to in the WAL
+fn scan_wal<R: ReadAt>(wal: R, wal_size: u64) -> Result<(u64, u64, Vec<String>), IoError> {
+    let mut offset = 0;
+    let mut truncated = 0;
+    let mut table_names = Vec::new();
+    while offset < wal_size {
+        let Some(content) = read_wal_record(&wal, &mut offset, wal_size)? else {
+            break;
+        };
+        match parse_wal_record(&content) {
+            Some((Operation::Truncate, _)) => truncated = offset,
+            Some((Operation::WriteSstableStart | Operation::ReserveSstable | Operation::MoveSstable, fields)) => {
+                let name = fields.last().unwrap();
+                table_names.push(String::from_utf8_lossy(name).into_owned());
+            }
+            _ => {}
+        }
+    }
+    Ok((truncated, offset, table_names))
+}
+
+// Split the content of a WAL record into its operation and fields, or None
+// if it is not valid
+fn parse_wal_record(content: &[u8]) -> Option<(Operation, Vec<Vec<u8>>)> {
+    let (&op, mut rest) = content.split_first()?;
+    let op = match op {
+        0 => Operation::Put,
+        1 => Operation::Delete,
+        2 => Operation::WriteSstableStart,
+        3 => Operation::WriteSstableEnd,
+        4 => Operation::Truncate,
+        5 => Operation::ReserveSstable,
+        6 => Operation::MoveSstable,
+        7 => Operation::DropPrefix,
+        _ => return None,
+    };
+    let field_count = match op {
+        Operation::Put | Operation::MoveSstable => 2,
+        _ => 1,
+    };
+    let mut fields = Vec::with_capacity(field_count);
+    for _ in 0..field_count {
+        let len = read_u32(rest.get(0..4)?) as usize;
+        fields.push(rest.get(4..4 + len)?.to_vec());
+        rest = &rest[4 + len..];
+    }
+    if !rest.is_empty() {
+        return None;
+    }
+    Some((op, fields))
+}
+
+// Append a record to the WAL: the length of its content and the CRC32 of
+// it, then the content, which is the operation and its fields. Returns the
+// size of the record
+fn write_wal_record<A: Append>(mut wal: A, op: u8, fields: &[&[u8]]) -> Result<u64, IoError> {
+    let mut content = vec![op];
+    for field in fields {
+        content.write_u32::<BigEndian>(field.len() as u32)?;
+        content.extend_from_slice(field);
+    }
+    let mut record = Vec::with_capacity(8 + content.len());
+    record.write_u32::<BigEndian>(content.len() as u32)?;
+    record.write_u32::<BigEndian>(crc32(&content))?;
+    record.extend_from_slice(&content);
+    wal.append(&record)?;
+    Ok(record.len() as u64)
+}
+
+// Size of the memtable above which it is flushed, unless configured
+const DEFAULT_MAX_MEMTABLE_BYTES: u64 = 4 * 1024 * 1024;
+
+// Bytes of WAL replayed between progress callbacks
+const REPLAY_PROGRESS_INTERVAL: u64 = 64 * 1024;
+
+// Bytes of sstable copied at once by `clone_into`
+const CLONE_CHUNK_SIZE: u64 = 1024 * 1024;
+
+// Most threads reading sstables at once in `get_parallel`
+const MAX_PARALLEL_READS: usize = 16;
+
+// Written by `checkpoint`, removed before the WAL is next written to
+const CLEAN_MARKER: &str = "clean";
+
+// The valid part of the WAL, written when dropping a partial record
+pub(crate) const WAL_TEMPORARY_NAME: &str = "wal.tmp";
+
+impl<W: Storage, D: Storage> Database<SplitStorage<W, D>> {
+    /// Open a database with the WAL in `wal_storage` and the sstables in
+    /// `data_storage`.
+    pub fn open_split(wal_storage: W, data_storage: D) -> Result<Database<SplitStorage<W, D>>, Error> {
+        Database::open(SplitStorage::new(wal_storage, data_storage))
+    }
+}
+
+impl Database<SingleFileStorage> {
+    /// Open a database kept in a single file, see [`SingleFileStorage`].
+    ///
+    /// The file is created if it doesn't exist.
+    pub fn open_file<P: AsRef<Path>>(path: P) -> Result<Database<SingleFileStorage>, Error> {
+        Database::open(SingleFileStorage::open(path)?)
+    }
+}
+
+impl Database<MemoryStorage> {
+    /// Open a copy of a database written by [`SingleFileStorage`], read
+    /// from `reader`, for example a file embedded in the program.
+    ///
+    /// The content is read in memory and the source is never written to:
+    /// writes to the returned database only change the copy.
+    pub fn open_reader<R: Read + Seek>(reader: R) -> Result<Database<MemoryStorage>, Error> {
+        Database::open(SingleFileStorage::read_into_memory(reader)?)
+    }
+}
+
+impl<S: Storage> Database<S> {
+    pub fn open(storage: S) -> Result<Database<S>, Error> {
+        Database::open_with_options(storage, Default::default())
+    }
+
+    /// Open a read-only view of the sstables in storage, while another
+    /// handle might be writing to it.
+    ///
+    /// The WAL is not read, so recent writes that are not flushed yet are not
+    /// visible, and the view doesn't change afterwards.
+    pub fn open_snapshot_readonly(storage: S) -> Result<Snapshot<S>, Error> {
+        Snapshot::open(storage)
+    }
+
+    pub fn open_with_options(storage: S, mut options: DatabaseOptions) -> Result<Database<S>, Error> {
+        if options.max_levels == Some(0) {
+            return Err(Error::IoError(IoError::new(IoErrorKind::InvalidInput, "max_levels must be at least 1")));
+        }
+        if options.sstable_alignment == Some(0) {
+            return Err(Error::IoError(IoError::new(IoErrorKind::InvalidInput, "sstable_alignment must be at least 1")));
+        }
+        let sstable_alignment = options.sstable_alignment.unwrap_or(1) as u64;
+        let max_memtable_bytes = options.max_memtable_bytes.unwrap_or(DEFAULT_MAX_MEMTABLE_BYTES);
+        let bloom_false_positive_rate = options.bloom_false_positive_rate.unwrap_or(DEFAULT_BLOOM_FALSE_POSITIVE_RATE);
+        if !(bloom_false_positive_rate > 0.0 && bloom_false_positive_rate <= 1.0) {
+            return Err(Error::IoError(IoError::new(IoErrorKind::InvalidInput, "bloom_false_positive_rate must be in (0, 1]")));
+        }
+
+        let mut wal_found = false;
+        let mut clean = false;
+        let mut sstable_names = Vec::new();
+        let mut temporary_names = Vec::new();
+        for entry in storage.list()? {
+            if &entry == "wal" {
+                wal_found = true;
+            } else if entry == CLEAN_MARKER {
+                clean = true;
+            } else if entry.ends_with(".sst") {
+                sstable_names.push(entry);
+            } else if entry.ends_with(".sst.tmp") || entry == WAL_TEMPORARY_NAME {
+                temporary_names.push(entry);
+            } else {
+                return Err(Error::Corruption(Corruption::UnexpectedFile(entry)));
+            }
+        }
+
+        let stored_names: HashSet<String> = sstable_names.iter().cloned().collect();
+        if let Some(allowlist) = &options.sstable_allowlist {
+            let count = sstable_names.len();
+            sstable_names.retain(|name| allowlist.contains(name));
+            if sstable_names.len() < count {
+                info!("Ignoring {} sstables not in the allowlist", count - sstable_names.len());
+            }
+        }
+
+        // Remove sstable and WAL rewrites that didn't complete
+        for name in &temporary_names {
+            storage.delete(name)?;
+        }
+
+        let mut mem_table: MemTable = Default::default();
+        let mut sstables = Vec::new();
+        let mut incomplete_sstables = HashSet::new();
+        let mut reserved_sstables = HashSet::new();
+        let mut report = OpenReport::default();
+        // Whether an sstable was completed in the replayed part of the WAL
+        let mut replay_completed_sstable = false;
+        // Opened during replay to log the sstables written and the moves
+        // completed, then used as the WAL
+        let mut replay_wal = None;
+
+        if !wal_found && !sstable_names.is_empty() {
+            return Err(Error::Corruption(Corruption::MissingWal));
+        } else if !wal_found {
+            // Initialize new empty database
+            info!("Opening empty database");
+        } else if clean {
+            // The WAL was empty when the database was closed
+            info!("Opening cleanly closed database, skipping WAL replay");
+        } else {
+            // Open existing database
+            info!("Opening existing database, replaying WAL");
+            let mut replay_names = Vec::new();
+            // Deleted by replaying a prefix drop, as nothing was left in them
+            let mut dropped_sstables = HashSet::new();
+            let mut completed_sstables = HashSet::new();
+            // Moves whose end was not logged, by destination
+            let mut pending_moves = HashMap::new();
+            let mut wal = storage.read("wal")?;
+            let wal_size = wal.size()?;
+            // The entries before the last truncation are already in
+            // sstables, don't write them out again
+            let (replay_from, valid_size, logged_names) = scan_wal(&wal, wal_size)?;
+            // Ids for the sstables written during replay, after all the
+            // existing ones, and after those named in the WAL, which might
+            // not have been written. Otherwise a start marker replayed after
+            // writing a table would have it deleted as incomplete
+            let mut next_replay_id = stored_names.iter()
+                .chain(&logged_names)
+                .filter_map(|name| parse_sstable_name(name).ok())
+                .filter(|&(level, _)| level == 1)
+                .map(|(_, id)| id + 1)
+                .max()
+                .unwrap_or(0);
+            let mut offset = 0;
+            let mut last_progress = 0;
+            loop {
+                if let Some(callback) = &mut options.replay_progress {
+                    if offset - last_progress >= REPLAY_PROGRESS_INTERVAL || offset >= wal_size {
+                        last_progress = offset;
+                        let progress = ReplayProgress {
+                            bytes_read: offset,
+                            total_bytes: wal_size,
+                        };
+                        if !callback(progress) {
+                            return Err(Error::ReplayAborted);
+                        }
+                    }
+                }
+
+                if offset >= wal_size {
+                    break;
+                }
+
+                // Read the whole record and check it before applying it, so
+                // that a record cut short by a crash can be discarded. The
+                // WAL might have been rewritten without it, and appended to
+                let record_start = offset;
+                let content = if offset < valid_size { read_wal_record(&wal, &mut offset, valid_size)? } else { None };
+                let Some(content) = content else {
+                    warn!(
+                        "Discarding partial record at offset {}, dropping the last {} bytes of the WAL",
+                        record_start,
+                        wal_size - record_start,
+                    );
+                    report.trailing_discarded = true;
+                    break;
+                };
+                let (op, fields) = parse_wal_record(&content).ok_or(Error::Corruption(Corruption::InvalidWalEntry))?;
+                let mut fields = fields.into_iter();
+
+                match op {
+                    Operation::Put => {
+                        let key = fields.next().unwrap();
+                        let value = fields.next().unwrap();
+                        if record_start >= replay_from {
+                            mem_table.put(&key, value);
+                        }
+                        if mem_table.size() as u64 > max_memtable_bytes {
+                            if replay_wal.is_none() {
+                                replay_wal = Some(Self::open_replay_wal(&storage, &wal, valid_size, wal_size)?);
+                                wal = storage.read("wal")?;
+                            }
+                            let id = (1, next_replay_id);
+                            next_replay_id += 1;
+                            replay_names.push(Self::write_replayed_sstable(&storage, replay_wal.as_mut().unwrap(), &mem_table, id, sstable_alignment, bloom_false_positive_rate, options.prefix_extractor.as_deref())?);
+                            mem_table = Default::default();
+                        }
+                    }
+                    Operation::Delete => {
+                        let key = fields.next().unwrap();
+                        if record_start >= replay_from {
+                            // The sstables are not open yet. If there are
+                            // none, the tombstones are dropped on flush
+                            mem_table.keep_tombstones = true;
+                            mem_table.delete(&key);
+                        }
+                    }
+                    Operation::WriteSstableStart => {
+                        let table_name = fields.next().unwrap();
+                        let table_name = parse_wal_table_name(table_name)?;
+                        if incomplete_sstables.contains(&table_name) {
+                            return Err(Error::Corruption(Corruption::DuplicateSstableStart(table_name)));
+                        }
+                        incomplete_sstables.insert(table_name);
+                    }
+                    Operation::WriteSstableEnd => {
+                        let table_name = fields.next().unwrap();
+                        let table_name = parse_wal_table_name(table_name)?;
+                        if pending_moves.remove(&table_name).is_some() {
+                            report.records_replayed += 1;
+                            continue;
+                        }
+                        // Either written by the database or registered
+                        if !incomplete_sstables.remove(&table_name) && !reserved_sstables.remove(&table_name) {
+                            return Err(Error::Corruption(Corruption::UnmatchedSstableEnd(table_name)));
+                        }
+                        completed_sstables.insert(table_name);
+                    }
+                    Operation::ReserveSstable => {
+                        let table_name = fields.next().unwrap();
+                        let table_name = parse_wal_table_name(table_name)?;
+                        reserved_sstables.insert(table_name);
+                    }
+                    Operation::MoveSstable => {
+                        let from = parse_wal_table_name(fields.next().unwrap())?;
+                        let to = parse_wal_table_name(fields.next().unwrap())?;
+                        pending_moves.insert(to, from);
+                    }
+                    Operation::DropPrefix => {
+                        let prefix = fields.next().unwrap();
+                        if record_start >= replay_from {
+                            mem_table.remove_prefix(&prefix);
+                            // The sstables might not all have been rewritten.
+                            // Those named in the WAL were written after the
+                            // drop, or are incomplete
+                            for name in &sstable_names {
+                                if logged_names.contains(name) || replay_names.contains(name) || dropped_sstables.contains(name) {
+                                    continue;
+                                }
+                                if !Self::replay_drop_prefix(&storage, name, &prefix, sstable_alignment, bloom_false_positive_rate, options.prefix_extractor.as_deref())? {
+                                    dropped_sstables.insert(name.clone());
+                                }
+                            }
+                        }
+                    }
+                    Operation::Truncate => {
+                        let table_name = fields.next().unwrap();
+                        let table_name = parse_wal_table_name(table_name)?;
+                        if !completed_sstables.contains(&table_name) {
+                            return Err(Error::Corruption(Corruption::TruncateWithoutSstable(table_name)));
+                        }
+                        // Everything before this is in sstables, the
+                        // truncation was interrupted
+                        info!("Found interrupted WAL truncation after writing '{}'", table_name);
+                        mem_table = Default::default();
+                    }
+                }
+                report.records_replayed += 1;
+            }
+            report.wal_bytes_scanned = offset;
+            replay_completed_sstable = !completed_sstables.is_empty();
+
+            // Complete the interrupted moves. If the rename happened, the
+            // source is gone and only the end marker is missing
+            for (to, from) in &pending_moves {
+                if !stored_names.contains(to) {
+                    info!("Completing interrupted move of sstable '{}' to '{}'", from, to);
+                    storage.rename(from, to)?;
+                    if let Some(name) = sstable_names.iter_mut().find(|name| *name == from) {
+                        *name = to.clone();
+                    }
+                }
+            }
+
+            sstable_names.retain(|name| !dropped_sstables.contains(name));
+
+            // Complete the interrupted registrations. If the table was
+            // renamed to its reserved name, only the end marker is missing
+            let mut registered = Vec::new();
+            for name in &reserved_sstables {
+                if stored_names.contains(name) && SSTableReader::open(storage.read(name)?).is_ok() {
+                    info!("Completing interrupted registration of sstable '{}'", name);
+                    registered.push(name.clone());
+                }
+            }
+            for name in &registered {
+                reserved_sstables.remove(name);
+            }
+
+            // Remove incomplete sstables
+            info!("{} incomplete sstables to delete", incomplete_sstables.len());
+            for sstable in &incomplete_sstables {
+                storage.delete(sstable)?;
+            }
+            report.incomplete_sstables_deleted = incomplete_sstables.len() as u64;
+
+            info!("Replayed {} WAL entries", report.records_replayed);
+
+            if replay_wal.is_none() && (report.trailing_discarded || !pending_moves.is_empty() || !registered.is_empty()) {
+                replay_wal = Some(Self::open_replay_wal(&storage, &wal, valid_size, wal_size)?);
+            }
+            if let Some(replay_wal) = &mut replay_wal {
+                if !replay_names.is_empty() {
+                    // Once part of the WAL is in sstables, it can no longer
+                    // be replayed, so write the rest of it out as well, and
+                    // truncate the WAL as flush_memtable does
+                    if !mem_table.entries.is_empty() {
+                        let id = (1, next_replay_id);
+                        replay_names.push(Self::write_replayed_sstable(&storage, replay_wal, &mem_table, id, sstable_alignment, bloom_false_positive_rate, options.prefix_extractor.as_deref())?);
+                        mem_table = Default::default();
+                    }
+                    info!("Wrote {} sstables during replay, truncating WAL", replay_names.len());
+                    write_wal_record(&mut *replay_wal, 4, &[replay_names.last().unwrap().as_bytes()])?;
+                    replay_wal.flush()?;
+                    replay_wal.truncate()?;
+                    for name in &reserved_sstables {
+                        write_wal_record(&mut *replay_wal, 5, &[name.as_bytes()])?;
+                    }
+                    sstable_names.extend(replay_names);
+                } else {
+                    // Log the end of the completed moves, so that the
+                    // sources are not moved again if their names are reused,
+                    // and of the completed registrations
+                    for name in pending_moves.keys().chain(&registered) {
+                        write_wal_record(&mut *replay_wal, 3, &[name.as_bytes()])?;
+                    }
+                }
+                replay_wal.flush()?;
+            }
+        }
+
+        // Open remaining sstables. Reserved ones are not registered yet
+        for name in sstable_names {
+            if !incomplete_sstables.contains(&name) && !reserved_sstables.contains(&name) {
+                let id = parse_sstable_name(&name).map_err(|_| Error::Corruption(Corruption::InvalidTableName(name.clone())))?;
+                let reader = storage.read(&name)?;
+                let table = SSTableReader::open(reader).map_err(|e| {
+                    if e.kind() == IoErrorKind::InvalidData {
+                        Error::Corruption(Corruption::BadSstableHeader { name: name.clone(), reason: e.to_string() })
+                    } else {
+                        e.into()
+                    }
+                })?;
+                sstables.push((id, table));
+            }
+        }
+        sstables.sort_by_key(|&(id, _)| recency(id));
+
+        // Drop the replayed entries that don't change what lookups return,
+        // because the most recent sstable holding the key agrees. Entries
+        // that disagree are more recent than the sstables, and are kept
+        if options.strict_replay && replay_completed_sstable {
+            let mut duplicates = Vec::new();
+            for (key, value) in &mem_table.entries {
+                let mut stored = None;
+                for (_, sstable) in &sstables {
+                    if let Some(found) = sstable.get(key)? {
+                        stored = found;
+                        break;
+                    }
+                }
+                if stored == *value {
+                    duplicates.push(key.clone());
+                }
+            }
+            info!("Dropping {} replayed entries already in sstables", duplicates.len());
+            for key in &duplicates {
+                mem_table.remove(key);
+            }
+            report.duplicates_dropped = duplicates.len() as u64;
+        }
+
+        let reserved_sstable_ids = reserved_sstables.iter()
+            .map(|name| parse_sstable_name(name).map_err(|_| Error::Corruption(Corruption::InvalidTableName(name.clone()))))
+            .collect::<Result<HashSet<_>, _>>()?;
+
+        let stats = DatabaseStats {
+            sstables: sstables.len() as u64,
+            sstable_bytes: sstables.iter().map(|(_, table)| table.file_size).sum(),
+            ..Default::default()
+        };
+        let wal_bytes = if wal_found { storage.read("wal")?.size()? } else { 0 };
+        let wal = match replay_wal {
+            Some(wal) => wal,
+            None => storage.append("wal")?,
+        };
+        Ok(Database {
+            storage,
+            sstables,
+            mem_table,
+            wal,
+            clean,
+            max_levels: options.max_levels,
+            check_sstable_order: options.check_sstable_order,
+            sstable_alignment,
+            scan_read_ahead: options.scan_read_ahead.unwrap_or(0) as u64,
+            bloom_false_positive_rate,
+            prefix_extractor: options.prefix_extractor,
+            corruption_policy: options.corruption_policy,
+            last_flush_stats: None,
+            stats,
+            open_report: report,
+            reserved_sstable_ids,
+            max_memtable_bytes,
+            max_wal_bytes: options.max_wal_bytes,
+            wal_bytes,
+            sync_on_write: options.sync_on_write,
+        })
+    }
+
+    // Open the WAL for appending during replay. A partial record at its end
+    // is dropped first, since new records would be read as part of it. The
+    // valid part is written separately then renamed over it, so that the WAL
+    // is never left empty
+    fn open_replay_wal(storage: &S, wal: &S::Reader, valid_size: u64, wal_size: u64) -> Result<S::Appender, IoError> {
+        if valid_size < wal_size {
+            let mut valid = vec![0u8; valid_size as usize];
+            wal.read_exact_at(&mut valid, 0)?;
+            storage.write(WAL_TEMPORARY_NAME, &valid)?;
+            storage.rename(WAL_TEMPORARY_NAME, "wal")?;
+        }
+        storage.append("wal")
+    }
+
+    // Write the memtable to an sstable while replaying the WAL, between
+    // start and end markers, so that it is deleted if this is interrupted
+    fn write_replayed_sstable(storage: &S, wal: &mut S::Appender, mem_table: &MemTable, id: SSTableId, alignment: u64, bloom_rate: f64, prefix_extractor: Option<&PrefixExtractor>) -> Result<String, IoError> {
+        let name = sstable_name(id);
+        info!("Writing replayed entries to new sstable '{}'", name);
+        write_wal_record(&mut *wal, 2, &[name.as_bytes()])?;
+        wal.flush()?;
+        storage.write(&name, &write_sstable(&mem_table.entries, alignment, bloom_rate, prefix_extractor))?;
+        write_wal_record(&mut *wal, 3, &[name.as_bytes()])?;
+        Ok(name)
+    }
+
+    // Rewrite an sstable without the keys starting with `prefix`, to finish
+    // an interrupted drop_prefix while replaying the WAL. Returns false if
+    // nothing was left, in which case the table is deleted
+    fn replay_drop_prefix(storage: &S, name: &str, prefix: &[u8], alignment: u64, bloom_rate: f64, prefix_extractor: Option<&PrefixExtractor>) -> Result<bool, IoError> {
+        let sstable = SSTableReader::open(storage.read(name)?)?;
+        let end = key_successor(prefix);
+        if sstable.iter_range(prefix, end.as_deref()).next().is_none() {
+            return Ok(true);
+        }
+        let mut entries = Vec::new();
+        for entry in sstable.iter_range(&[], None) {
+            let entry = entry?;
+            if !entry.0.starts_with(prefix) {
+                entries.push(entry);
+            }
+        }
+        if entries.is_empty() {
+            info!("Deleting sstable '{}' holding only dropped keys", name);
+            storage.delete(name)?;
+            return Ok(false);
+        }
+        info!("Rewriting sstable '{}' without dropped keys", name);
+        let temporary_name = format!("{}.tmp", name);
+        storage.write(&temporary_name, &write_sstable(&entries, alignment, bloom_rate, prefix_extractor))?;
+        storage.rename(&temporary_name, name)?;
+        Ok(true)
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), IoError> {
+        self.mark_dirty()?;
+
+        // Write to WAL
+        self.wal_bytes += self.append_wal(0, &[key, value])?;
+        if self.sync_on_write {
+            self.wal.sync()?;
+        }
+
+        // Update memtable
+        self.mem_table.put(key, value.into());
+        self.flush_if_memtable_full()?;
+
+        Ok(())
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, IoError> {
+        // Read from mem table
+        if let Some(value) = self.mem_table.get(key) {
+            self.stats.record_read(value.is_some());
+            return Ok(value.map(|value| value.into()));
+        }
+
+        // Read from sstables
+        for (id, sstable) in &self.sstables {
+            if let Some(value) = self.check_truncated(*id, key, sstable.get(key))? {
+                self.stats.record_read(value.is_some());
+                return Ok(value);
+            }
+        }
+
+        self.stats.record_read(false);
+        Ok(None)
+    }
+
+    // Apply the corruption policy if a lookup ran into the end of the table
+    fn check_truncated<T>(&self, id: SSTableId, key: &[u8], result: Result<Option<T>, IoError>) -> Result<Option<T>, IoError> {
+        match result {
+            Err(e) if e.kind() == IoErrorKind::UnexpectedEof => {
+                let name = sstable_name(id);
+                match self.corruption_policy {
+                    CorruptionPolicy::Error => Err(IoError::new(
+                        IoErrorKind::InvalidData,
+                        format!("Truncated entry in sstable '{}' for key {:?}", name, String::from_utf8_lossy(key)),
+                    )),
+                    CorruptionPolicy::Skip => {
+                        warn!("Skipping truncated entry in sstable '{}' for key {:?}", name, String::from_utf8_lossy(key));
+                        Ok(None)
+                    }
+                }
+            }
+            result => result,
+        }
+    }
+
+    /// Check whether a key might be present, without reading any value.
+    ///
+    /// Returns `false` only if the key is definitely absent. Sstables are
+    /// only checked against their first and last key, their bloom filter and
+    /// the length of their keys if fixed, which can give false positives.
+    pub fn may_contain(&self, key: &[u8]) -> Result<bool, IoError> {
+        if let Some(value) = self.mem_table.get(key) {
+            return Ok(value.is_some());
+        }
+        for (_, sstable) in &self.sstables {
+            if !sstable.may_contain(key) {
+                continue;
+            }
+            if let Some((first, last)) = sstable.key_range()? {
+                if key >= first as &[u8] && key <= last as &[u8] {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Estimate how expensive looking up a key would be, without reading
+    /// any entry.
+    ///
+    /// This counts the sstables that can't be ruled out from their key
+    /// range, bloom filter and fixed key length, and which a
+    /// [`get`](Database::get) might read.
+    pub fn estimate_get_cost(&self, key: &[u8]) -> Result<GetCost, IoError> {
+        if self.mem_table.get(key).is_some() {
+            return Ok(GetCost { in_memtable: true, sstables: 0 });
+        }
+        let mut sstables = 0;
+        for (_, sstable) in &self.sstables {
+            if !sstable.may_contain(key) {
+                continue;
+            }
+            if let Some((first, last)) = sstable.key_range()? {
+                if key >= first as &[u8] && key <= last as &[u8] {
+                    sstables += 1;
+                }
+            }
+        }
+        Ok(GetCost { in_memtable: false, sstables })
+    }
+
+    /// Look up many keys, which must be sorted.
+    ///
+    /// Each sstable is searched once for all the keys, going forward from
+    /// where the previous key was found, which is faster than separate
+    /// lookups when the keys are close together.
+    pub fn get_batch_sorted(&mut self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>, IoError> {
+        if !keys.windows(2).all(|w| w[0] <= w[1]) {
+            return Err(IoError::new(IoErrorKind::InvalidInput, "Keys are not sorted"));
+        }
+
+        // Outer None until found, inner None if deleted
+        let mut results: Vec<Option<Option<Vec<u8>>>> = keys.iter()
+            .map(|key| self.mem_table.get(key).map(|value| value.map(|value| value.into())))
+            .collect();
+        for (_, sstable) in &self.sstables {
+            let pending: Vec<usize> = (0..keys.len()).filter(|&i| results[i].is_none()).collect();
+            if pending.is_empty() {
+                break;
+            }
+            let pending_keys: Vec<&[u8]> = pending.iter().map(|&i| keys[i]).collect();
+            for (i, value) in pending.into_iter().zip(sstable.get_sorted(&pending_keys)?) {
+                results[i] = value;
+            }
+        }
+        for result in &results {
+            self.stats.record_read(matches!(result, Some(Some(_))));
+        }
+        Ok(results.into_iter().map(Option::flatten).collect())
+    }
+
+    /// Look up a key, returning [`Error::KeyNotFound`] if it is absent.
+    pub fn get_required(&mut self, key: &[u8]) -> Result<Vec<u8>, Error> {
+        self.get(key)?.ok_or(Error::KeyNotFound)
+    }
+
+    /// Look up the length of a key's value.
+    ///
+    /// For a key in an sstable, only the length stored before the value is
+    /// read, not the value itself.
+    pub fn value_len(&mut self, key: &[u8]) -> Result<Option<u64>, IoError> {
+        // Read from mem table
+        if let Some(value) = self.mem_table.get(key) {
+            return Ok(value.map(|value| value.len() as u64));
+        }
+
+        // Read from sstables
+        for (id, sstable) in &self.sstables {
+            if let Some(len) = self.check_truncated(*id, key, sstable.value_len(key))? {
+                return Ok(len.map(|len| len as u64));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Look up a key, querying all the sstables concurrently.
+    ///
+    /// This is the same as [`get`](Database::get), but each sstable is read
+    /// from a separate thread, so the latency is that of the slowest table
+    /// rather than the sum. This is useful with high-latency storage.
+    ///
+    /// Tables ruled out by their bloom filter or key range are skipped, and
+    /// the others are read in batches of at most 16 threads, from most to
+    /// least recent.
+    pub fn get_parallel(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, IoError> {
+        // Read from mem table
+        if let Some(value) = self.mem_table.get(key) {
+            self.stats.record_read(value.is_some());
+            return Ok(value.map(|value| value.into()));
+        }
+
+        // Read from sstables, keeping the most recent result. A batch only
+        // needs to be read if the more recent ones didn't have the key
+        let candidates = self.sstables.iter()
+            .filter(|(_, sstable)| sstable.may_hold(key))
+            .collect::<Vec<_>>();
+        for batch in candidates.chunks(MAX_PARALLEL_READS) {
+            let results = std::thread::scope(|scope| {
+                let threads = batch.iter()
+                    .map(|(_, sstable)| scope.spawn(|| sstable.get_in_range(key)))
+                    .collect::<Vec<_>>();
+                threads.into_iter()
+                    .map(|thread| thread.join().unwrap())
+                    .collect::<Vec<_>>()
+            });
+            for ((id, _), result) in batch.iter().zip(results) {
+                if let Some(value) = self.check_truncated(*id, key, result)? {
+                    self.stats.record_read(value.is_some());
+                    return Ok(value);
+                }
+            }
+        }
+
+        self.stats.record_read(false);
+        Ok(None)
+    }
+
+    /// Look up a key in a single level.
+    ///
+    /// Level 0 is the memtable, sstables are in levels 1 and up. This exposes
+    /// the value stored at that level even if a more recent one shadows it.
+    pub fn get_from_level(&mut self, key: &[u8], level: u32) -> Result<Option<Vec<u8>>, IoError> {
+        if level == 0 {
+            return Ok(self.mem_table.get(key).flatten().map(|value| value.into()));
+        }
+
+        // Tables of the level are ordered from most to least recent
+        for (id, sstable) in &self.sstables {
+            if id.0 == level {
+                if let Some(value) = self.check_truncated(*id, key, sstable.get(key))? {
+                    return Ok(value);
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Look up a key, failing with a [`TimedOut`](IoErrorKind::TimedOut)
+    /// error if the lookup is not done by `deadline`.
+    ///
+    /// The deadline is checked before reading each sstable; a read that is
+    /// already in progress is not interrupted.
+    pub fn get_with_deadline(&mut self, key: &[u8], deadline: Instant) -> Result<Option<Vec<u8>>, IoError> {
+        // Read from mem table
+        if let Some(value) = self.mem_table.get(key) {
+            self.stats.record_read(value.is_some());
+            return Ok(value.map(|value| value.into()));
+        }
+
+        // Read from sstables
+        for (id, sstable) in &self.sstables {
+            if Instant::now() >= deadline {
+                return Err(IoError::new(IoErrorKind::TimedOut, "Lookup deadline exceeded"));
+            }
+            if let Some(value) = self.check_truncated(*id, key, sstable.get(key))? {
+                self.stats.record_read(value.is_some());
+                return Ok(value);
+            }
+        }
+
+        self.stats.record_read(false);
+        Ok(None)
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<(), IoError> {
+        self.mark_dirty()?;
+
+        // Write to WAL
+        self.wal_bytes += self.append_wal(1, &[key])?;
+        if self.sync_on_write {
+            self.wal.sync()?;
+        }
+
+        // Update memtable
+        self.mem_table.keep_tombstones = !self.sstables.is_empty();
+        self.mem_table.delete(key);
+        self.flush_if_memtable_full()?;
+
+        Ok(())
+    }
+
+    // Flush the memtable after a write if it went over the limit, see
+    // DatabaseOptions::max_memtable_bytes
+    fn flush_if_memtable_full(&mut self) -> Result<(), IoError> {
+        if self.mem_table.size() as u64 > self.max_memtable_bytes {
+            info!("Memtable is over {} bytes, flushing", self.max_memtable_bytes);
+            self.flush_memtable(true)?;
+        }
+        Ok(())
+    }
+
+    /// Copy all the entries of `src` into `dst`.
+    ///
+    /// Deletions recorded in `src` are applied to `dst` too. For keys present
+    /// in both databases, `preference` selects which value is kept, or
+    /// whether the key is deleted.
+    pub fn merge_databases<T: Storage>(dst: &mut Database<S>, src: Database<T>, preference: MergePreference) -> Result<(), IoError> {
+        let mut copied = 0;
+        let mut deleted = 0;
+        for entry in merge_sorted_runs(src.range_runs(&[], None, true, None), true, false) {
+            let (key, value) = entry?;
+            if preference == MergePreference::Destination && dst.get(&key)?.is_some() {
+                continue;
+            }
+            match value {
+                Some(value) => {
+                    dst.put(&key, &value)?;
+                    copied += 1;
+                }
+                None => {
+                    if dst.get(&key)?.is_some() {
+                        dst.delete(&key)?;
+                        deleted += 1;
+                    }
+                }
+            }
+        }
+        info!("Merged {} entries and {} deletions from other database", copied, deleted);
+        Ok(())
+    }
+
+    /// What happened while opening the database, such as how much of the
+    /// WAL was replayed.
+    pub fn open_report(&self) -> &OpenReport {
+        &self.open_report
+    }
+
+    /// Total length of the keys and values in the memtable, which is flushed
+    /// once this goes over [`DatabaseOptions::max_memtable_bytes`].
+    ///
+    /// Deleted keys count their length.
+    pub fn memtable_size(&self) -> u64 {
+        self.mem_table.size() as u64
+    }
+
+    /// Statistics about the last memtable flush, if any since the database
+    /// was opened.
+    pub fn last_flush_stats(&self) -> Option<FlushStats> {
+        self.last_flush_stats
+    }
+
+    /// Counters of the database's activity since it was opened, and the
+    /// current number and size of the sstables.
+    ///
+    /// The counters are kept up to date as operations happen, so this is
+    /// cheap and doesn't access storage.
+    pub fn stats(&self) -> DatabaseStats {
+        self.stats
+    }
+
+    /// Describe the sstables, from most to least recent.
+    pub fn sstable_info(&self) -> Vec<SSTableInfo> {
+        self.sstables.iter()
+            .map(|(id, sstable)| SSTableInfo {
+                id: *id,
+                entries: sstable.size as u64,
+                bytes: sstable.file_size,
+                bloom_bytes: sstable.bloom.as_ref().map_or(0, |bloom| bloom.bits().len() as u64),
+                bloom_false_positive_rate: sstable.bloom.as_ref().map_or(1.0, |bloom| bloom.false_positive_rate(sstable.size)),
+                stats: sstable.stats.map(|(tombstones, key_bytes, value_bytes)| {
+                    let average = |bytes: u64, count: u64| if count == 0 { 0.0 } else { bytes as f64 / count as f64 };
+                    SSTableStats {
+                        tombstones,
+                        average_key_len: average(key_bytes, sstable.size as u64),
+                        average_value_len: average(value_bytes, sstable.size as u64 - tombstones),
+                    }
+                }),
+            })
+            .collect()
+    }
+
+    /// Size of the sstable that flushing the memtable would write.
+    pub fn estimated_flush_bytes(&self) -> u64 {
+        if self.sstables.is_empty() {
+            // The flush drops the tombstones, see flush_memtable()
+            let entries: Vec<Entry> = self.mem_table.entries.iter()
+                .filter(|(_, value)| value.is_some())
+                .cloned()
+                .collect();
+            return sstable_len(&entries, self.sstable_alignment, self.bloom_false_positive_rate, self.prefix_extractor.as_deref());
+        }
+        sstable_len(&self.mem_table.entries, self.sstable_alignment, self.bloom_false_positive_rate, self.prefix_extractor.as_deref())
+    }
+
+    /// Load the index of every sstable in memory.
+    ///
+    /// This makes the following lookups faster, at the cost of memory.
+    pub fn warmup(&mut self) -> Result<(), IoError> {
+        for (_, sstable) in &mut self.sstables {
+            sstable.load_offsets()?;
+        }
+        Ok(())
+    }
+
+    /// Release the memory the memtable holds beyond its current entries.
+    ///
+    /// If there are no sstables, the tombstones of deleted keys are dropped
+    /// as well. Otherwise they are kept until the next flush, since they
+    /// hide the older entries of those keys.
+    pub fn shrink_memtable(&mut self) {
+        if self.sstables.is_empty() {
+            self.mem_table.drop_tombstones();
+        }
+        self.mem_table.shrink_to_fit();
+    }
+
+    /// Iterate on the entries with keys in `[key_start, key_end)`, in order.
+    ///
+    /// Iteration stops at the first I/O error, which can then be retrieved
+    /// with [`RangeIterator::take_error`]. Use
+    /// [`try_iter_range`](Database::try_iter_range) to get errors as items.
+    pub fn iter_range<'a>(&'a mut self, key_start: &'a [u8], key_end: &'a [u8]) -> RangeIterator<'a, S> {
+        RangeIterator::new(self.try_iter_range(key_start, key_end))
+    }
+
+    /// Iterate on the entries with keys in `[key_start, key_end)`, in order,
+    /// returning I/O errors as items.
+    pub fn try_iter_range<'a>(&'a mut self, key_start: &'a [u8], key_end: &'a [u8]) -> TryRangeIterator<'a, S> {
+        TryRangeIterator {
+            merged: self.merge_range(key_start, Some(key_end), true, None),
+            prefix: None,
+        }
+    }
+
+    /// Iterate on the entries with keys in `[key_start, key_end)` that match
+    /// `filter`, in order.
+    ///
+    /// The filter is applied to the keys before the values are read, so
+    /// values of entries that are filtered out are never loaded.
+    pub fn iter_range_filtered<'a, F: Fn(&[u8]) -> bool + 'a>(&'a mut self, key_start: &'a [u8], key_end: &'a [u8], filter: F) -> TryRangeIterator<'a, S> {
+        TryRangeIterator {
+            merged: self.merge_range(key_start, Some(key_end), true, Some(Rc::new(filter))),
+            prefix: None,
+        }
+    }
+
+    /// Iterate on the entries whose key starts with `prefix`, in order.
+    ///
+    /// If [`DatabaseOptions::prefix_extractor`] returns `prefix` itself for
+    /// it, the sstables whose prefix filter rules it out are not read.
+    pub fn iter_prefix<'a>(&'a mut self, prefix: &'a [u8]) -> TryRangeIterator<'a, S> {
+        let mut runs = self.range_runs(prefix, None, true, None);
+        let filtered = !prefix.is_empty() && self.prefix_extractor.as_ref().is_some_and(|extractor| extractor(prefix) == prefix);
+        if filtered {
+            runs.retain(|run| match run {
+                RangeSource::SSTable(iter) => iter.sstable.may_contain_prefix(prefix),
+                _ => true,
+            });
+        }
+        TryRangeIterator {
+            merged: merge_sorted_runs(runs, true, true),
+            prefix: Some(prefix),
+        }
+    }
+
+    /// Iterate on all entries, along with the sstable they were read from.
+    ///
+    /// The source is the `(level, id)` of the sstable holding the most recent
+    /// version of the entry, or `None` if it is in the memtable.
+    pub fn iter_with_level(&mut self) -> LevelIterator<'_, S> {
+        LevelIterator {
+            database: self,
+            merged: self.merge_range(&[], None, true, None),
+        }
+    }
+
+    /// Returns the smallest and largest key of each level's sstables.
+    ///
+    /// Levels are listed in order. The range is `None` if all the sstables of
+    /// the level are empty.
+    pub fn level_ranges(&mut self) -> Result<Vec<(u32, Option<KeyRange>)>, IoError> {
+        let mut levels: Vec<(u32, Option<KeyRange>)> = Vec::new();
+        // Tables are sorted by level
+        for ((level, _), sstable) in &self.sstables {
+            if levels.last().is_none_or(|&(l, _)| l != *level) {
+                levels.push((*level, None));
+            }
+            let range = &mut levels.last_mut().unwrap().1;
+            if let Some((first, last)) = sstable.key_range()? {
+                match range {
+                    None => *range = Some((first.clone(), last.clone())),
+                    Some((min, max)) => {
+                        if first < min {
+                            *min = first.clone();
+                        }
+                        if last > max {
+                            *max = last.clone();
+                        }
+                    }
+                }
+            }
+        }
+        Ok(levels)
+    }
+
+    /// Returns about `n` keys, sorted and spread evenly across the keyspace.
+    ///
+    /// Each source contributes samples in proportion to its number of
+    /// entries, read at evenly spaced positions of its offset table, so this
+    /// reads a few entries per table instead of scanning. Deleted keys can be
+    /// returned until compaction drops their tombstones.
+    pub fn sample_keys(&self, n: usize) -> Result<Vec<Vec<u8>>, IoError> {
+        let total = self.mem_table.entries.len()
+            + self.sstables.iter().map(|(_, sstable)| sstable.size).sum::<usize>();
+        if n == 0 || total == 0 {
+            return Ok(Vec::new());
+        }
+        // Evenly spaced indices, for a source of this size. Sources can hold
+        // interleaved keys, so they are sampled at staggered positions, and
+        // more samples than needed are taken to pick among them once merged
+        let sources = 1 + self.sstables.len();
+        let positions = |size: usize, source: usize| {
+            let count = (size * n * sources).div_ceil(total).min(size);
+            (0..count).map(move |i| (i * sources + source) * size / (count * sources))
+        };
+
+        let mut samples = Vec::new();
+        for i in positions(self.mem_table.entries.len(), 0) {
+            samples.push(self.mem_table.entries[i].0.clone());
+        }
+        for (source, (_, sstable)) in self.sstables.iter().enumerate() {
+            for i in positions(sstable.size, source + 1) {
+                samples.push(sstable.read_key(i)?);
+            }
+        }
+        samples.sort();
+        samples.dedup();
+
+        if samples.len() > n {
+            let len = samples.len();
+            samples = (0..n).map(|i| std::mem::take(&mut samples[i * len / n])).collect();
+        }
+        Ok(samples)
+    }
+
+    /// Returns, for each pair of sstables, whether their key ranges overlap.
+    ///
+    /// Tables are identified by level and id, and listed from most to least
+    /// recent. Empty tables overlap nothing.
+    pub fn overlap_graph(&self) -> Result<Vec<(SSTableId, SSTableId, bool)>, IoError> {
+        let mut ranges = Vec::with_capacity(self.sstables.len());
+        for (id, sstable) in &self.sstables {
+            ranges.push((*id, sstable.key_range()?));
+        }
+        let mut graph = Vec::new();
+        for (i, (id1, range1)) in ranges.iter().enumerate() {
+            for (id2, range2) in &ranges[i + 1..] {
+                let overlap = match (range1, range2) {
+                    (Some((first1, last1)), Some((first2, last2))) => first1 <= last2 && first2 <= last1,
+                    _ => false,
+                };
+                graph.push((*id1, *id2, overlap));
+            }
+        }
+        Ok(graph)
+    }
+
+    /// Iterate on the distinct key prefixes up to the first `separator`.
+    ///
+    /// A key that doesn't contain the separator is its own prefix. Rather than
+    /// scanning every key, each prefix is found by seeking past the previous
+    /// one, so this is efficient even if each prefix has many keys.
+    pub fn iter_prefixes(&mut self, separator: u8) -> PrefixIterator<'_, S> {
+        PrefixIterator {
+            database: self,
+            separator,
+            next_start: Some(Vec::new()),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Start a paginated scan of the entries with keys from `key_start`.
+    ///
+    /// Each page returns a [`Cursor`] that can be used to resume the scan
+    /// later, even from another process, by passing its key to `scan_from`.
+    pub fn scan_from(&mut self, key_start: &[u8]) -> Scan<'_, S> {
+        Scan {
+            database: self,
+            next_start: Some(key_start.to_owned()),
+        }
+    }
+
+    /// Count the keys in `[key_start, key_end)`, without reading the values.
+    pub fn count_range(&mut self, key_start: &[u8], key_end: &[u8]) -> Result<u64, IoError> {
+        if key_start >= key_end {
+            return Ok(0);
+        }
+
+        // If only one source has keys in the range and no tombstones, there
+        // is nothing to merge
+        let counts = self.source_counts(key_start, key_end)?;
+        let mut sources = counts.iter().enumerate().filter(|&(_, &c)| c > 0);
+        match (sources.next(), sources.next()) {
+            (None, _) => return Ok(0),
+            (Some((0, &count)), None)
+                if self.mem_table.iter_range(key_start, key_end).all(|(_, value)| value.is_some()) =>
+            {
+                return Ok(count as u64);
+            }
+            (Some((i, &count)), None) if i > 0 && !self.sstables[i - 1].1.tombstones => {
+                return Ok(count as u64);
+            }
+            _ => {}
+        }
+
+        let mut count = 0;
+        for entry in self.merge_range(key_start, Some(key_end), false, None) {
+            entry?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Estimate the number of keys in `[key_start, key_end)`.
+    ///
+    /// This only binary searches the index of each sstable, and adds up the
+    /// counts. Keys present in multiple sstables are counted multiple times,
+    /// so this is an upper bound.
+    pub fn estimate_keys_between(&mut self, key_start: &[u8], key_end: &[u8]) -> Result<u64, IoError> {
+        if key_start >= key_end {
+            return Ok(0);
+        }
+
+        let counts = self.source_counts(key_start, key_end)?;
+        Ok(counts.iter().sum::<usize>() as u64)
+    }
+
+    // Number of entries in the range in the memtable then in each sstable
+    fn source_counts(&self, key_start: &[u8], key_end: &[u8]) -> Result<Vec<usize>, IoError> {
+        let mut counts = Vec::with_capacity(1 + self.sstables.len());
+        counts.push(self.mem_table.iter_range(key_start, key_end).count());
+        for (_, sstable) in &self.sstables {
+            counts.push(sstable.lower_bound(key_end)? - sstable.lower_bound(key_start)?);
+        }
+        Ok(counts)
+    }
+
+    fn merge_range<'a>(&'a self, key_start: &'a [u8], key_end: Option<&'a [u8]>, values: bool, filter: Option<KeyFilter<'a>>) -> MergedRuns<RangeSource<'a, S::Reader>> {
+        merge_sorted_runs(self.range_runs(key_start, key_end, values, filter), true, true)
+    }
+
+    // The memtable and sstables as sorted runs over the range, most recent
+    // first
+    fn range_runs<'a>(&'a self, key_start: &'a [u8], key_end: Option<&'a [u8]>, values: bool, filter: Option<KeyFilter<'a>>) -> Vec<RangeSource<'a, S::Reader>> {
+        let mut runs = Vec::with_capacity(1 + self.sstables.len());
+        let mem_table = match key_end {
+            Some(key_end) => self.mem_table.iter_range(key_start, key_end),
+            None => self.mem_table.iter_from(key_start),
+        };
+        runs.push(match (&filter, values) {
+            (Some(filter), _) => RangeSource::MemTableFiltered(mem_table, filter.clone(), values),
+            (None, true) => RangeSource::MemTable(mem_table),
+            (None, false) => RangeSource::MemTableKeys(mem_table),
+        });
+        for (_, sstable) in &self.sstables {
+            let mut iter = sstable.iter_range(key_start, key_end);
+            iter.read_values = values;
+            iter.filter = filter.clone();
+            iter.read_ahead = self.scan_read_ahead;
+            runs.push(RangeSource::SSTable(iter));
+        }
+        runs
+    }
+
+    pub fn maintain(&mut self) -> Result<(), IoError> {
+        // TODO: Merge tables
+
+        self.flush_memtable(true)?;
+        Ok(())
+    }
+
+    /// Write the memtable to a new sstable and truncate the WAL.
+    ///
+    /// Returns the `(level, id)` of the new sstable, or `None` if the memtable
+    /// was empty, in which case nothing is written.
+    pub fn flush(&mut self) -> Result<Option<SSTableId>, IoError> {
+        self.flush_memtable(true)
+    }
+
+    /// Flush the memtable if it or the WAL is larger than the configured
+    /// limits, see [`DatabaseOptions::max_memtable_bytes`] and
+    /// [`DatabaseOptions::max_wal_bytes`].
+    ///
+    /// Returns whether a flush happened. This is cheap when none is needed, so
+    /// it can be called periodically.
+    pub fn flush_if_needed(&mut self) -> Result<bool, IoError> {
+        let memtable_full = self.mem_table.size() as u64 > self.max_memtable_bytes;
+        let wal_full = self.max_wal_bytes.is_some_and(|max| self.wal_bytes > max);
+        if !memtable_full && !wal_full {
+            return Ok(false);
+        }
+        self.flush_memtable(true)?;
+        Ok(true)
+    }
+
+    /// Flush the memtable to an sstable but leave the WAL in place.
+    ///
+    /// This leaves the database in the state of a crash between the sstable
+    /// write and the WAL truncation, so that the next `open` has to replay
+    /// records that are already in an sstable.
+    #[cfg(test)]
+    pub(crate) fn flush_without_wal_truncation(&mut self) -> Result<(), IoError> {
+        self.flush_memtable(false)?;
+        Ok(())
+    }
+
+    fn flush_memtable(&mut self, truncate_wal: bool) -> Result<Option<SSTableId>, IoError> {
+        if self.sstables.is_empty() {
+            // Tombstones are only needed to hide older entries
+            self.mem_table.drop_tombstones();
+        }
+        if self.mem_table.entries.is_empty() {
+            // Don't write an empty sstable. Nothing in the WAL needs to be
+            // kept, so it can still be truncated
+            if truncate_wal {
+                self.wal.truncate()?;
+                self.wal_bytes = 0;
+                self.log_reservations()?;
+            }
+            return Ok(None);
+        }
+
+        self.mark_dirty()?;
+
+        // Write memtable to disk
+        let new_id = (1, self.next_sstable_id(1));
+        let new_name = sstable_name(new_id);
+        info!("Writing memtable to new sstable '{}'", new_name);
+
+        self.append_wal(2, &[new_name.as_bytes()])?;
+        self.wal.flush()?;
+
+        let buf = self.encode_sstable(&self.mem_table.entries)?;
+        self.store_sstable(&new_name, &buf)?;
+        self.stats.flushes += 1;
+        let stats = FlushStats {
+            entries: self.mem_table.entries.len() as u64,
+            bytes: buf.len() as u64,
+            overwrites: self.mem_table.overwrites,
+        };
+
+        self.append_wal(3, &[new_name.as_bytes()])?;
+        info!("New sstable write complete");
+
+        // Open new memtable
+        let reader = self.storage.read(&new_name)?;
+        let table = SSTableReader::open(reader)?;
+        self.insert_sstable(new_id, table);
+        self.mem_table = Default::default();
+        self.last_flush_stats = Some(stats);
+
+        // Truncate WAL, recording which sstable holds its content in case
+        // the truncation is interrupted
+        if truncate_wal {
+            info!("Truncating WAL");
+            self.append_wal(4, &[new_name.as_bytes()])?;
+            self.wal.flush()?;
+            self.wal.truncate()?;
+            self.wal_bytes = 0;
+            self.log_reservations()?;
+        }
+
+        Ok(Some(new_id))
+    }
+
+    /// Physically remove keys from the database.
+    ///
+    /// The keys are deleted and the memtable is flushed, so that the WAL no
+    /// longer holds them. Then every sstable containing them or their
+    /// tombstones is rewritten without them, instead of waiting for
+    /// compaction to drop them.
+    pub fn purge_keys(&mut self, keys: &[&[u8]]) -> Result<(), IoError> {
+        let purged: HashSet<&[u8]> = keys.iter().copied().collect();
+        self.purge(
+            keys,
+            |sstable| {
+                for key in &purged {
+                    if sstable.may_contain(key) && sstable.binary_search(key)?.1.is_some() {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            },
+            |key| purged.contains(key),
+        )
+    }
+
+    // Delete the keys, then rewrite the sstables for which `holds` returns
+    // true without the entries and tombstones whose key `matches`
+    fn purge<H, M>(&mut self, keys: &[&[u8]], holds: H, matches: M) -> Result<(), IoError>
+    where
+        H: Fn(&SSTableReader<S::Reader>) -> Result<bool, IoError>,
+        M: Fn(&[u8]) -> bool,
+    {
+        for key in keys {
+            self.delete(key)?;
+        }
+        self.flush_memtable(true)?;
+        self.purge_sstables(holds, matches)
+    }
+
+    // Rewrite the sstables for which `holds` returns true without the
+    // entries and tombstones whose key `matches`
+    fn purge_sstables<H, M>(&mut self, holds: H, matches: M) -> Result<(), IoError>
+    where
+        H: Fn(&SSTableReader<S::Reader>) -> Result<bool, IoError>,
+        M: Fn(&[u8]) -> bool,
+    {
+        // Least recent first, so that if this is interrupted, the tombstones
+        // are still there to hide the older entries
+        for i in (0..self.sstables.len()).rev() {
+            let (id, sstable) = &self.sstables[i];
+            if !holds(sstable)? {
+                continue;
+            }
+            let id = *id;
+
+            let mut entries = Vec::new();
+            for entry in sstable.iter_range(&[], None) {
+                let entry = entry?;
+                if !matches(&entry.0) {
+                    entries.push(entry);
+                }
+            }
+
+            if entries.is_empty() {
+                info!("Deleting sstable '{}' holding only purged keys", sstable_name(id));
+                self.remove_sstables(&[id]);
+                self.storage.delete(&sstable_name(id))?;
+            } else {
+                info!("Rewriting sstable '{}' without purged keys", sstable_name(id));
+                self.rewrite_sstable(i, &entries)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Delete all the keys starting with `prefix`.
+    ///
+    /// The drop is recorded in the WAL as a single operation, rather than a
+    /// deletion for each key. Then the sstables whose key range overlaps
+    /// the prefix are rewritten without the keys and their tombstones, as
+    /// with [`purge_keys`](Database::purge_keys). If this is interrupted,
+    /// the rewrite is finished when the database is next opened. Returns the
+    /// number of keys that were deleted.
+    pub fn drop_prefix(&mut self, prefix: &[u8]) -> Result<u64, IoError> {
+        // Flush first, so that the drop only applies to the sstables
+        self.flush_memtable(true)?;
+        let end = key_successor(prefix);
+        let mut count = 0;
+        for entry in self.merge_range(prefix, end.as_deref(), false, None) {
+            entry?;
+            count += 1;
+        }
+        info!("Dropping {} keys with prefix {:?}", count, String::from_utf8_lossy(prefix));
+
+        self.mark_dirty()?;
+        self.append_wal(7, &[prefix])?;
+        self.wal.flush()?;
+        self.purge_sstables(
+            |sstable| {
+                Ok(sstable.key_range()?.is_some_and(|(first, last)| {
+                    last.as_slice() >= prefix && end.as_ref().is_none_or(|end| first < end)
+                }))
+            },
+            |key| key.starts_with(prefix),
+        )?;
+
+        // The drop is complete, truncate the WAL which only holds its record
+        self.flush_memtable(true)?;
+        Ok(count)
+    }
+
+    /// Rewrite the sstables whose keys are not in order.
+    ///
+    /// Lookups in such a table can silently fail. Its entries are sorted and
+    /// only the last one for each key is kept. This is a last resort salvage
+    /// tool, the result is only as good as the damaged table's contents.
+    ///
+    /// Returns the number of sstables that were rewritten.
+    pub fn repair_ordering(&mut self) -> Result<usize, IoError> {
+        let mut repaired = 0;
+        for i in 0..self.sstables.len() {
+            let (id, sstable) = &self.sstables[i];
+            let mut entries = Vec::with_capacity(sstable.size);
+            let mut sorted = true;
+            for entry in sstable.iter_range(&[], None) {
+                let entry = entry?;
+                if entries.last().is_some_and(|(last, _): &Entry| *last >= entry.0) {
+                    sorted = false;
+                }
+                entries.push(entry);
+            }
+            if sorted {
+                continue;
+            }
+
+            info!("Rewriting mis-ordered sstable '{}'", sstable_name(*id));
+            // The sort is stable, so reversing first keeps the last entry for
+            // each key
+            entries.reverse();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            entries.dedup_by(|a, b| a.0 == b.0);
+            self.rewrite_sstable(i, &entries)?;
+            repaired += 1;
+        }
+
+        Ok(repaired)
+    }
+
+    // Serialize an sstable, checking the order of the entries if enabled
+    fn encode_sstable(&self, entries: &[Entry]) -> Result<Vec<u8>, IoError> {
+        if self.check_sstable_order {
+            check_sorted(entries)?;
+        }
+        Ok(write_sstable(entries, self.sstable_alignment, self.bloom_false_positive_rate, self.prefix_extractor.as_deref()))
+    }
+
+    // Write an encoded sstable to storage. With sync_on_write, it is synced,
+    // since the WAL entries it holds can be truncated once it is complete
+    fn store_sstable(&mut self, name: &str, buf: &[u8]) -> Result<(), IoError> {
+        if self.sync_on_write {
+            let mut file = self.storage.append(name)?;
+            file.truncate()?;
+            file.append(buf)?;
+            file.sync()?;
+        } else {
+            self.storage.write(name, buf)?;
+        }
+        self.stats.bytes_written += buf.len() as u64;
+        Ok(())
+    }
+
+    // Write the new table under a temporary name, then replace the original,
+    // keeping the same name and therefore recency
+    fn rewrite_sstable(&mut self, i: usize, entries: &[Entry]) -> Result<(), IoError> {
+        let name = sstable_name(self.sstables[i].0);
+        let temporary_name = format!("{}.tmp", name);
+        let buf = self.encode_sstable(entries)?;
+        self.store_sstable(&temporary_name, &buf)?;
+        self.storage.rename(&temporary_name, &name)?;
+        let reader = self.storage.read(&name)?;
+        let table = SSTableReader::open(reader)?;
+        self.stats.sstable_bytes = self.stats.sstable_bytes - self.sstables[i].1.file_size + table.file_size;
+        self.sstables[i].1 = table;
+        Ok(())
+    }
+
+    /// Merge all the sstables of `level` with those of the next level.
+    ///
+    /// The result is written as a single sstable in level `level + 1`, and
+    /// the input tables are deleted. If `level` is the last level allowed by
+    /// [`DatabaseOptions::max_levels`], its tables are merged into a single
+    /// sstable in the same level instead.
+    ///
+    /// If the key ranges of the tables don't overlap, the tables of `level`
+    /// are moved to the next level without being rewritten.
+    pub fn compact(&mut self, level: u32) -> Result<(), IoError> {
+        if !self.sstables.iter().any(|&((l, _), _)| l == level) {
+            return Ok(());
+        }
+        self.stats.compactions += 1;
+
+        let target = match self.max_levels {
+            Some(max) if level >= max => level,
+            _ => level + 1,
+        };
+        if target != level && self.is_disjoint(level, target)? {
+            return self.move_level(level, target);
+        }
+
+        let new_id = (target, self.next_sstable_id(target));
+        let new_name = sstable_name(new_id);
+        info!("Compacting level {} into new sstable '{}'", level, new_name);
+
+        // Tables are already ordered most recent first
+        let inputs: Vec<SSTableId> = self.sstables.iter()
+            .map(|&(id, _)| id)
+            .filter(|&(l, _)| l == level || l == target)
+            .collect();
+        let runs = self.sstables.iter()
+            .filter(|&&((l, _), _)| l == level || l == target)
+            .map(|(_, sstable)| {
+                let mut iter = sstable.iter_range(&[], None);
+                iter.read_ahead = self.scan_read_ahead;
+                iter
+            })
+            .collect();
+        // Tombstones can be dropped once no older table is left for them to
+        // hide
+        let bottom = !self.sstables.iter().any(|&((l, _), _)| l > target);
+        let mut entries = Vec::new();
+        for entry in merge_sorted_runs(runs, true, bottom) {
+            entries.push(entry?);
+        }
+
+        self.mark_dirty()?;
+        self.append_wal(2, &[new_name.as_bytes()])?;
+        self.wal.flush()?;
+
+        let buf = self.encode_sstable(&entries)?;
+        self.store_sstable(&new_name, &buf)?;
+
+        self.append_wal(3, &[new_name.as_bytes()])?;
+
+        // Swap tables
+        self.remove_sstables(&inputs);
+        let reader = self.storage.read(&new_name)?;
+        let table = SSTableReader::open(reader)?;
+        self.insert_sstable(new_id, table);
+
+        // Least recent first, so that if this is interrupted, the tombstones
+        // the new table dropped are still there to hide the older entries
+        for id in inputs.iter().rev() {
+            self.storage.delete(&sstable_name(*id))?;
+        }
+        info!("Compaction complete");
+
+        Ok(())
+    }
+
+    /// Merge the sstables chosen by `strategy`, such as a
+    /// [`SizeTieredStrategy`].
+    ///
+    /// Each run of tables is merged into a single table, which replaces the
+    /// most recent table of the run, keeping its name and level. Returns the
+    /// number of runs that were merged.
+    ///
+    /// Tombstones are only dropped from a run ending with the least recent
+    /// table, once the other tables of the run are deleted, which takes a
+    /// second rewrite.
+    pub fn compact_with<C: CompactionStrategy + ?Sized>(&mut self, strategy: &C) -> Result<usize, IoError> {
+        let runs = strategy.select(&self.sstable_info());
+        let mut end = 0;
+        for run in &runs {
+            if run.start < end || run.is_empty() || run.end > self.sstables.len() {
+                return Err(IoError::new(IoErrorKind::InvalidInput, "Compaction strategy selected invalid tables"));
+            }
+            end = run.end;
+        }
+        // Least recent first, so that the indices of the next runs are not
+        // changed by the removed tables
+        for run in runs.iter().rev() {
+            self.merge_tables(run.clone())?;
+        }
+        Ok(runs.len())
+    }
+
+    // Merge adjacent tables into the most recent of them. The merged table
+    // keeps the tombstones until the older inputs are deleted, so that if
+    // this is interrupted, they only hold entries that it overrides
+    fn merge_tables(&mut self, run: Range<usize>) -> Result<(), IoError> {
+        self.stats.compactions += 1;
+        let name = sstable_name(self.sstables[run.start].0);
+        info!("Merging {} sstables into '{}'", run.len(), name);
+
+        let runs = self.sstables[run.clone()].iter()
+            .map(|(_, sstable)| {
+                let mut iter = sstable.iter_range(&[], None);
+                iter.read_ahead = self.scan_read_ahead;
+                iter
+            })
+            .collect();
+        let bottom = run.end == self.sstables.len();
+        let mut entries = Vec::new();
+        for entry in merge_sorted_runs(runs, true, false) {
+            entries.push(entry?);
+        }
+
+        let mut inputs: Vec<SSTableId> = self.sstables[run.start + 1..run.end].iter().map(|&(id, _)| id).collect();
+        if entries.is_empty() {
+            inputs.push(self.sstables[run.start].0);
+        } else if !inputs.is_empty() {
+            self.rewrite_sstable(run.start, &entries)?;
+        }
+        self.remove_sstables(&inputs);
+        for id in inputs {
+            self.storage.delete(&sstable_name(id))?;
+        }
+
+        // Tombstones can be dropped once there is no older table
+        if bottom && entries.iter().any(|(_, value)| value.is_none()) {
+            entries.retain(|(_, value)| value.is_some());
+            if entries.is_empty() {
+                let id = self.sstables[run.start].0;
+                self.remove_sstables(&[id]);
+                self.storage.delete(&sstable_name(id))?;
+            } else {
+                self.rewrite_sstable(run.start, &entries)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Whether the tables of `level` and `target` have no overlapping key
+    // ranges, so that their order doesn't matter
+    fn is_disjoint(&self, level: u32, target: u32) -> Result<bool, IoError> {
+        let mut ranges = Vec::new();
+        for ((l, _), sstable) in &self.sstables {
+            if *l == level || *l == target {
+                if let Some(range) = sstable.key_range()? {
+                    ranges.push(range);
+                }
+            }
+        }
+        ranges.sort();
+        Ok(ranges.windows(2).all(|w| w[0].1 < w[1].0))
+    }
+
+    // Move the tables of `level` to `target` by renaming them
+    fn move_level(&mut self, level: u32, target: u32) -> Result<(), IoError> {
+        // Least recent first, so they get increasing ids in the same order
+        let moved: Vec<SSTableId> = self.sstables.iter().rev()
+            .map(|&(id, _)| id)
+            .filter(|&(l, _)| l == level)
+            .collect();
+        for id in moved {
+            let name = sstable_name(id);
+            let new_id = (target, self.next_sstable_id(target));
+            let new_name = sstable_name(new_id);
+            info!("Moving sstable '{}' to '{}'", name, new_name);
+
+            // The move is logged with both names, so that if the rename
+            // is interrupted, replay can complete it
+            self.mark_dirty()?;
+            self.append_wal(6, &[name.as_bytes(), new_name.as_bytes()])?;
+            self.wal.flush()?;
+
+            self.storage.rename(&name, &new_name)?;
+
+            self.append_wal(3, &[new_name.as_bytes()])?;
+
+            self.remove_sstables(&[id]);
+            let reader = self.storage.read(&new_name)?;
+            let table = SSTableReader::open(reader)?;
+            self.insert_sstable(new_id, table);
+        }
+
+        Ok(())
+    }
+
+    /// Check `get` against a brute-force lookup of every source.
+    ///
+    /// Returns the keys for which `get` disagrees with a linear scan of the
+    /// memtable and of each sstable, from most to least recent.
+    #[cfg(test)]
+    pub(crate) fn audit_consistency(&mut self, keys: &[Vec<u8>]) -> Result<Vec<Vec<u8>>, IoError> {
+        let mut mismatches = Vec::new();
+        for key in keys {
+            let mut expected = self.mem_table.entries.iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.clone());
+            if expected.is_none() {
+                'tables: for (_, sstable) in &self.sstables {
+                    for entry in sstable.iter_range(&[], None) {
+                        let (k, v) = entry?;
+                        if &k == key {
+                            expected = Some(v);
+                            break 'tables;
+                        }
+                    }
+                }
+            }
+
+            if self.get(key)? != expected.flatten() {
+                mismatches.push(key.clone());
+            }
+        }
+        Ok(mismatches)
+    }
+
+    /// Flush the memtable and truncate the WAL, then mark the database as
+    /// cleanly closed.
+    ///
+    /// Until the next write, opening the database doesn't need to read the
+    /// WAL at all.
+    ///
+    /// If there are sstable ids reserved with
+    /// [`allocate_sstable_id`](Database::allocate_sstable_id) that were not
+    /// registered yet, the database is not marked clean, so that the
+    /// reservations are read back from the WAL.
+    pub fn checkpoint(&mut self) -> Result<(), IoError> {
+        self.flush_memtable(true)?;
+        self.wal.flush()?;
+        if !self.reserved_sstable_ids.is_empty() {
+            return Ok(());
+        }
+        self.storage.write(CLEAN_MARKER, &[])?;
+        self.clean = true;
+        Ok(())
+    }
+
+    /// Copy the database into `dst`, which must be empty.
+    ///
+    /// The memtable is flushed, then the sstables are copied and an empty
+    /// WAL is written, so the copy can be opened and written to without
+    /// affecting this database. The WAL is written last, so a copy that was
+    /// interrupted fails to open with [`Corruption::MissingWal`].
+    pub fn clone_into<T: Storage>(&mut self, dst: T) -> Result<(), IoError> {
+        if !dst.list()?.is_empty() {
+            return Err(IoError::new(IoErrorKind::InvalidInput, "Destination storage is not empty"));
+        }
+        self.flush()?;
+        let mut buf = Vec::new();
+        for (id, sstable) in &self.sstables {
+            let name = sstable_name(*id);
+            info!("Copying sstable '{}'", name);
+            let mut appender = dst.append(&name)?;
+            let mut offset = 0;
+            while offset < sstable.file_size {
+                let len = (sstable.file_size - offset).min(CLONE_CHUNK_SIZE);
+                buf.resize(len as usize, 0);
+                sstable.file.read_exact_at(&mut buf, offset)?;
+                appender.append(&buf)?;
+                offset += len;
+            }
+            appender.flush()?;
+        }
+        dst.write("wal", &[])?;
+        Ok(())
+    }
+
+    /// Checkpoint and close the database.
+    pub fn close(mut self) -> Result<(), IoError> {
+        self.checkpoint()
+    }
+
+    // Remove the clean shutdown marker, before anything is written to the WAL
+    fn mark_dirty(&mut self) -> Result<(), IoError> {
+        if self.clean {
+            self.storage.delete(CLEAN_MARKER)?;
+            self.clean = false;
+        }
+        Ok(())
+    }
+
+    fn next_sstable_id(&self, level: u32) -> u32 {
+        self.sstables.iter()
+            .map(|&(id, _)| id)
+            .chain(self.reserved_sstable_ids.iter().copied())
+            .filter(|&(l, _)| l == level)
+            .map(|(_, id)| id + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Reserve a fresh sstable id at a level, for a table written outside of
+    /// the database.
+    ///
+    /// The reservation is recorded in the WAL, so the id is not reused, even
+    /// after a crash, until the table is added with
+    /// [`register_sstable`](Database::register_sstable).
+    pub fn allocate_sstable_id(&mut self, level: u32) -> Result<u32, IoError> {
+        if level == 0 {
+            return Err(IoError::new(IoErrorKind::InvalidInput, "sstable levels start at 1"));
+        }
+        self.mark_dirty()?;
+        let id = (level, self.next_sstable_id(level));
+        self.append_wal(5, &[sstable_name(id).as_bytes()])?;
+        self.wal.flush()?;
+        self.reserved_sstable_ids.insert(id);
+        Ok(id.1)
+    }
+
+    /// Add an sstable written outside of the database, stored under `name`,
+    /// using an id reserved with
+    /// [`allocate_sstable_id`](Database::allocate_sstable_id).
+    ///
+    /// The file is renamed to the table's name. It should be written to a
+    /// name ending in `.sst.tmp`, so that it is cleaned up if the database is
+    /// opened before it is registered. If this is interrupted after the
+    /// rename, the registration is completed when the database is opened.
+    pub fn register_sstable(&mut self, level: u32, id: u32, name: &str) -> Result<(), IoError> {
+        let id = (level, id);
+        if !self.reserved_sstable_ids.contains(&id) {
+            return Err(IoError::new(IoErrorKind::InvalidInput, "sstable id was not reserved"));
+        }
+        self.mark_dirty()?;
+        let new_name = sstable_name(id);
+        info!("Registering external sstable '{}' as '{}'", name, new_name);
+        if name != new_name {
+            self.storage.rename(name, &new_name)?;
+        }
+        let reader = self.storage.read(&new_name)?;
+        let table = SSTableReader::open(reader)?;
+        self.insert_sstable(id, table);
+
+        self.append_wal(3, &[new_name.as_bytes()])?;
+        self.reserved_sstable_ids.remove(&id);
+        Ok(())
+    }
+
+    // Append a record to the WAL, returning its size
+    fn append_wal(&mut self, op: u8, fields: &[&[u8]]) -> Result<u64, IoError> {
+        let len = write_wal_record(&mut self.wal, op, fields)?;
+        self.stats.bytes_written += len;
+        Ok(len)
+    }
+
+    // Write the outstanding reservations back after truncating the WAL
+    fn log_reservations(&mut self) -> Result<(), IoError> {
+        let names: Vec<String> = self.reserved_sstable_ids.iter().map(|&id| sstable_name(id)).collect();
+        for name in names {
+            self.append_wal(5, &[name.as_bytes()])?;
+        }
+        Ok(())
+    }
+
+    fn insert_sstable(&mut self, id: SSTableId, table: SSTableReader<S::Reader>) {
+        self.stats.sstables += 1;
+        self.stats.sstable_bytes += table.file_size;
+        let index = self.sstables.partition_point(|&(k, _)| recency(k) < recency(id));
+        self.sstables.insert(index, (id, table));
+    }
+
+    fn remove_sstables(&mut self, ids: &[SSTableId]) {
+        let stats = &mut self.stats;
+        self.sstables.retain(|(id, table)| {
+            if ids.contains(id) {
+                stats.sstables -= 1;
+                stats.sstable_bytes -= table.file_size;
+                false
+            } else {
+                true
+            }
+        });
+    }
+}
+
+enum RangeSource<'a, R: ReadAt> {
+    MemTable(MemTableRangeIterator<'a>),
+    MemTableKeys(MemTableRangeIterator<'a>),
+    MemTableFiltered(MemTableRangeIterator<'a>, KeyFilter<'a>, bool),
+    SSTable(SSTableRangeIterator<'a, R>),
+}
+
+impl<'a, R: ReadAt> Iterator for RangeSource<'a, R> {
+    type Item = Result<Entry, IoError>;
+
+    fn next(&mut self) -> Option<Result<Entry, IoError>> {
+        match self {
+            RangeSource::MemTable(iter) => iter.next().map(|(k, v)| Ok((k.clone(), v.clone()))),
+            RangeSource::MemTableKeys(iter) => iter.next().map(|(k, v)| Ok((k.clone(), v.as_ref().map(|_| Vec::new())))),
+            RangeSource::MemTableFiltered(iter, filter, values) => {
+                let (k, v) = iter.find(|(k, _)| filter(k))?;
+                let v = if *values { v.clone() } else { v.as_ref().map(|_| Vec::new()) };
+                Some(Ok((k.clone(), v)))
+            }
+            RangeSource::SSTable(iter) => iter.next(),
+        }
+    }
+}
+
+/// Iterator over a range of entries, returned by [`Database::iter_range`].
+///
+/// The iterator borrows the database, so the sstables it reads from can't be
+/// flushed or compacted away while it is in use:
+///
+/// ```compile_fail
+/// # use lsmtree::{Database, DirectoryStorage};
+/// # let storage = DirectoryStorage::new("/tmp").unwrap();
+/// let mut db = Database::open(storage).unwrap();
+/// let mut iter = db.iter_range(b"a", b"z");
+/// db.compact(1).unwrap();
+/// iter.next();
+/// ```
+pub struct RangeIterator<'a, S: Storage> {
+    inner: TryRangeIterator<'a, S>,
+    error: Option<IoError>,
+    failed: bool,
+}
+
+impl<'a, S: Storage> RangeIterator<'a, S> {
+    fn new(inner: TryRangeIterator<'a, S>) -> RangeIterator<'a, S> {
+        RangeIterator {
+            inner,
+            error: None,
+            failed: false,
+        }
+    }
+
+    /// Take the I/O error that ended the iteration, if any.
+    pub fn take_error(&mut self) -> Option<IoError> {
+        self.error.take()
+    }
+}
+
+impl<'a, S: Storage> Iterator for RangeIterator<'a, S> {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
+        if self.failed {
+            return None;
+        }
+        match self.inner.next()? {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                self.error = Some(e);
+                self.failed = true;
+                None
+            }
+        }
+    }
+}
+
+/// Iterator over a range of entries returning I/O errors as items, returned
+/// by [`Database::try_iter_range`], [`Database::iter_range_filtered`] and
+/// [`Database::iter_prefix`].
+pub struct TryRangeIterator<'a, S: Storage> {
+    merged: MergedRuns<RangeSource<'a, S::Reader>>,
+    // If set, the runs are not bounded, and iteration stops at the first key
+    // without this prefix
+    prefix: Option<&'a [u8]>,
+}
+
+impl<'a, S: Storage> Iterator for TryRangeIterator<'a, S> {
+    type Item = Result<(Vec<u8>, Vec<u8>), IoError>;
+
+    fn next(&mut self) -> Option<Result<(Vec<u8>, Vec<u8>), IoError>> {
+        match self.merged.next()? {
+            Ok((key, _)) if self.prefix.is_some_and(|prefix| !key.starts_with(prefix)) => None,
+            Ok((key, Some(value))) => Some(Ok((key, value))),
+            // Tombstones are dropped by the merge
+            Ok((_, None)) => unreachable!(),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Iterator over entries and their source, returned by
+/// [`Database::iter_with_level`].
+pub struct LevelIterator<'a, S: Storage> {
+    database: &'a Database<S>,
+    merged: MergedRuns<RangeSource<'a, S::Reader>>,
+}
+
+impl<'a, S: Storage> Iterator for LevelIterator<'a, S> {
+    type Item = Result<(Vec<u8>, Vec<u8>, Option<SSTableId>), IoError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.merged.next_with_run()? {
+            Ok((run, (key, Some(value)))) => {
+                // The memtable is the first run, then the sstables in order
+                let source = run.checked_sub(1).map(|i| self.database.sstables[i].0);
+                Some(Ok((key, value, source)))
+            }
+            // Tombstones are dropped by the merge
+            Ok((_, (_, None))) => unreachable!(),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Iterator over key prefixes, returned by [`Database::iter_prefixes`].
+pub struct PrefixIterator<'a, S: Storage> {
+    database: &'a Database<S>,
+    separator: u8,
+    next_start: Option<Vec<u8>>,
+    // Keys without the separator that were returned as prefixes, and for which
+    // keys with that prefix and the separator might still follow
+    pending: Vec<Vec<u8>>,
+}
+
+impl<'a, S: Storage> PrefixIterator<'a, S> {
+    fn read_next(&mut self) -> Result<Option<Vec<u8>>, IoError> {
+        loop {
+            let Some(start) = self.next_start.take() else {
+                return Ok(None);
+            };
+            let Some(entry) = self.database.merge_range(&start, None, false, None).next() else {
+                return Ok(None);
+            };
+            let (mut key, _) = entry?;
+
+            while let Some(top) = self.pending.last_mut() {
+                top.push(self.separator);
+                let end = key_successor(top);
+                top.pop();
+                if end.is_some_and(|end| key >= end) {
+                    self.pending.pop();
+                } else {
+                    break;
+                }
+            }
+
+            match key.iter().position(|&b| b == self.separator) {
+                Some(pos) => {
+                    // Skip all the keys starting with this prefix and separator
+                    key.truncate(pos + 1);
+                    self.next_start = key_successor(&key);
+                    key.pop();
+                    if self.pending.last() == Some(&key) {
+                        // Already returned for the key equal to the prefix
+                        self.pending.pop();
+                        continue;
+                    }
+                    return Ok(Some(key));
+                }
+                None => {
+                    let mut next_start = key.clone();
+                    next_start.push(0);
+                    self.next_start = Some(next_start);
+                    self.pending.push(key.clone());
+                    return Ok(Some(key));
+                }
+            }
+        }
+    }
+}
+
+impl<'a, S: Storage> Iterator for PrefixIterator<'a, S> {
+    type Item = Result<Vec<u8>, IoError>;
+
+    fn next(&mut self) -> Option<Result<Vec<u8>, IoError>> {
+        match self.read_next() {
+            Ok(Some(prefix)) => Some(Ok(prefix)),
+            Ok(None) => None,
+            Err(e) => {
+                self.next_start = None;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Position where a [`Scan`] stopped, from which it can be resumed.
+///
+/// This is the first key that was not returned, and can be stored and used
+/// with [`Database::scan_from`] later.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cursor(Vec<u8>);
+
+impl Cursor {
+    pub fn from_bytes(key: Vec<u8>) -> Cursor {
+        Cursor(key)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+/// Entries read by [`Scan::next_page`], and the cursor to the next entry.
+pub type Page = (Vec<(Vec<u8>, Vec<u8>)>, Option<Cursor>);
+
+/// Paginated scan, returned by [`Database::scan_from`].
+pub struct Scan<'a, S: Storage> {
+    database: &'a Database<S>,
+    // None once the end was reached
+    next_start: Option<Vec<u8>>,
+}
+
+impl<'a, S: Storage> Scan<'a, S> {
+    /// Read up to `limit` entries.
+    ///
+    /// Also returns a cursor to the next entry, or `None` if the scan is
+    /// complete.
+    pub fn next_page(&mut self, limit: usize) -> Result<Page, IoError> {
+        let Some(start) = self.next_start.take() else {
+            return Ok((Vec::new(), None));
+        };
+        let mut entries = Vec::new();
+        for entry in self.database.merge_range(&start, None, true, None) {
+            let (key, value) = entry?;
+            if entries.len() == limit {
+                // Resume from this entry
+                self.next_start = Some(key.clone());
+                return Ok((entries, Some(Cursor(key))));
+            }
+            // Tombstones are dropped by the merge
+            entries.push((key, value.unwrap()));
+        }
+        Ok((entries, None))
+    }
+}
+
+// Returns the smallest key greater than all the keys starting with `prefix`,
+// or `None` if there is no such key.
+fn key_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut key = prefix.to_owned();
+    while let Some(last) = key.pop() {
+        if last < 0xFF {
+            key.push(last + 1);
+            return Some(key);
+        }
+    }
+    None
+}
+
+fn parse_wal_table_name(name: Vec<u8>) -> Result<String, Error> {
+    match String::from_utf8(name) {
+        Ok(name) if name.is_ascii() => Ok(name),
+        Ok(name) => Err(Error::Corruption(Corruption::InvalidTableName(name))),
+        Err(e) => Err(Error::Corruption(Corruption::InvalidTableName(String::from_utf8_lossy(e.as_bytes()).into_owned()))),
+    }
+}
+
+enum Operation {
+    Put,
+    Delete,
+    WriteSstableStart,
+    WriteSstableEnd,
+    Truncate,
+    ReserveSstable,
+    MoveSstable,
+    DropPrefix,
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+// CRC-32 (IEEE), covering every WAL record, and every record of a
+// SingleFileStorage
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc = (crc >> 8) ^ CRC32_TABLE[((crc ^ byte as u32) & 0xFF) as usize];
+    }
+    !crc
+}
+
+fn parse_sstable_name(name: &str) -> Result<(u32, u32), ()> {
+    let Some(dash) = name.find('-') else {
+        return Err(());
+    };
+    let level = name[0..dash].parse().map_err(|_| ())?;
+    let dot = match name[dash+1..].find('.') {
+        Some(i) => dash + 1 + i,
+        None => return Err(()),
+    };
+    let id = name[dash+1..dot].parse().map_err(|_| ())?;
+    if &name[dot..] != ".sst" {
+        return Err(());
+    }
+    Ok((level, id))
+}
+
+#[test]
+fn test_parse_sstable_name() {
+    assert_eq!(parse_sstable_name("1-0.sst"), Ok((1, 0)));
+    assert_eq!(parse_sstable_name("123-456.sst"), Ok((123, 456)));
+    assert_eq!(parse_sstable_name(""), Err(()));
+    assert_eq!(parse_sstable_name("-0.sst"), Err(()));
+    assert_eq!(parse_sstable_name("1-.sst"), Err(()));
+    assert_eq!(parse_sstable_name("1-0."), Err(()));
+    assert_eq!(parse_sstable_name("1-0"), Err(()));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+    use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+    use std::rc::Rc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tempdir::TempDir;
+
+    use crate::{crc32, Append, Corruption, CorruptionPolicy, Cursor, Database, DatabaseOptions, DatabaseStats, DirectoryStorage, Error, FlushStats, GetCost, MemoryStorage, MergePreference, OpenReport, ReadAt, Storage};
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_database_send_sync() {
+        assert_send_sync::<Database<DirectoryStorage>>();
+
+        // Also holds for any storage
+        fn generic<S: Storage>() {
+            assert_send_sync::<Database<S>>();
+        }
+        generic::<DirectoryStorage>();
+    }
+
+    fn v(s: &[u8]) -> Vec<u8> {
+        s.into()
+    }
+
+    // Frame the content of a WAL record with its length and checksum
+    pub(crate) fn wal_record(content: &[u8]) -> Vec<u8> {
+        let mut record = Vec::new();
+        record.extend_from_slice(&(content.len() as u32).to_be_bytes());
+        record.extend_from_slice(&crc32(content).to_be_bytes());
+        record.extend_from_slice(content);
+        record
+    }
+
+    // Wraps a storage, counting the reads made on its readers and the bytes
+    // they returned, and optionally slowing them down. Reads of the WAL are
+    // counted separately
+    pub(crate) struct CountingStorage<S: Storage> {
+        pub(crate) inner: S,
+        pub(crate) reads: Arc<AtomicUsize>,
+        pub(crate) wal_reads: Arc<AtomicUsize>,
+        pub(crate) bytes_read: Arc<AtomicUsize>,
+        pub(crate) sizes: Arc<AtomicUsize>,
+        pub(crate) read_delay: Duration,
+    }
+
+    impl<S: Storage> CountingStorage<S> {
+        pub(crate) fn new(inner: S) -> CountingStorage<S> {
+            CountingStorage {
+                inner,
+                reads: Default::default(),
+                wal_reads: Default::default(),
+                bytes_read: Default::default(),
+                sizes: Default::default(),
+                read_delay: Duration::ZERO,
+            }
+        }
+
+        pub(crate) fn take_reads(&self) -> usize {
+            self.reads.swap(0, Ordering::Relaxed)
+        }
+
+        pub(crate) fn take_bytes_read(&self) -> usize {
+            self.bytes_read.swap(0, Ordering::Relaxed)
+        }
+
+        pub(crate) fn take_sizes(&self) -> usize {
+            self.sizes.swap(0, Ordering::Relaxed)
+        }
+    }
+
+    pub(crate) struct CountingReader<R: ReadAt> {
+        inner: R,
+        reads: Arc<AtomicUsize>,
+        bytes_read: Arc<AtomicUsize>,
+        sizes: Arc<AtomicUsize>,
+        read_delay: Duration,
+    }
+
+    impl<R: ReadAt> ReadAt for CountingReader<R> {
+        fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<(), IoError> {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            self.bytes_read.fetch_add(buf.len(), Ordering::Relaxed);
+            std::thread::sleep(self.read_delay);
+            self.inner.read_exact_at(buf, offset)
+        }
+
+        fn size(&self) -> Result<u64, IoError> {
+            self.sizes.fetch_add(1, Ordering::Relaxed);
+            self.inner.size()
+        }
+    }
+
+    impl<S: Storage> Storage for CountingStorage<S> {
+        type Reader = CountingReader<S::Reader>;
+        type Appender = S::Appender;
+
+        fn read(&self, key: &str) -> Result<Self::Reader, IoError> {
+            Ok(CountingReader {
+                inner: self.inner.read(key)?,
+                reads: if key == "wal" { self.wal_reads.clone() } else { self.reads.clone() },
+                bytes_read: self.bytes_read.clone(),
+                sizes: self.sizes.clone(),
+                read_delay: self.read_delay,
+            })
+        }
+
+        fn write(&self, key: &str, value: &[u8]) -> Result<(), IoError> {
+            self.inner.write(key, value)
+        }
+
+        fn append(&self, key: &str) -> Result<Self::Appender, IoError> {
+            self.inner.append(key)
+        }
+
+        fn delete(&self, key: &str) -> Result<(), IoError> {
+            self.inner.delete(key)
+        }
+
+        fn list(&self) -> Result<Vec<String>, IoError> {
+            self.inner.list()
+        }
+
+        fn rename(&self, from: &str, to: &str) -> Result<(), IoError> {
+            self.inner.rename(from, to)
+        }
+    }
+
+    // Storage failing to delete the given file, as if the database crashed
+    // right before deleting it
+    pub(crate) struct FailDelete(pub(crate) DirectoryStorage, pub(crate) &'static str);
+
+    impl Storage for FailDelete {
+        type Reader = <DirectoryStorage as Storage>::Reader;
+        type Appender = <DirectoryStorage as Storage>::Appender;
+
+        fn read(&self, key: &str) -> Result<Self::Reader, IoError> {
+            self.0.read(key)
+        }
+
+        fn write(&self, key: &str, value: &[u8]) -> Result<(), IoError> {
+            self.0.write(key, value)
+        }
+
+        fn append(&self, key: &str) -> Result<Self::Appender, IoError> {
+            self.0.append(key)
+        }
+
+        fn delete(&self, key: &str) -> Result<(), IoError> {
+            if key == self.1 {
+                return Err(IoError::other("crash"));
+            }
+            self.0.delete(key)
+        }
+
+        fn list(&self) -> Result<Vec<String>, IoError> {
+            self.0.list()
+        }
+
+        fn rename(&self, from: &str, to: &str) -> Result<(), IoError> {
+            self.0.rename(from, to)
+        }
+    }
+
+    pub(crate) struct BufReader(pub(crate) Vec<u8>);
+
+    impl ReadAt for BufReader {
+        fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<(), IoError> {
+            let start = offset as usize;
+            match self.0.get(start..start + buf.len()) {
+                Some(data) => {
+                    buf.copy_from_slice(data);
+                    Ok(())
+                }
+                None => Err(IoError::new(IoErrorKind::UnexpectedEof, "Read past end")),
+            }
+        }
+
+        fn size(&self) -> Result<u64, IoError> {
+            Ok(self.0.len() as u64)
+        }
+    }
+
+    #[test]
+    fn test_database() {
+        pretty_env_logger::formatted_timed_builder()
+            .parse_filters("info")
+            .try_init().unwrap();
+
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+
+        fn check(db: &mut Database<DirectoryStorage>) {
+            db.put(b"ghi", b"111").unwrap();
+            db.put(b"abc", b"222").unwrap();
+            db.put(b"mno", b"333").unwrap();
+            db.put(b"ghi", b"444").unwrap();
+            db.put(b"def", b"555").unwrap();
+            db.put(b"jkl", b"666").unwrap();
+            db.put(b"def", b"777").unwrap();
+            db.delete(b"ghi").unwrap();
+        }
+        check(&mut db);
+
+        db.maintain().unwrap();
+        check(&mut db);
+
+        assert_eq!(db.get(b"abc").unwrap(), Some(v(b"222")));
+        assert_eq!(db.get(b"def").unwrap(), Some(v(b"777")));
+        assert_eq!(db.get(b"ghi").unwrap(), None);
+        assert_eq!(db.get(b"jkl").unwrap(), Some(v(b"666")));
+        assert_eq!(db.get(b"mno").unwrap(), Some(v(b"333")));
+        assert_eq!(db.get(b"zzz").unwrap(), None);
+
+        assert_eq!(
+            db.iter_range(b"def", b"jkl").collect::<Vec<_>>(),
+            vec![
+                (v(b"def"), v(b"777")),
+            ],
+        );
+
+        assert_eq!(
+            db.iter_range(b"a", b"jz").collect::<Vec<_>>(),
+            vec![
+                (v(b"abc"), v(b"222")),
+                (v(b"def"), v(b"777")),
+                (v(b"jkl"), v(b"666")),
+            ],
+        );
+
+        assert_eq!(
+            db.iter_range(b"def", b"z").collect::<Vec<_>>(),
+            vec![
+                (v(b"def"), v(b"777")),
+                (v(b"jkl"), v(b"666")),
+                (v(b"mno"), v(b"333")),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_iter_range_overlapping() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        db.put(b"a", b"old").unwrap();
+        db.put(b"b", b"old").unwrap();
+        db.put(b"c", b"old").unwrap();
+        db.put(b"e", b"old").unwrap();
+        db.maintain().unwrap();
+        db.put(b"b", b"newer").unwrap();
+        db.put(b"c", b"newer").unwrap();
+        db.put(b"d", b"newer").unwrap();
+        db.maintain().unwrap();
+        db.put(b"c", b"memtable").unwrap();
+        db.put(b"f", b"memtable").unwrap();
+        db.put(b"g", b"memtable").unwrap();
+        db.delete(b"g").unwrap();
+
+        assert_eq!(
+            db.iter_range(b"a", b"z").collect::<Vec<_>>(),
+            vec![
+                (v(b"a"), v(b"old")),
+                (v(b"b"), v(b"newer")),
+                (v(b"c"), v(b"memtable")),
+                (v(b"d"), v(b"newer")),
+                (v(b"e"), v(b"old")),
+                (v(b"f"), v(b"memtable")),
+            ],
+        );
+
+        // The start is included, the end is not
+        assert_eq!(
+            db.iter_range(b"b", b"e").collect::<Vec<_>>(),
+            vec![
+                (v(b"b"), v(b"newer")),
+                (v(b"c"), v(b"memtable")),
+                (v(b"d"), v(b"newer")),
+            ],
+        );
+        assert_eq!(db.iter_range(b"c", b"c").count(), 0);
+        assert_eq!(db.iter_range(b"h", b"z").count(), 0);
+    }
+
+    #[test]
+    fn test_compact() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+
+        db.put(b"abc", b"111").unwrap();
+        db.put(b"def", b"222").unwrap();
+        db.maintain().unwrap();
+        db.put(b"def", b"333").unwrap();
+        db.put(b"ghi", b"444").unwrap();
+        db.maintain().unwrap();
+        db.put(b"abc", b"555").unwrap();
+
+        db.compact(1).unwrap();
+        let mut files = std::fs::read_dir(dir.path()).unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files, vec!["2-0.sst", "wal"]);
+
+        assert_eq!(db.get(b"abc").unwrap(), Some(v(b"555")));
+        assert_eq!(db.get(b"def").unwrap(), Some(v(b"333")));
+        assert_eq!(
+            db.iter_range(b"a", b"z").collect::<Vec<_>>(),
+            vec![
+                (v(b"abc"), v(b"555")),
+                (v(b"def"), v(b"333")),
+                (v(b"ghi"), v(b"444")),
+            ],
+        );
+
+        // Reopen
+        drop(db);
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        assert_eq!(
+            db.iter_range(b"a", b"z").collect::<Vec<_>>(),
+            vec![
+                (v(b"abc"), v(b"555")),
+                (v(b"def"), v(b"333")),
+                (v(b"ghi"), v(b"444")),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_compact_crash() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = FailDelete(DirectoryStorage::new(dir.path()).unwrap(), "1-0.sst");
+        let mut db = Database::open(storage).unwrap();
+        db.put(b"abc", b"111").unwrap();
+        db.put(b"def", b"222").unwrap();
+        db.maintain().unwrap();
+        db.delete(b"abc").unwrap();
+        db.maintain().unwrap();
+
+        // The merged table drops the tombstone, the crash happens while
+        // deleting the inputs
+        assert!(db.compact(1).is_err());
+        drop(db);
+
+        // The deleted key doesn't come back
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        assert_eq!(db.get(b"abc").unwrap(), None);
+        assert_eq!(db.get(b"def").unwrap(), Some(v(b"222")));
+        assert_eq!(db.iter_range(b"a", b"z").collect::<Vec<_>>(), vec![(v(b"def"), v(b"222"))]);
+    }
+
+    #[test]
+    fn test_compact_trivial_move() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = CountingStorage::new(DirectoryStorage::new(dir.path()).unwrap());
+        let mut db = Database::open(storage).unwrap();
+        let list = || {
+            let mut files = std::fs::read_dir(dir.path()).unwrap()
+                .map(|e| e.unwrap().file_name().into_string().unwrap())
+                .collect::<Vec<_>>();
+            files.sort();
+            files
+        };
+
+        for prefix in ["a", "b", "c"] {
+            for i in 0..100u32 {
+                db.put(format!("{}{:03}", prefix, i).as_bytes(), b"value").unwrap();
+            }
+            db.maintain().unwrap();
+        }
+        db.compact(1).unwrap();
+        assert_eq!(list(), vec!["2-0.sst", "2-1.sst", "2-2.sst", "wal"]);
+
+        // Tables are moved without reading their contents, only their first
+        // and last keys
+        for i in 0..100u32 {
+            db.put(format!("d{:03}", i).as_bytes(), b"value").unwrap();
+        }
+        db.maintain().unwrap();
+        db.storage.take_reads();
+        db.compact(1).unwrap();
+        assert!(db.storage.take_reads() < 50);
+        assert_eq!(list(), vec!["2-0.sst", "2-1.sst", "2-2.sst", "2-3.sst", "wal"]);
+
+        // Overlapping tables are merged
+        db.put(b"b050", b"new").unwrap();
+        db.maintain().unwrap();
+        db.compact(1).unwrap();
+        assert_eq!(list(), vec!["2-4.sst", "wal"]);
+
+        assert_eq!(db.count_range(b"", b"z").unwrap(), 400);
+        assert_eq!(db.get(b"b050").unwrap(), Some(v(b"new")));
+        drop(db);
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        assert_eq!(db.count_range(b"", b"z").unwrap(), 400);
+        assert_eq!(db.get(b"a000").unwrap(), Some(v(b"value")));
+        assert_eq!(db.get(b"b050").unwrap(), Some(v(b"new")));
+    }
+
+    #[test]
+    fn test_max_levels() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let options = DatabaseOptions::builder().max_levels(3).build();
+        let mut db = Database::open_with_options(storage, options).unwrap();
+
+        for round in 1..=32u32 {
+            for i in 0..10u32 {
+                let key = format!("key{:03}", (round * 7 + i * 13) % 200);
+                db.put(key.as_bytes(), format!("{}", round).as_bytes()).unwrap();
+            }
+            db.maintain().unwrap();
+            // Compact like a tiered policy would, which without a limit
+            // reaches level 6
+            for level in 1..=round.trailing_zeros() + 1 {
+                db.compact(level).unwrap();
+            }
+            for (level, _) in db.level_ranges().unwrap() {
+                assert!(level <= 3);
+            }
+        }
+
+        let mut expected = BTreeMap::new();
+        for round in 1..=32u32 {
+            for i in 0..10u32 {
+                let key = format!("key{:03}", (round * 7 + i * 13) % 200);
+                expected.insert(v(key.as_bytes()), v(format!("{}", round).as_bytes()));
+            }
+        }
+        assert_eq!(
+            db.iter_range(b"", b"z").collect::<Vec<_>>(),
+            expected.into_iter().collect::<Vec<_>>(),
+        );
+    }
+
+    #[test]
+    fn test_clean_shutdown() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = CountingStorage::new(DirectoryStorage::new(dir.path()).unwrap());
+        let mut db = Database::open(storage).unwrap();
+        db.put(b"abc", b"111").unwrap();
+        db.put(b"def", b"222").unwrap();
+        db.checkpoint().unwrap();
+        assert!(dir.path().join("clean").exists());
+
+        // Clean reopen doesn't read the WAL
+        let mut db = Database::open(db.storage).unwrap();
+        assert_eq!(db.storage.wal_reads.swap(0, Ordering::Relaxed), 0);
+        assert_eq!(db.get(b"abc").unwrap(), Some(v(b"111")));
+
+        // Writing removes the marker
+        db.put(b"abc", b"333").unwrap();
+        assert!(!dir.path().join("clean").exists());
+
+        // Unclean reopen replays
+        let mut db = Database::open(db.storage).unwrap();
+        assert!(db.storage.wal_reads.swap(0, Ordering::Relaxed) > 0);
+        assert_eq!(db.get(b"abc").unwrap(), Some(v(b"333")));
+        assert_eq!(db.get(b"def").unwrap(), Some(v(b"222")));
+
+        db.close().unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        assert_eq!(db.get(b"abc").unwrap(), Some(v(b"333")));
+    }
+
+    #[test]
+    fn test_open_report_trailing_record() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let mut db = Database::open(DirectoryStorage::new(dir.path()).unwrap()).unwrap();
+        assert_eq!(*db.open_report(), OpenReport::default());
+        db.put(b"abc", b"111").unwrap();
+        db.put(b"def", b"222").unwrap();
+        drop(db);
+        let complete_size = std::fs::metadata(dir.path().join("wal")).unwrap().len();
+
+        // Add a put whose value was not fully written
+        {
+            let mut wal = std::fs::OpenOptions::new().append(true).open(dir.path().join("wal")).unwrap();
+            let record = wal_record(b"\x00\x00\x00\x00\x03ghi\x00\x00\x00\x03333");
+            std::io::Write::write_all(&mut wal, &record[..record.len() - 2]).unwrap();
+        }
+
+        let mut db = Database::open(DirectoryStorage::new(dir.path()).unwrap()).unwrap();
+        assert_eq!(
+            *db.open_report(),
+            OpenReport {
+                records_replayed: 2,
+                incomplete_sstables_deleted: 0,
+                wal_bytes_scanned: complete_size,
+                trailing_discarded: true,
+                duplicates_dropped: 0,
+            },
+        );
+        assert_eq!(db.get(b"abc").unwrap(), Some(v(b"111")));
+        assert_eq!(db.get(b"ghi").unwrap(), None);
+
+        // The partial record is gone, so new records replay correctly
+        db.put(b"ghi", b"333").unwrap();
+        drop(db);
+        let mut db = Database::open(DirectoryStorage::new(dir.path()).unwrap()).unwrap();
+        assert!(!db.open_report().trailing_discarded);
+        assert_eq!(db.open_report().records_replayed, 3);
+        assert_eq!(db.get(b"def").unwrap(), Some(v(b"222")));
+        assert_eq!(db.get(b"ghi").unwrap(), Some(v(b"333")));
+    }
+
+    #[test]
+    fn test_wal_garbage_tail() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let mut db = Database::open(DirectoryStorage::new(dir.path()).unwrap()).unwrap();
+        db.put(b"abc", b"111").unwrap();
+        db.put(b"def", b"222").unwrap();
+        db.delete(b"abc").unwrap();
+        drop(db);
+        let wal = std::fs::read(dir.path().join("wal")).unwrap();
+
+        // Garbage that reads as a record header, and a full-length record
+        // whose content doesn't match its checksum
+        let mut corrupted = wal_record(b"\x00\x00\x00\x00\x03ghi\x00\x00\x00\x03333");
+        corrupted[10] ^= 1;
+        for garbage in [&b"\x00\x00\x00\x05\xde\xad\xbe\xefgarbage"[..], &corrupted] {
+            std::fs::write(dir.path().join("wal"), [&wal[..], garbage].concat()).unwrap();
+            let mut db = Database::open(DirectoryStorage::new(dir.path()).unwrap()).unwrap();
+            assert_eq!(db.open_report().records_replayed, 3);
+            assert_eq!(db.open_report().wal_bytes_scanned, wal.len() as u64);
+            assert!(db.open_report().trailing_discarded);
+            assert_eq!(db.get(b"abc").unwrap(), None);
+            assert_eq!(db.get(b"def").unwrap(), Some(v(b"222")));
+            assert_eq!(db.get(b"ghi").unwrap(), None);
+            drop(db);
+            assert_eq!(std::fs::read(dir.path().join("wal")).unwrap(), wal);
+            assert!(!dir.path().join("wal.tmp").exists());
+        }
+
+        // A rewrite interrupted before the rename leaves the WAL intact, and
+        // its temporary file is removed
+        std::fs::write(dir.path().join("wal.tmp"), &wal[..10]).unwrap();
+        let mut db = Database::open(DirectoryStorage::new(dir.path()).unwrap()).unwrap();
+        assert_eq!(db.open_report().records_replayed, 3);
+        assert_eq!(db.get(b"def").unwrap(), Some(v(b"222")));
+        assert!(!dir.path().join("wal.tmp").exists());
+    }
+
+    #[test]
+    fn test_stats() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = CountingStorage::new(DirectoryStorage::new(dir.path()).unwrap());
+        let mut db = Database::open(storage).unwrap();
+        assert_eq!(db.stats(), Default::default());
+
+        db.put(b"abc", b"111").unwrap();
+        db.put(b"def", b"222").unwrap();
+        assert_eq!(db.get(b"abc").unwrap(), Some(v(b"111")));
+        assert_eq!(db.get(b"ghi").unwrap(), None);
+        db.maintain().unwrap();
+        db.delete(b"abc").unwrap();
+        db.put(b"ghi", b"333").unwrap();
+        db.maintain().unwrap();
+        assert_eq!(db.get_batch_sorted(&[b"abc", b"def", b"ghi"]).unwrap(), vec![None, Some(v(b"222")), Some(v(b"333"))]);
+        db.compact(1).unwrap();
+
+        let stats = db.stats();
+        assert_eq!(stats.reads, 5);
+        assert_eq!(stats.hits, 3);
+        assert_eq!(stats.flushes, 2);
+        assert_eq!(stats.compactions, 1);
+        assert_eq!(stats.sstables, 1);
+        let size = std::fs::metadata(dir.path().join("2-0.sst")).unwrap().len();
+        assert_eq!(stats.sstable_bytes, size);
+        assert!(stats.bytes_written > size);
+
+        // Reading the counters doesn't touch storage
+        db.storage.take_reads();
+        db.storage.take_sizes();
+        for _ in 0..100 {
+            assert_eq!(db.stats(), stats);
+        }
+        assert_eq!(db.storage.take_reads(), 0);
+        assert_eq!(db.storage.take_sizes(), 0);
+
+        // The sstables are counted when reopening
+        drop(db);
+        let db = Database::open(DirectoryStorage::new(dir.path()).unwrap()).unwrap();
+        assert_eq!(db.stats(), DatabaseStats { sstables: 1, sstable_bytes: size, ..Default::default() });
+    }
+
+    #[test]
+    fn test_clone_into() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let mut db = Database::open(DirectoryStorage::new(dir.path()).unwrap()).unwrap();
+        db.put(b"abc", b"111").unwrap();
+        db.put(b"def", b"222").unwrap();
+        db.maintain().unwrap();
+        db.put(b"abc", b"333").unwrap();
+        db.delete(b"def").unwrap();
+        db.put(b"ghi", b"444").unwrap();
+
+        let clone_dir = TempDir::new("lsmtree-test").unwrap();
+        db.clone_into(DirectoryStorage::new(clone_dir.path()).unwrap()).unwrap();
+        assert_eq!(std::fs::metadata(clone_dir.path().join("wal")).unwrap().len(), 0);
+
+        // Write to the clone
+        let mut clone = Database::open(DirectoryStorage::new(clone_dir.path()).unwrap()).unwrap();
+        assert_eq!(clone.get(b"abc").unwrap(), Some(v(b"333")));
+        assert_eq!(clone.get(b"def").unwrap(), None);
+        clone.put(b"abc", b"555").unwrap();
+        clone.put(b"jkl", b"666").unwrap();
+        clone.maintain().unwrap();
+        clone.compact(1).unwrap();
+        assert_eq!(clone.get(b"abc").unwrap(), Some(v(b"555")));
+
+        // The original is unchanged
+        drop(db);
+        let mut db = Database::open(DirectoryStorage::new(dir.path()).unwrap()).unwrap();
+        assert_eq!(
+            db.iter_range(b"a", b"z").collect::<Vec<_>>(),
+            vec![(v(b"abc"), v(b"333")), (v(b"ghi"), v(b"444"))],
+        );
+
+        // The destination must be empty
+        match db.clone_into(DirectoryStorage::new(clone_dir.path()).unwrap()) {
+            Err(e) => assert_eq!(e.kind(), IoErrorKind::InvalidInput),
+            Ok(()) => panic!("cloned into non-empty storage"),
+        }
+    }
+
+    #[test]
+    fn test_merge_databases() {
+        fn merged(preference: MergePreference) -> Vec<(Vec<u8>, Vec<u8>)> {
+            let dst_dir = TempDir::new("lsmtree-test").unwrap();
+            let mut dst = Database::open(DirectoryStorage::new(dst_dir.path()).unwrap()).unwrap();
+            dst.put(b"a", b"dst").unwrap();
+            dst.put(b"b", b"dst").unwrap();
+            dst.maintain().unwrap();
+            dst.put(b"c", b"dst").unwrap();
+            dst.put(b"f", b"dst").unwrap();
+
+            let src_dir = TempDir::new("lsmtree-test").unwrap();
+            let mut src = Database::open(DirectoryStorage::new(src_dir.path()).unwrap()).unwrap();
+            src.put(b"b", b"src").unwrap();
+            src.put(b"d", b"src").unwrap();
+            src.maintain().unwrap();
+            src.put(b"c", b"src").unwrap();
+            src.put(b"e", b"src").unwrap();
+            src.delete(b"e").unwrap();
+            // Deletions of keys only in dst
+            src.delete(b"a").unwrap();
+            src.maintain().unwrap();
+            src.delete(b"f").unwrap();
+
+            Database::merge_databases(&mut dst, src, preference).unwrap();
+            dst.iter_range(b"", b"z").collect::<Vec<_>>()
+        }
+
+        assert_eq!(
+            merged(MergePreference::Source),
+            vec![
+                (v(b"b"), v(b"src")),
+                (v(b"c"), v(b"src")),
+                (v(b"d"), v(b"src")),
+            ],
+        );
+        assert_eq!(
+            merged(MergePreference::Destination),
+            vec![
+                (v(b"a"), v(b"dst")),
+                (v(b"b"), v(b"dst")),
+                (v(b"c"), v(b"dst")),
+                (v(b"d"), v(b"src")),
+                (v(b"f"), v(b"dst")),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_recover_untruncated_wal() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+
+        db.put(b"abc", b"111").unwrap();
+        db.put(b"def", b"222").unwrap();
+        db.flush_without_wal_truncation().unwrap();
+        db.put(b"abc", b"333").unwrap();
+        db.put(b"ghi", b"444").unwrap();
+
+        fn check(db: &mut Database<DirectoryStorage>) {
+            assert_eq!(db.get(b"abc").unwrap(), Some(v(b"333")));
+            assert_eq!(db.get(b"def").unwrap(), Some(v(b"222")));
+            assert_eq!(
+                db.iter_range(b"a", b"z").collect::<Vec<_>>(),
+                vec![
+                    (v(b"abc"), v(b"333")),
+                    (v(b"def"), v(b"222")),
+                    (v(b"ghi"), v(b"444")),
+                ],
+            );
+        }
+        check(&mut db);
+
+        // Reopen, the whole WAL is replayed on top of the sstable
+        drop(db);
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        assert_eq!(db.sstables.len(), 1);
+        check(&mut db);
+
+        // Flush and reopen again
+        db.maintain().unwrap();
+        drop(db);
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        assert_eq!(db.sstables.len(), 2);
+        check(&mut db);
+    }
+
+    #[test]
+    fn test_strict_replay() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let open = |strict| {
+            let storage = DirectoryStorage::new(dir.path()).unwrap();
+            let options = DatabaseOptions::builder().strict_replay(strict).build();
+            Database::open_with_options(storage, options).unwrap()
+        };
+        let mut db = open(false);
+
+        db.put(b"abc", b"111").unwrap();
+        db.put(b"def", b"222").unwrap();
+        db.delete(b"jkl").unwrap();
+        db.flush_without_wal_truncation().unwrap();
+        db.put(b"abc", b"333").unwrap();
+        db.put(b"ghi", b"444").unwrap();
+        drop(db);
+
+        fn check(db: &mut Database<DirectoryStorage>) {
+            assert_eq!(
+                db.iter_range(b"a", b"z").collect::<Vec<_>>(),
+                vec![
+                    (v(b"abc"), v(b"333")),
+                    (v(b"def"), v(b"222")),
+                    (v(b"ghi"), v(b"444")),
+                ],
+            );
+        }
+
+        // The entries already in the sstable are replayed again
+        let mut db = open(false);
+        assert_eq!(db.open_report().duplicates_dropped, 0);
+        assert_eq!(db.mem_table.entries.len(), 4);
+        check(&mut db);
+        drop(db);
+
+        // Identical entries are dropped, the newer value of abc is kept
+        let mut db = open(true);
+        assert_eq!(db.open_report().duplicates_dropped, 2);
+        assert_eq!(
+            db.mem_table.entries,
+            vec![(v(b"abc"), Some(v(b"333"))), (v(b"ghi"), Some(v(b"444")))],
+        );
+        check(&mut db);
+        db.maintain().unwrap();
+        assert_eq!(db.last_flush_stats().unwrap().entries, 2);
+        drop(db);
+        let mut db = open(true);
+        assert_eq!(db.open_report().duplicates_dropped, 0);
+        check(&mut db);
+
+        // A compaction completed after a write, holding an older value: the
+        // write is more recent and is kept
+        db.put(b"abc", b"555").unwrap();
+        db.compact(1).unwrap();
+        drop(db);
+        let mut db = open(true);
+        assert_eq!(db.open_report().duplicates_dropped, 0);
+        assert_eq!(db.get(b"abc").unwrap(), Some(v(b"555")));
+    }
+
+    #[test]
+    fn test_count_range() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+
+        for i in 0..20u32 {
+            db.put(format!("key{:02}", i * 2).as_bytes(), b"old").unwrap();
+        }
+        db.maintain().unwrap();
+        for i in 0..20u32 {
+            db.put(format!("key{:02}", i * 3).as_bytes(), b"new").unwrap();
+        }
+        db.maintain().unwrap();
+        db.put(b"key05", b"mem").unwrap();
+        db.put(b"key99", b"mem").unwrap();
+
+        let ranges: &[(&[u8], &[u8])] = &[
+            (b"", b"z"),
+            (b"key00", b"key10"),
+            (b"key07", b"key08"),
+            (b"key30", b"key60"),
+            (b"key60", b"key90"),
+            (b"key39", b"key58"),
+            (b"key90", b"z"),
+            (b"key99", b"key90"),
+            (b"a", b"b"),
+        ];
+        for &(start, end) in ranges {
+            let expected = db.iter_range(start, end).count() as u64;
+            assert_eq!(db.count_range(start, end).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_iter_range_filtered() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = CountingStorage::new(DirectoryStorage::new(dir.path()).unwrap());
+        let mut db = Database::open(storage).unwrap();
+        for i in 0..100u32 {
+            db.put(format!("key{:02}", i).as_bytes(), &[b'x'; 1000]).unwrap();
+        }
+        db.maintain().unwrap();
+        db.put(b"key30", b"mem").unwrap();
+        db.put(b"key31", b"mem").unwrap();
+
+        let filter = |key: &[u8]| key.ends_with(b"0");
+        db.storage.take_bytes_read();
+        let entries = db.iter_range_filtered(b"key20", b"key60", filter)
+            .collect::<Result<Vec<_>, _>>().unwrap();
+        let bytes_read = db.storage.take_bytes_read();
+        assert_eq!(
+            entries,
+            vec![
+                (v(b"key20"), vec![b'x'; 1000]),
+                (v(b"key30"), v(b"mem")),
+                (v(b"key40"), vec![b'x'; 1000]),
+                (v(b"key50"), vec![b'x'; 1000]),
+            ],
+        );
+        // Only the values of key20, key30 (shadowed), key40 and key50 are read
+        assert!(bytes_read < 5000, "read {} bytes", bytes_read);
+
+        let expected = db.iter_range(b"key20", b"key60")
+            .filter(|(k, _)| filter(k))
+            .collect::<Vec<_>>();
+        assert_eq!(entries, expected);
+        assert!(db.storage.take_bytes_read() > 40_000);
+    }
+
+    #[test]
+    fn test_single_entry_sstable() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        db.put(b"key", b"value").unwrap();
+        db.maintain().unwrap();
+
+        drop(db);
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        assert_eq!(db.get(b"key").unwrap(), Some(v(b"value")));
+        assert_eq!(db.get(b"a").unwrap(), None);
+        assert_eq!(db.get(b"kez").unwrap(), None);
+        assert_eq!(db.get(b"z").unwrap(), None);
+    }
+
+    #[test]
+    fn test_sstable_offset_width() {
+        use crate::{write_sstable, write_sstable_with_format, EntryIndex, SSTableReader};
+
+        let entries = (0..50u32)
+            .map(|i| (format!("key{:02}", i).into_bytes(), Some(format!("value{}", i).into_bytes())))
+            .collect::<Vec<_>>();
+
+        let narrow = write_sstable(&entries, 1, 0.01, None);
+        let wide = write_sstable_with_format(&entries, EntryIndex::Offsets64, 1, 0.01, None);
+        assert_eq!(wide.len() - narrow.len(), 50 * 4);
+
+        for buf in [narrow, wide] {
+            let file = BufReader(buf);
+            let table = SSTableReader::open(&file).unwrap();
+            for (key, value) in &entries {
+                assert_eq!(table.get(key).unwrap().as_ref(), Some(value));
+            }
+            assert_eq!(table.get(b"key50").unwrap(), None);
+            assert_eq!(
+                table.iter_range(b"key10", Some(b"key13")).map(|e| e.unwrap().0).collect::<Vec<_>>(),
+                vec![v(b"key10"), v(b"key11"), v(b"key12")],
+            );
+        }
+    }
+
+    #[test]
+    fn test_sstable_fixed_size() {
+        use crate::{sstable_len, write_sstable, write_sstable_with_format, EntryIndex, SSTableReader, SSTABLE_FLAG_FIXED};
+
+        let entries = (0..100u32)
+            .map(|i| (format!("key{:03}", i * 2).into_bytes(), Some(format!("{:08}", i).into_bytes())))
+            .collect::<Vec<_>>();
+
+        let fixed = write_sstable(&entries, 1, 0.01, None);
+        let general = write_sstable_with_format(&entries, EntryIndex::Offsets32, 1, 0.01, None);
+        assert_eq!(fixed[4] & SSTABLE_FLAG_FIXED, SSTABLE_FLAG_FIXED);
+        assert_eq!(general.len() - fixed.len(), 100 * 4 - 8);
+        assert_eq!(fixed.len() as u64, sstable_len(&entries, 1, 0.01, None));
+
+        let file = BufReader(fixed);
+        let mut table = SSTableReader::open(&file).unwrap();
+        assert_eq!(table.fixed, Some((6, 4 + 6 + 4 + 8)));
+        for (key, value) in &entries {
+            assert_eq!(table.get(key).unwrap().as_ref(), Some(value));
+        }
+        assert_eq!(table.get(b"key001").unwrap(), None);
+        assert_eq!(table.get(b"key999").unwrap(), None);
+        assert_eq!(table.value_len(b"key010").unwrap(), Some(Some(8)));
+        // Keys of another length are ruled out without reading
+        assert!(!table.may_contain(b"key0000"));
+        assert_eq!(table.get(b"key").unwrap(), None);
+        assert_eq!(
+            table.iter_range(b"key010", Some(b"key016")).map(|e| e.unwrap()).collect::<Vec<_>>(),
+            entries[5..8].to_vec(),
+        );
+        assert_eq!(table.get_sorted(&[b"key000", b"key001", b"key198"]).unwrap(), vec![
+            Some(entries[0].1.clone()),
+            None,
+            Some(entries[99].1.clone()),
+        ]);
+        table.load_offsets().unwrap();
+        assert_eq!(table.get(b"key100").unwrap().as_ref(), Some(&entries[50].1));
+
+        // With alignment, deletions, or entries of different lengths
+        let buf = write_sstable(&entries, 64, 0.01, None);
+        let table = SSTableReader::open(BufReader(buf)).unwrap();
+        assert_eq!(table.fixed, Some((6, 64)));
+        assert_eq!(table.get(b"key198").unwrap().as_ref(), Some(&entries[99].1));
+        let mut other = entries.clone();
+        other[10].1 = None;
+        assert_eq!(write_sstable(&other, 1, 0.01, None)[4] & SSTABLE_FLAG_FIXED, 0);
+        other[10].1 = Some(v(b"longer value"));
+        assert_eq!(write_sstable(&other, 1, 0.01, None)[4] & SSTABLE_FLAG_FIXED, 0);
+
+        // Through the database
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let mut db = Database::open(DirectoryStorage::new(dir.path()).unwrap()).unwrap();
+        for (key, value) in &entries {
+            db.put(key, value.as_ref().unwrap()).unwrap();
+        }
+        db.maintain().unwrap();
+        assert_eq!(std::fs::read(dir.path().join("1-0.sst")).unwrap()[4] & SSTABLE_FLAG_FIXED, SSTABLE_FLAG_FIXED);
+        drop(db);
+        let mut db = Database::open(DirectoryStorage::new(dir.path()).unwrap()).unwrap();
+        assert_eq!(db.get(b"key042").unwrap(), Some(v(b"00000021")));
+        assert_eq!(db.get(b"key043").unwrap(), None);
+        assert_eq!(db.count_range(b"key000", b"key100").unwrap(), 50);
+    }
+
+    #[test]
+    fn test_sstable_alignment() {
+        use crate::{sstable_len, write_sstable, write_sstable_with_format, EntryIndex, SSTableReader};
+
+        let entries = (0..50u32)
+            .map(|i| (format!("key{:02}", i).into_bytes(), Some(vec![b'x'; i as usize * 100])))
+            .collect::<Vec<_>>();
+
+        for buf in [write_sstable(&entries, 4096, 0.01, None), write_sstable_with_format(&entries, EntryIndex::Offsets64, 4096, 0.01, None)] {
+            let file = BufReader(buf);
+            let mut table = SSTableReader::open(&file).unwrap();
+            assert_eq!(table.alignment, 4096);
+            assert_eq!(table.section_entries() % 4096, 0);
+            for i in 0..entries.len() {
+                assert_eq!(table.get_offset(i).unwrap() % 4096, 0);
+            }
+            for (key, value) in &entries {
+                assert_eq!(table.get(key).unwrap().as_ref(), Some(value));
+            }
+            assert_eq!(
+                table.iter_range(b"key10", Some(b"key13")).map(|e| e.unwrap()).collect::<Vec<_>>(),
+                entries[10..13].to_vec(),
+            );
+            table.load_offsets().unwrap();
+            assert_eq!(table.get(b"key49").unwrap().as_ref(), Some(&entries[49].1));
+        }
+        assert_eq!(write_sstable(&entries, 4096, 0.01, None).len() as u64, sstable_len(&entries, 4096, 0.01, None));
+
+        // Through the database
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let options = DatabaseOptions::builder().sstable_alignment(512).build();
+        let mut db = Database::open_with_options(storage, options).unwrap();
+        for (key, value) in &entries {
+            db.put(key, value.as_ref().unwrap()).unwrap();
+        }
+        let estimated = db.estimated_flush_bytes();
+        db.maintain().unwrap();
+        assert_eq!(std::fs::metadata(dir.path().join("1-0.sst")).unwrap().len(), estimated);
+        drop(db);
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        assert_eq!(
+            db.iter_range(b"", b"z").collect::<Vec<_>>(),
+            entries.into_iter().map(|(k, v)| (k, v.unwrap())).collect::<Vec<_>>(),
+        );
+    }
+
+    #[test]
+    fn test_check_sorted() {
+        use crate::check_sorted;
+
+        let entries = |keys: &[&[u8]]| keys.iter().map(|k| (k.to_vec(), Some(v(b"value")))).collect::<Vec<_>>();
+        assert!(check_sorted(&entries(&[])).is_ok());
+        assert!(check_sorted(&entries(&[b"a"])).is_ok());
+        assert!(check_sorted(&entries(&[b"a", b"ab", b"b"])).is_ok());
+        let err = check_sorted(&entries(&[b"a", b"c", b"b"])).unwrap_err();
+        assert_eq!(err.kind(), IoErrorKind::InvalidData);
+        assert!(check_sorted(&entries(&[b"a", b"b", b"b"])).is_err());
+
+        // The check is enabled by an option
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let options = DatabaseOptions::builder().check_sstable_order(true).build();
+        let mut db = Database::open_with_options(storage, options).unwrap();
+        db.put(b"b", b"1").unwrap();
+        db.put(b"a", b"2").unwrap();
+        db.maintain().unwrap();
+        db.put(b"c", b"3").unwrap();
+        db.maintain().unwrap();
+        db.compact(1).unwrap();
+        assert_eq!(db.count_range(b"", b"z").unwrap(), 3);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "unsorted entries")]
+    fn test_write_unsorted_sstable() {
+        use crate::write_sstable;
+
+        write_sstable(&[(v(b"b"), Some(v(b"1"))), (v(b"a"), Some(v(b"2")))], 1, 0.01, None);
+    }
+
+    #[test]
+    fn test_estimate_keys_between() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+
+        for i in 0..100u32 {
+            db.put(format!("key{:03}", i).as_bytes(), b"old").unwrap();
+        }
+        db.maintain().unwrap();
+        for i in 0..20u32 {
+            db.put(format!("key{:03}", i * 5).as_bytes(), b"new").unwrap();
+        }
+        db.maintain().unwrap();
+        for i in 100..120u32 {
+            db.put(format!("key{:03}", i).as_bytes(), b"mem").unwrap();
+        }
+
+        let ranges: &[(&[u8], &[u8])] = &[
+            (b"", b"z"),
+            (b"key010", b"key020"),
+            (b"key095", b"key110"),
+            (b"key100", b"key120"),
+        ];
+        for &(start, end) in ranges {
+            let exact = db.iter_range(start, end).count() as u64;
+            let estimate = db.estimate_keys_between(start, end).unwrap();
+            assert!(estimate >= exact);
+            assert!(estimate <= exact * 5 / 4);
+        }
+        assert_eq!(db.estimate_keys_between(b"key120", b"z").unwrap(), 0);
+    }
+
+    #[test]
+    fn test_oversized_length() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        // Without a bloom filter, so the index follows the header, and with
+        // values of different lengths, so there is an index
+        let options = DatabaseOptions::builder().bloom_false_positive_rate(1.0).build();
+        let mut db = Database::open_with_options(storage, options).unwrap();
+        db.put(b"abc", b"111").unwrap();
+        db.put(b"def", b"222").unwrap();
+        db.put(b"ghi", b"3333").unwrap();
+        db.maintain().unwrap();
+        drop(db);
+
+        // Set the length of the middle key, read first by the binary search,
+        // to almost 4 GiB
+        let path = dir.path().join("1-0.sst");
+        let mut table = std::fs::read(&path).unwrap();
+        let header_len = 5 + 3 * 4;
+        let entry_len = 4 + 3 + 4 + 3;
+        table[header_len + entry_len..header_len + entry_len + 4].copy_from_slice(&[0xFF, 0xFF, 0xFF, 0xF0]);
+        std::fs::write(&path, &table).unwrap();
+
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        let err = db.get(b"def").unwrap_err();
+        assert_eq!(err.kind(), IoErrorKind::InvalidData);
+        assert!(err.to_string().contains("1-0.sst"));
+        assert!(db.try_iter_range(b"a", b"z").collect::<Result<Vec<_>, _>>().is_err());
+
+        // The plain iterator stops and keeps the error
+        let mut iter = db.iter_range(b"a", b"z");
+        assert_eq!(iter.by_ref().count(), 0);
+        assert!(iter.take_error().is_some());
+    }
+
+    #[test]
+    fn test_auto_flush() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let mut db = Database::open(DirectoryStorage::new(dir.path()).unwrap()).unwrap();
+        let value = vec![b'x'; 1024 * 1024];
+        for i in 0..3 {
+            db.put(format!("key{}", i).as_bytes(), &value).unwrap();
+        }
+        assert_eq!(db.memtable_size(), 3 * (4 + 1024 * 1024));
+        assert!(!dir.path().join("1-0.sst").exists());
+
+        // Going over the default limit of 4 MiB flushes
+        db.put(b"key3", &value).unwrap();
+        assert!(dir.path().join("1-0.sst").exists());
+        assert_eq!(db.memtable_size(), 0);
+        db.delete(b"key0").unwrap();
+        assert_eq!(db.memtable_size(), 4);
+        assert_eq!(db.get(b"key1").unwrap(), Some(value));
+
+        // It can be raised
+        drop(db);
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let options = DatabaseOptions::builder().max_memtable_bytes(u64::MAX).build();
+        let mut db = Database::open_with_options(storage, options).unwrap();
+        for i in 0..5 {
+            db.put(format!("key{}", i).as_bytes(), &[b'x'; 1024 * 1024]).unwrap();
+        }
+        assert!(!dir.path().join("1-1.sst").exists());
+        assert_eq!(db.stats().flushes, 0);
+    }
+
+    #[test]
+    fn test_database_options() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap().with_append_buffer(64 * 1024);
+        let options = DatabaseOptions::builder()
+            .max_memtable_bytes(1000)
+            .sync_on_write(true)
+            .build();
+        let mut db = Database::open_with_options(storage, options).unwrap();
+
+        // Synced, so the write is in the WAL despite the buffer
+        db.put(b"abc", b"111").unwrap();
+        let wal_len = || std::fs::metadata(dir.path().join("wal")).unwrap().len();
+        assert_eq!(wal_len(), 8 + 1 + 4 + 3 + 4 + 3);
+        db.delete(b"abc").unwrap();
+        assert_eq!(wal_len(), 8 + 1 + 4 + 3 + 4 + 3 + 8 + 1 + 4 + 3);
+
+        // The custom memtable limit is used
+        for i in 0..90u32 {
+            db.put(format!("key{:03}", i).as_bytes(), b"value").unwrap();
+        }
+        assert!(!dir.path().join("1-0.sst").exists());
+        for i in 90..100u32 {
+            db.put(format!("key{:03}", i).as_bytes(), b"value").unwrap();
+        }
+        assert!(dir.path().join("1-0.sst").exists());
+        assert_eq!(db.get(b"key050").unwrap(), Some(v(b"value")));
+
+        // Without syncing, writes stay in the buffer
+        drop(db);
+        let storage = DirectoryStorage::new(dir.path()).unwrap().with_append_buffer(64 * 1024);
+        let mut db = Database::open(storage).unwrap();
+        let before = wal_len();
+        db.put(b"def", b"222").unwrap();
+        assert_eq!(wal_len(), before);
+        drop(db);
+        assert!(wal_len() > before);
+    }
+
+    #[test]
+    fn test_flush_if_needed() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let options = DatabaseOptions::builder().max_memtable_bytes(100).build();
+        let mut db = Database::open_with_options(storage, options).unwrap();
+        assert!(!db.flush_if_needed().unwrap());
+        db.put(b"key0", b"value").unwrap();
+        assert_eq!(db.memtable_size(), 9);
+        assert!(!db.flush_if_needed().unwrap());
+
+        // The memtable limit is enforced by the writes themselves
+        for i in 1..20 {
+            db.put(format!("key{}", i).as_bytes(), b"value").unwrap();
+        }
+        assert!(dir.path().join("1-0.sst").exists());
+        assert!(db.memtable_size() <= 100);
+        assert!(!db.flush_if_needed().unwrap());
+        db.flush().unwrap();
+
+        // WAL limit, with a memtable that stays small
+        drop(db);
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let options = DatabaseOptions::builder().max_wal_bytes(250).build();
+        let mut db = Database::open_with_options(storage, options).unwrap();
+        for _ in 0..5 {
+            db.put(b"key", b"value").unwrap();
+            db.delete(b"key").unwrap();
+        }
+        assert!(!db.flush_if_needed().unwrap());
+        for _ in 0..5 {
+            db.put(b"key", b"value").unwrap();
+            db.delete(b"key").unwrap();
+        }
+        assert!(db.flush_if_needed().unwrap());
+        assert_eq!(std::fs::metadata(dir.path().join("wal")).unwrap().len(), 0);
+        assert!(!db.flush_if_needed().unwrap());
+        assert_eq!(db.get(b"key5").unwrap(), Some(v(b"value")));
+    }
+
+    #[test]
+    fn test_replay_max_memtable_bytes() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        db.put(b"key0000", b"old").unwrap();
+        db.maintain().unwrap();
+        for i in 0..1000u32 {
+            db.put(format!("key{:04}", i).as_bytes(), &[b'x'; 100]).unwrap();
+        }
+        db.put(b"key0500", b"new").unwrap();
+        drop(db);
+
+        let options = DatabaseOptions::builder().max_memtable_bytes(10_000).build();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open_with_options(storage, options).unwrap();
+        let tables = std::fs::read_dir(dir.path()).unwrap()
+            .filter(|e| e.as_ref().unwrap().file_name().to_str().unwrap().ends_with(".sst"))
+            .count();
+        assert!(tables > 5);
+        assert_eq!(std::fs::metadata(dir.path().join("wal")).unwrap().len(), 0);
+
+        let check = |db: &mut Database<DirectoryStorage>| {
+            assert_eq!(db.count_range(b"", b"z").unwrap(), 1000);
+            assert_eq!(db.get(b"key0000").unwrap(), Some(vec![b'x'; 100]));
+            assert_eq!(db.get(b"key0500").unwrap(), Some(v(b"new")));
+            assert_eq!(db.get(b"key0999").unwrap(), Some(vec![b'x'; 100]));
+        };
+        check(&mut db);
+        drop(db);
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        check(&mut db);
+    }
+
+    #[test]
+    fn test_replay_max_memtable_bytes_truncated() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let options = || DatabaseOptions::builder().max_memtable_bytes(10_000).build();
+        let list = || {
+            let mut files = std::fs::read_dir(dir.path()).unwrap()
+                .map(|e| e.unwrap().file_name().into_string().unwrap())
+                .collect::<Vec<_>>();
+            files.sort();
+            files
+        };
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open_with_options(storage, DatabaseOptions::builder().max_memtable_bytes(u64::MAX).build()).unwrap();
+        for i in 0..1000u32 {
+            db.put(format!("key{:04}", i).as_bytes(), &[b'x'; 100]).unwrap();
+        }
+        db.put(b"key0500", b"new").unwrap();
+        let wal = std::fs::read(dir.path().join("wal")).unwrap();
+        db.maintain().unwrap();
+        drop(db);
+
+        // The truncation after writing the sstable was interrupted
+        let wal = [
+            &wal[..],
+            &wal_record(b"\x02\x00\x00\x00\x071-0.sst"),
+            &wal_record(b"\x03\x00\x00\x00\x071-0.sst"),
+            &wal_record(b"\x04\x00\x00\x00\x071-0.sst"),
+            &wal_record(b"\x00\x00\x00\x00\x07key1000\x00\x00\x00\x03new"),
+        ].concat();
+        std::fs::write(dir.path().join("wal"), &wal).unwrap();
+
+        // The entries already in the sstable are not written out again,
+        // where they would shadow the more recent values
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open_with_options(storage, options()).unwrap();
+        assert_eq!(list(), vec!["1-0.sst", "wal"]);
+        assert_eq!(db.get(b"key0500").unwrap(), Some(v(b"new")));
+        assert_eq!(db.get(b"key1000").unwrap(), Some(v(b"new")));
+        assert_eq!(db.count_range(b"", b"z").unwrap(), 1001);
+    }
+
+    #[test]
+    fn test_replay_max_memtable_bytes_crash() {
+        // Storage failing to write the given sstable, as if the database
+        // crashed while replaying
+        struct FailWrite(DirectoryStorage, &'static str);
+
+        impl Storage for FailWrite {
+            type Reader = <DirectoryStorage as Storage>::Reader;
+            type Appender = <DirectoryStorage as Storage>::Appender;
+
+            fn read(&self, key: &str) -> Result<Self::Reader, IoError> {
+                self.0.read(key)
+            }
+
+            fn write(&self, key: &str, value: &[u8]) -> Result<(), IoError> {
+                if key == self.1 {
+                    self.0.write(key, &value[..value.len() / 2])?;
+                    return Err(IoError::other("crash"));
+                }
+                self.0.write(key, value)
+            }
+
+            fn append(&self, key: &str) -> Result<Self::Appender, IoError> {
+                self.0.append(key)
+            }
+
+            fn delete(&self, key: &str) -> Result<(), IoError> {
+                self.0.delete(key)
+            }
+
+            fn list(&self) -> Result<Vec<String>, IoError> {
+                self.0.list()
+            }
+
+            fn rename(&self, from: &str, to: &str) -> Result<(), IoError> {
+                self.0.rename(from, to)
+            }
+        }
+
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let options = || DatabaseOptions::builder().max_memtable_bytes(10_000).build();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        for i in 0..1000u32 {
+            db.put(format!("key{:04}", i).as_bytes(), &[b'x'; 100]).unwrap();
+        }
+        db.put(b"key0500", b"new").unwrap();
+        drop(db);
+
+        // The partial sstable was logged as started, and is deleted
+        let storage = FailWrite(DirectoryStorage::new(dir.path()).unwrap(), "1-2.sst");
+        assert!(Database::open_with_options(storage, options()).is_err());
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open_with_options(storage, options()).unwrap();
+        assert_eq!(db.open_report().incomplete_sstables_deleted, 1);
+        assert_eq!(db.get(b"key0500").unwrap(), Some(v(b"new")));
+        assert_eq!(db.count_range(b"", b"z").unwrap(), 1000);
+        drop(db);
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        assert_eq!(db.get(b"key0500").unwrap(), Some(v(b"new")));
+    }
+
+    #[test]
+    fn test_replay_max_memtable_bytes_flush_crash() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let options = || DatabaseOptions::builder().max_memtable_bytes(10_000).build();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open_with_options(storage, DatabaseOptions::builder().max_memtable_bytes(u64::MAX).build()).unwrap();
+        for i in 0..1000u32 {
+            db.put(format!("key{:04}", i).as_bytes(), &[b'x'; 100]).unwrap();
+        }
+        drop(db);
+
+        // The database crashed while flushing, after logging the start of
+        // the sstable but before writing it
+        let mut wal = std::fs::read(dir.path().join("wal")).unwrap();
+        wal.extend_from_slice(&wal_record(b"\x02\x00\x00\x00\x071-0.sst"));
+        std::fs::write(dir.path().join("wal"), &wal).unwrap();
+
+        // The sstables written during replay don't reuse its name, and are
+        // not deleted as incomplete
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open_with_options(storage, options()).unwrap();
+        assert_eq!(db.open_report().incomplete_sstables_deleted, 1);
+        assert!(db.sstable_info().iter().all(|table| table.id != (1, 0)));
+        assert_eq!(db.count_range(b"", b"z").unwrap(), 1000);
+        drop(db);
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        assert_eq!(db.get(b"key0000").unwrap(), Some(vec![b'x'; 100]));
+        assert_eq!(db.count_range(b"", b"z").unwrap(), 1000);
+    }
+
+    #[test]
+    fn test_replay_progress() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        for i in 0..5000u32 {
+            db.put(format!("key{}", i).as_bytes(), &[b'x'; 100]).unwrap();
+        }
+        drop(db);
+
+        let progress = Rc::new(RefCell::new(Vec::new()));
+        let options = DatabaseOptions {
+            replay_progress: Some(Box::new({
+                let progress = progress.clone();
+                move |p| {
+                    progress.borrow_mut().push(p);
+                    true
+                }
+            })),
+            ..Default::default()
+        };
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open_with_options(storage, options).unwrap();
+        assert_eq!(db.get(b"key4999").unwrap(), Some(vec![b'x'; 100]));
+
+        let progress = progress.borrow();
+        assert!(progress.len() > 5);
+        let total = progress[0].total_bytes;
+        assert!(progress.windows(2).all(|w| w[0].bytes_read < w[1].bytes_read));
+        assert!(progress.iter().all(|p| p.total_bytes == total));
+        assert_eq!(progress.last().unwrap().bytes_read, total);
+
+        // Abort
+        drop(db);
+        let options = DatabaseOptions {
+            replay_progress: Some(Box::new(|p| p.bytes_read < 100_000)),
+            ..Default::default()
+        };
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        assert!(matches!(
+            Database::open_with_options(storage, options),
+            Err(Error::ReplayAborted),
+        ));
+    }
+
+    #[test]
+    fn test_random_operations() {
+        // xorshift, deterministic across runs
+        let mut state = 0x2545F4914F6CDD1Du64;
+        let mut rand = move |n: u64| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state % n
+        };
+
+        let keys = (0..40u32).map(|i| format!("key{:02}", i).into_bytes()).collect::<Vec<_>>();
+
+        for _ in 0..10 {
+            let dir = TempDir::new("lsmtree-test").unwrap();
+            let storage = DirectoryStorage::new(dir.path()).unwrap();
+            let mut db = Database::open(storage).unwrap();
+
+            for step in 0..300 {
+                let key = &keys[rand(keys.len() as u64) as usize];
+                match rand(100) {
+                    0..=59 => db.put(key, format!("value{}", step).as_bytes()).unwrap(),
+                    60..=89 => db.delete(key).unwrap(),
+                    90..=95 => db.maintain().unwrap(),
+                    96..=97 => db.compact(1).unwrap(),
+                    _ => db.compact(2).unwrap(),
+                }
+
+                if step % 20 == 0 {
+                    assert_eq!(db.audit_consistency(&keys).unwrap(), Vec::<Vec<u8>>::new());
+                }
+            }
+
+            // Reopen
+            drop(db);
+            let storage = DirectoryStorage::new(dir.path()).unwrap();
+            let mut db = Database::open(storage).unwrap();
+            assert_eq!(db.audit_consistency(&keys).unwrap(), Vec::<Vec<u8>>::new());
+        }
+    }
+
+    #[test]
+    fn test_scan_pages() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        for i in 0..50u32 {
+            db.put(format!("key{:02}", i * 2).as_bytes(), b"old").unwrap();
+        }
+        db.maintain().unwrap();
+        for i in 0..50u32 {
+            db.put(format!("key{:02}", i * 2 + 1).as_bytes(), b"new").unwrap();
+        }
+
+        let mut scan = db.scan_from(b"key10");
+        let (page, cursor) = scan.next_page(30).unwrap();
+        assert_eq!(page.len(), 30);
+        assert_eq!(page[0].0, v(b"key10"));
+        assert_eq!(cursor, Some(Cursor::from_bytes(v(b"key40"))));
+        let (page, cursor) = scan.next_page(30).unwrap();
+        assert_eq!(page[0].0, v(b"key40"));
+        let cursor = cursor.unwrap().into_bytes();
+
+        // Resume after reopening
+        drop(db);
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        let mut keys = Vec::new();
+        let mut cursor = Some(Cursor::from_bytes(cursor));
+        while let Some(c) = cursor {
+            let (page, next) = db.scan_from(c.as_bytes()).next_page(7).unwrap();
+            assert!(page.len() <= 7);
+            keys.extend(page.into_iter().map(|(k, _)| k));
+            cursor = next;
+        }
+        assert_eq!(
+            keys,
+            (70..100u32).map(|i| format!("key{:02}", i).into_bytes()).collect::<Vec<_>>(),
+        );
+
+        // Pages of the exact size of the remaining entries
+        let mut scan = db.scan_from(b"key90");
+        let (page, cursor) = scan.next_page(10).unwrap();
+        assert_eq!((page.len(), cursor), (10, None));
+        assert_eq!(scan.next_page(10).unwrap(), (vec![], None));
+    }
+
+    #[test]
+    fn test_iter_prefixes() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+
+        db.put(b"a/1", b"").unwrap();
+        db.put(b"a/2", b"").unwrap();
+        db.put(b"b/1", b"").unwrap();
+        db.maintain().unwrap();
+        db.put(b"a/3", b"").unwrap();
+        db.put(b"c", b"").unwrap();
+        db.put(b"c-", b"").unwrap();
+        db.put(b"c/1/2", b"").unwrap();
+        db.put(b"d\xFF/1", b"").unwrap();
+
+        assert_eq!(
+            db.iter_prefixes(b'/').collect::<Result<Vec<_>, _>>().unwrap(),
+            vec![v(b"a"), v(b"b"), v(b"c"), v(b"c-"), v(b"d\xFF")],
+        );
+        assert_eq!(
+            db.iter_prefixes(0xFF).collect::<Result<Vec<_>, _>>().unwrap(),
+            vec![v(b"a/1"), v(b"a/2"), v(b"a/3"), v(b"b/1"), v(b"c"), v(b"c-"), v(b"c/1/2"), v(b"d")],
+        );
+    }
+
+    #[test]
+    fn test_truncated_sstable() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        db.put(b"abc", b"111").unwrap();
+        db.put(b"def", b"222").unwrap();
+        db.maintain().unwrap();
+        drop(db);
+
+        // Cut the table right after its header
+        let file = std::fs::OpenOptions::new().write(true).open(dir.path().join("1-0.sst")).unwrap();
+        file.set_len(7).unwrap();
+        drop(file);
+
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        match Database::open(storage) {
+            Err(Error::Corruption(Corruption::BadSstableHeader { name, .. })) => assert_eq!(name, "1-0.sst"),
+            Err(e) => panic!("unexpected error {:?}", e),
+            Ok(_) => panic!("truncated sstable was opened"),
+        }
+    }
+
+    #[test]
+    fn test_wal_truncate_record() {
+        // Storage whose WAL is never truncated, as if the database crashed
+        // right before
+        struct NoTruncate<A: Append>(A);
+
+        impl<A: Append> Append for NoTruncate<A> {
+            fn append(&mut self, buffer: &[u8]) -> Result<(), IoError> {
+                self.0.append(buffer)
+            }
+
+            fn truncate(&mut self) -> Result<(), IoError> {
+                Ok(())
+            }
+
+            fn flush(&mut self) -> Result<(), IoError> {
+                self.0.flush()
+            }
+        }
+
+        struct CrashBeforeTruncate(DirectoryStorage);
+
+        impl Storage for CrashBeforeTruncate {
+            type Reader = <DirectoryStorage as Storage>::Reader;
+            type Appender = NoTruncate<<DirectoryStorage as Storage>::Appender>;
+
+            fn read(&self, key: &str) -> Result<Self::Reader, IoError> {
+                self.0.read(key)
+            }
+
+            fn write(&self, key: &str, value: &[u8]) -> Result<(), IoError> {
+                self.0.write(key, value)
+            }
+
+            fn append(&self, key: &str) -> Result<Self::Appender, IoError> {
+                Ok(NoTruncate(self.0.append(key)?))
+            }
+
+            fn delete(&self, key: &str) -> Result<(), IoError> {
+                self.0.delete(key)
+            }
+
+            fn list(&self) -> Result<Vec<String>, IoError> {
+                self.0.list()
+            }
+
+            fn rename(&self, from: &str, to: &str) -> Result<(), IoError> {
+                self.0.rename(from, to)
+            }
+        }
+
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let mut db = Database::open(CrashBeforeTruncate(DirectoryStorage::new(dir.path()).unwrap())).unwrap();
+        db.put(b"abc", b"111").unwrap();
+        db.delete(b"def").unwrap();
+        db.maintain().unwrap();
+        drop(db);
+
+        // The WAL ends with the truncate record
+        let wal = std::fs::read(dir.path().join("wal")).unwrap();
+        let record = wal_record(b"\x04\x00\x00\x00\x071-0.sst");
+        assert!(wal.ends_with(&record));
+
+        // It is validated on reopen, and the records before it are not
+        // replayed
+        let mut db = Database::open(DirectoryStorage::new(dir.path()).unwrap()).unwrap();
+        assert_eq!(db.open_report().records_replayed, 5);
+        assert_eq!(db.flush().unwrap(), None);
+        assert_eq!(db.get(b"abc").unwrap(), Some(v(b"111")));
+        drop(db);
+
+        // With a bad checksum, the truncate record is discarded as partial,
+        // and the records before it are replayed
+        let mut corrupted = wal.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        std::fs::write(dir.path().join("wal"), &corrupted).unwrap();
+        let mut db = Database::open(DirectoryStorage::new(dir.path()).unwrap()).unwrap();
+        assert_eq!(db.open_report().records_replayed, 4);
+        assert!(db.open_report().trailing_discarded);
+        assert_eq!(db.get(b"abc").unwrap(), Some(v(b"111")));
+        drop(db);
+
+        // The sstable must have been completed
+        std::fs::write(dir.path().join("wal"), &record).unwrap();
+        match Database::open(DirectoryStorage::new(dir.path()).unwrap()) {
+            Err(Error::Corruption(Corruption::TruncateWithoutSstable(name))) => assert_eq!(name, "1-0.sst"),
+            Err(e) => panic!("unexpected error {:?}", e),
+            Ok(_) => panic!("invalid WAL was opened"),
+        }
+    }
+
+    #[test]
+    fn test_move_crash() {
+        // Storage whose rename fails, as if the database crashed before or
+        // after renaming the file
+        struct CrashOnRename(DirectoryStorage, bool);
+
+        impl Storage for CrashOnRename {
+            type Reader = <DirectoryStorage as Storage>::Reader;
+            type Appender = <DirectoryStorage as Storage>::Appender;
+
+            fn read(&self, key: &str) -> Result<Self::Reader, IoError> {
+                self.0.read(key)
+            }
+
+            fn write(&self, key: &str, value: &[u8]) -> Result<(), IoError> {
+                self.0.write(key, value)
+            }
+
+            fn append(&self, key: &str) -> Result<Self::Appender, IoError> {
+                self.0.append(key)
+            }
+
+            fn delete(&self, key: &str) -> Result<(), IoError> {
+                self.0.delete(key)
+            }
+
+            fn list(&self) -> Result<Vec<String>, IoError> {
+                self.0.list()
+            }
+
+            fn rename(&self, from: &str, to: &str) -> Result<(), IoError> {
+                if self.1 {
+                    self.0.rename(from, to)?;
+                }
+                Err(IoError::other("crash"))
+            }
+        }
+
+        for renamed in [false, true] {
+            let dir = TempDir::new("lsmtree-test").unwrap();
+            let mut db = Database::open(CrashOnRename(DirectoryStorage::new(dir.path()).unwrap(), renamed)).unwrap();
+            db.put(b"abc", b"111").unwrap();
+            db.maintain().unwrap();
+            assert!(db.compact(1).is_err());
+            drop(db);
+
+            // The move is completed on reopen
+            let mut db = Database::open(DirectoryStorage::new(dir.path()).unwrap()).unwrap();
+            assert_eq!(db.get(b"abc").unwrap(), Some(v(b"111")));
+            assert!(dir.path().join("2-0.sst").exists());
+            assert!(!dir.path().join("1-0.sst").exists());
+
+            // It is not done again once a new table reuses the name
+            db.put(b"def", b"222").unwrap();
+            db.maintain().unwrap();
+            drop(db);
+            let mut db = Database::open(DirectoryStorage::new(dir.path()).unwrap()).unwrap();
+            assert_eq!(db.get(b"abc").unwrap(), Some(v(b"111")));
+            assert_eq!(db.get(b"def").unwrap(), Some(v(b"222")));
+            assert!(dir.path().join("1-0.sst").exists());
+            assert!(dir.path().join("2-0.sst").exists());
+        }
+    }
+
+    #[test]
+    fn test_corruption_errors() {
+        fn open_error(files: &[(&str, &[u8])]) -> Corruption {
+            let dir = TempDir::new("lsmtree-test").unwrap();
+            for (name, content) in files {
+                std::fs::write(dir.path().join(name), content).unwrap();
+            }
+            match Database::open(DirectoryStorage::new(dir.path()).unwrap()) {
+                Err(Error::Corruption(corruption)) => corruption,
+                Err(e) => panic!("unexpected error {:?}", e),
+                Ok(_) => panic!("invalid database was opened"),
+            }
+        }
+
+        assert_eq!(open_error(&[("1-0.sst", b"")]), Corruption::MissingWal);
+        assert_eq!(open_error(&[("wal", b""), ("other", b"")]), Corruption::UnexpectedFile("other".into()));
+        assert_eq!(open_error(&[("wal", &wal_record(b"\x09"))]), Corruption::InvalidWalEntry);
+        assert_eq!(
+            open_error(&[("wal", &wal_record(b"\x01\x00\x00\x00\x05abc"))]),
+            Corruption::InvalidWalEntry,
+        );
+        assert_eq!(open_error(&[("wal", b""), ("x.sst", b"")]), Corruption::InvalidTableName("x.sst".into()));
+        assert_eq!(
+            open_error(&[("wal", &wal_record(b"\x02\x00\x00\x00\x02\xC3\xA9"))]),
+            Corruption::InvalidTableName("\u{e9}".into()),
+        );
+        assert_eq!(
+            open_error(&[("wal", b""), ("1-0.sst", b"\x00\x00\x00\x00\x80")]),
+            Corruption::BadSstableHeader { name: "1-0.sst".into(), reason: "Unknown sstable flags".into() },
+        );
+        assert_eq!(
+            open_error(&[("wal", &wal_record(b"\x03\x00\x00\x00\x071-0.sst"))]),
+            Corruption::UnmatchedSstableEnd("1-0.sst".into()),
+        );
+        assert_eq!(
+            open_error(&[("wal", &wal_record(b"\x02\x00\x00\x00\x071-0.sst").repeat(2))]),
+            Corruption::DuplicateSstableStart("1-0.sst".into()),
+        );
+    }
+
+    #[test]
+    fn test_sstable_allowlist() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let mut db = Database::open(DirectoryStorage::new(dir.path()).unwrap()).unwrap();
+        db.put(b"abc", b"111").unwrap();
+        db.maintain().unwrap();
+        drop(db);
+
+        // Add a stray table, shadowing the value
+        std::fs::write(dir.path().join("1-5.sst"), crate::write_sstable(&[(v(b"abc"), Some(v(b"stray")))], 1, 0.01, None)).unwrap();
+        let mut db = Database::open(DirectoryStorage::new(dir.path()).unwrap()).unwrap();
+        assert_eq!(db.get(b"abc").unwrap(), Some(v(b"stray")));
+        drop(db);
+
+        let options = DatabaseOptions::builder()
+            .sstable_allowlist(["1-0.sst".to_owned()])
+            .build();
+        let mut db = Database::open_with_options(DirectoryStorage::new(dir.path()).unwrap(), options).unwrap();
+        assert_eq!(db.get(b"abc").unwrap(), Some(v(b"111")));
+        assert!(dir.path().join("1-5.sst").exists());
+    }
+
+    #[test]
+    fn test_truncated_value() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        db.put(b"zzz", b"old").unwrap();
+        db.maintain().unwrap();
+        db.put(b"abc", b"111").unwrap();
+        db.put(b"zzz", &[b'x'; 1000]).unwrap();
+        db.maintain().unwrap();
+        drop(db);
+
+        // Cut the last value short
+        let path = dir.path().join("1-1.sst");
+        let len = std::fs::metadata(&path).unwrap().len();
+        std::fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 500).unwrap();
+
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        assert_eq!(db.get(b"abc").unwrap(), Some(v(b"111")));
+        let err = db.get(b"zzz").unwrap_err();
+        assert_eq!(err.kind(), IoErrorKind::InvalidData);
+        let message = err.to_string();
+        assert!(message.contains("1-1.sst") && message.contains("zzz"), "{}", message);
+        drop(db);
+
+        let options = DatabaseOptions::builder().corruption_policy(CorruptionPolicy::Skip).build();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open_with_options(storage, options).unwrap();
+        assert_eq!(db.get(b"abc").unwrap(), Some(v(b"111")));
+        assert_eq!(db.get(b"zzz").unwrap(), Some(v(b"old")));
+        assert_eq!(db.get_parallel(b"zzz").unwrap(), Some(v(b"old")));
+    }
+
+    #[test]
+    fn test_warmup() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = CountingStorage::new(DirectoryStorage::new(dir.path()).unwrap());
+        let mut db = Database::open(storage).unwrap();
+        // Values of different lengths, so the table has an offset table
+        for i in 0..1000u32 {
+            db.put(format!("key{:04}", i).as_bytes(), format!("value{}", i).as_bytes()).unwrap();
+        }
+        db.maintain().unwrap();
+
+        db.storage.take_reads();
+        assert_eq!(db.get(b"key0123").unwrap(), Some(v(b"value123")));
+        let cold_reads = db.storage.take_reads();
+
+        db.warmup().unwrap();
+        db.storage.take_reads();
+        assert_eq!(db.get(b"key0123").unwrap(), Some(v(b"value123")));
+        let warm_reads = db.storage.take_reads();
+        assert!(warm_reads < cold_reads);
+        assert_eq!(db.get(b"key1000").unwrap(), None);
+    }
+
+    #[test]
+    fn test_iter_with_level() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+
+        db.put(b"a", b"1").unwrap();
+        db.put(b"b", b"1").unwrap();
+        db.maintain().unwrap();
+        db.compact(1).unwrap();
+        db.put(b"b", b"2").unwrap();
+        db.put(b"c", b"2").unwrap();
+        db.maintain().unwrap();
+        db.put(b"c", b"3").unwrap();
+        db.put(b"d", b"3").unwrap();
+
+        assert_eq!(
+            db.iter_with_level().collect::<Result<Vec<_>, _>>().unwrap(),
+            vec![
+                (v(b"a"), v(b"1"), Some((2, 0))),
+                (v(b"b"), v(b"2"), Some((1, 0))),
+                (v(b"c"), v(b"3"), None),
+                (v(b"d"), v(b"3"), None),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_buffered_wal() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap().with_append_buffer(4096);
+        let mut db = Database::open(storage).unwrap();
+        for i in 0..100u32 {
+            db.put(format!("key{:03}", i).as_bytes(), b"value").unwrap();
+        }
+        db.delete(b"key050").unwrap();
+        drop(db);
+
+        let storage = DirectoryStorage::new(dir.path()).unwrap().with_append_buffer(4096);
+        let mut db = Database::open(storage).unwrap();
+        assert_eq!(db.count_range(b"", b"z").unwrap(), 99);
+        assert_eq!(db.get(b"key099").unwrap(), Some(v(b"value")));
+    }
+
+    #[test]
+    fn test_level_ranges() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        assert_eq!(db.level_ranges().unwrap(), vec![]);
+
+        db.put(b"d", b"").unwrap();
+        db.put(b"f", b"").unwrap();
+        db.maintain().unwrap();
+        db.put(b"b", b"").unwrap();
+        db.put(b"e", b"").unwrap();
+        db.maintain().unwrap();
+        db.compact(1).unwrap();
+        db.put(b"c", b"").unwrap();
+        db.maintain().unwrap();
+        db.put(b"a", b"").unwrap();
+        db.put(b"g", b"").unwrap();
+        db.maintain().unwrap();
+
+        assert_eq!(
+            db.level_ranges().unwrap(),
+            vec![
+                (1, Some((v(b"a"), v(b"g")))),
+                (2, Some((v(b"b"), v(b"f")))),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_overlap_graph() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        assert_eq!(db.overlap_graph().unwrap(), vec![]);
+
+        db.put(b"a", b"").unwrap();
+        db.put(b"c", b"").unwrap();
+        db.maintain().unwrap();
+        db.put(b"c", b"").unwrap();
+        db.put(b"d", b"").unwrap();
+        db.maintain().unwrap();
+        db.put(b"e", b"").unwrap();
+        db.put(b"f", b"").unwrap();
+        db.maintain().unwrap();
+        db.put(b"b", b"").unwrap();
+        db.maintain().unwrap();
+
+        assert_eq!(
+            db.overlap_graph().unwrap(),
+            vec![
+                ((1, 3), (1, 2), false),
+                ((1, 3), (1, 1), false),
+                ((1, 3), (1, 0), true),
+                ((1, 2), (1, 1), false),
+                ((1, 2), (1, 0), false),
+                ((1, 1), (1, 0), true),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_scan_read_ahead() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        for i in 0..300u32 {
+            db.put(format!("key{:03}", i).as_bytes(), b"value").unwrap();
+            if i % 100 == 99 {
+                db.maintain().unwrap();
+            }
+        }
+        db.put(b"key150", b"new").unwrap();
+        drop(db);
+
+        let scan = |options: DatabaseOptions| {
+            let storage = CountingStorage::new(DirectoryStorage::new(dir.path()).unwrap());
+            let mut db = Database::open_with_options(storage, options).unwrap();
+            db.storage.take_reads();
+            let entries = db.iter_range(b"key050", b"key250").collect::<Vec<_>>();
+            (entries, db.storage.take_reads())
+        };
+        let (entries, reads) = scan(Default::default());
+        let (entries_read_ahead, reads_read_ahead) = scan(DatabaseOptions::builder().scan_read_ahead(4096).build());
+        assert_eq!(entries.len(), 200);
+        assert_eq!(entries[100], (v(b"key150"), v(b"new")));
+        assert_eq!(entries_read_ahead, entries);
+        assert!(reads_read_ahead * 10 < reads);
+
+        // Read-ahead smaller than an entry still works
+        let (entries_small, _) = scan(DatabaseOptions::builder().scan_read_ahead(3).build());
+        assert_eq!(entries_small, entries);
+    }
+
+    #[test]
+    fn test_register_sstable() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let open = || Database::open(DirectoryStorage::new(dir.path()).unwrap()).unwrap();
+        let mut db = open();
+        db.put(b"abc", b"111").unwrap();
+        db.maintain().unwrap();
+
+        let id = db.allocate_sstable_id(1).unwrap();
+        assert_eq!(id, 1);
+        // The reservation is kept across WAL truncations and crashes
+        db.put(b"def", b"222").unwrap();
+        db.maintain().unwrap();
+        drop(db);
+        let mut db = open();
+        let other = db.allocate_sstable_id(1).unwrap();
+        assert_eq!(other, 3);
+        assert!(db.register_sstable(1, 7, "import.sst.tmp").is_err());
+
+        // Write the table externally and register it
+        let entries = [(v(b"abc"), Some(v(b"imported"))), (v(b"ghi"), Some(v(b"imported")))];
+        std::fs::write(dir.path().join("import.sst.tmp"), crate::write_sstable(&entries, 1, 0.01, None)).unwrap();
+        db.register_sstable(1, id, "import.sst.tmp").unwrap();
+        assert!(db.register_sstable(1, id, "import.sst.tmp").is_err());
+        assert_eq!(db.get(b"abc").unwrap(), Some(v(b"imported")));
+        assert_eq!(db.get(b"def").unwrap(), Some(v(b"222")));
+        assert_eq!(db.get(b"ghi").unwrap(), Some(v(b"imported")));
+        assert!(dir.path().join("1-1.sst").exists());
+
+        drop(db);
+        let mut db = open();
+        assert_eq!(db.get(b"ghi").unwrap(), Some(v(b"imported")));
+        assert_eq!(db.allocate_sstable_id(1).unwrap(), 4);
+
+        // A crash after renaming the table, before logging its end, doesn't
+        // lose it
+        let entries = [(v(b"jkl"), Some(v(b"imported")))];
+        std::fs::write(dir.path().join("1-3.sst"), crate::write_sstable(&entries, 1, 0.01, None)).unwrap();
+        drop(db);
+        let mut db = open();
+        assert_eq!(db.get(b"jkl").unwrap(), Some(v(b"imported")));
+        assert!(db.register_sstable(1, 3, "1-3.sst").is_err());
+        drop(db);
+        let mut db = open();
+        assert_eq!(db.get(b"jkl").unwrap(), Some(v(b"imported")));
+        assert_eq!(db.allocate_sstable_id(1).unwrap(), 5);
+    }
+
+    #[test]
+    fn test_get_from_level() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        db.put(b"abc", b"level2").unwrap();
+        db.put(b"def", b"level2").unwrap();
+        db.maintain().unwrap();
+        db.compact(1).unwrap();
+        db.put(b"abc", b"level1").unwrap();
+        db.maintain().unwrap();
+        db.put(b"abc", b"memtable").unwrap();
+
+        assert_eq!(db.get(b"abc").unwrap(), Some(v(b"memtable")));
+        assert_eq!(db.get_from_level(b"abc", 0).unwrap(), Some(v(b"memtable")));
+        assert_eq!(db.get_from_level(b"abc", 1).unwrap(), Some(v(b"level1")));
+        assert_eq!(db.get_from_level(b"abc", 2).unwrap(), Some(v(b"level2")));
+        assert_eq!(db.get_from_level(b"abc", 3).unwrap(), None);
+        assert_eq!(db.get_from_level(b"def", 0).unwrap(), None);
+        assert_eq!(db.get_from_level(b"def", 1).unwrap(), None);
+        assert_eq!(db.get_from_level(b"def", 2).unwrap(), Some(v(b"level2")));
+    }
+
+    #[test]
+    fn test_sample_keys() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = CountingStorage::new(DirectoryStorage::new(dir.path()).unwrap());
+        let mut db = Database::open(storage).unwrap();
+        assert_eq!(db.sample_keys(10).unwrap(), Vec::<Vec<u8>>::new());
 
-    use crate::{Database, DirectoryStorage};
+        // Interleave the keys of the tables
+        for table in 0..4u32 {
+            for i in 0..250u32 {
+                db.put(format!("key{:04}", i * 4 + table).as_bytes(), b"value").unwrap();
+            }
+            db.maintain().unwrap();
+        }
+        for i in 0..100u32 {
+            db.put(format!("key{:04}", 1000 + i).as_bytes(), b"value").unwrap();
+        }
+        db.storage.take_reads();
 
-    fn v(s: &[u8]) -> Vec<u8> {
-        s.into()
+        let samples = db.sample_keys(11).unwrap();
+        assert!(db.storage.take_reads() < 200);
+        assert_eq!(samples.len(), 11);
+        assert!(samples.windows(2).all(|w| w[0] < w[1]));
+        let positions = samples.iter()
+            .map(|key| std::str::from_utf8(&key[3..]).unwrap().parse::<u32>().unwrap())
+            .collect::<Vec<_>>();
+        for w in positions.windows(2) {
+            assert!(w[1] - w[0] > 50 && w[1] - w[0] < 150, "uneven samples {:?}", positions);
+        }
+        for key in &samples {
+            assert!(db.get(key).unwrap().is_some());
+        }
     }
 
     #[test]
-    fn test_database() {
-        pretty_env_logger::formatted_timed_builder()
-            .parse_filters("info")
-            .try_init().unwrap();
+    fn test_purge_keys() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+
+        db.put(b"alice", b"secret1").unwrap();
+        db.put(b"bob", b"public").unwrap();
+        db.maintain().unwrap();
+        db.put(b"alice", b"secret2").unwrap();
+        db.put(b"carol", b"public").unwrap();
+        db.maintain().unwrap();
+        db.compact(1).unwrap();
+        db.put(b"alice", b"secret3").unwrap();
+        db.put(b"dave", b"public").unwrap();
+        db.maintain().unwrap();
+        db.put(b"alice", b"secret4").unwrap();
+
+        // A tombstone that was already flushed
+        db.delete(b"erin").unwrap();
+        db.maintain().unwrap();
+
+        db.purge_keys(&[b"alice", b"erin"]).unwrap();
+        assert_eq!(db.get(b"alice").unwrap(), None);
+        assert_eq!(db.get(b"bob").unwrap(), Some(v(b"public")));
+        assert_eq!(db.get(b"dave").unwrap(), Some(v(b"public")));
+
+        // Neither the sstables nor the WAL hold the keys, not even as
+        // tombstones
+        for entry in std::fs::read_dir(dir.path()).unwrap() {
+            let data = std::fs::read(entry.unwrap().path()).unwrap();
+            assert!(!data.windows(5).any(|w| w == b"alice"));
+            assert!(!data.windows(4).any(|w| w == b"erin"));
+            assert!(!data.windows(6).any(|w| w == b"secret"));
+        }
 
+        drop(db);
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        assert_eq!(db.get(b"alice").unwrap(), None);
+        assert_eq!(db.count_range(b"", b"z").unwrap(), 3);
+    }
+
+    #[test]
+    fn test_drop_prefix() {
         let dir = TempDir::new("lsmtree-test").unwrap();
         let storage = DirectoryStorage::new(dir.path()).unwrap();
         let mut db = Database::open(storage).unwrap();
+        for round in 0..3u32 {
+            for tenant in [&b"t1/"[..], b"t2/", b"\xFF", b"\xFF\xFF"] {
+                for i in 0..50u32 {
+                    let mut key = tenant.to_vec();
+                    key.extend_from_slice(format!("{:02}-{}", i, round).as_bytes());
+                    db.put(&key, b"value").unwrap();
+                }
+            }
+            if round < 2 {
+                db.maintain().unwrap();
+            }
+        }
+        db.put(b"t1", b"value").unwrap();
 
-        fn check(db: &mut Database<DirectoryStorage>) {
-            db.put(b"ghi", b"111").unwrap();
-            db.put(b"abc", b"222").unwrap();
-            db.put(b"mno", b"333").unwrap();
-            db.put(b"ghi", b"444").unwrap();
-            db.put(b"def", b"555").unwrap();
-            db.put(b"jkl", b"666").unwrap();
-            db.put(b"def", b"777").unwrap();
-            db.delete(b"ghi").unwrap();
+        assert_eq!(db.drop_prefix(b"t1/").unwrap(), 150);
+        assert_eq!(db.count_range(b"t1/", b"t10").unwrap(), 0);
+        assert_eq!(db.count_range(b"t2/", b"t20").unwrap(), 150);
+        assert_eq!(db.get(b"t1").unwrap(), Some(v(b"value")));
+
+        // No successor for the prefix
+        assert_eq!(db.drop_prefix(b"\xFF\xFF").unwrap(), 150);
+        assert_eq!(db.count_range(b"\xFF", b"\xFF\xFF").unwrap(), 150);
+        assert_eq!(db.drop_prefix(b"\xFF").unwrap(), 150);
+
+        // The sstables hold neither the keys nor tombstones for them
+        for entry in std::fs::read_dir(dir.path()).unwrap() {
+            let data = std::fs::read(entry.unwrap().path()).unwrap();
+            assert!(!data.windows(3).any(|w| w == b"t1/"));
         }
-        check(&mut db);
 
+        drop(db);
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        assert_eq!(
+            db.iter_with_level().map(|e| e.unwrap().0).collect::<Vec<_>>(),
+            std::iter::once(v(b"t1"))
+                .chain((0..50u32).flat_map(|i| (0..3u32).map(move |r| format!("t2/{:02}-{}", i, r).into_bytes())))
+                .collect::<Vec<_>>(),
+        );
+    }
+
+    #[test]
+    fn test_drop_prefix_crash() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = FailDelete(DirectoryStorage::new(dir.path()).unwrap(), "1-0.sst");
+        let mut db = Database::open(storage).unwrap();
+        db.put(b"t1/a", b"111").unwrap();
         db.maintain().unwrap();
+        db.put(b"t1/b", b"222").unwrap();
+        db.put(b"t2/a", b"333").unwrap();
+        db.maintain().unwrap();
+        db.put(b"t1/c", b"444").unwrap();
+
+        // The drop is a single WAL record
+        assert!(db.drop_prefix(b"t1/").is_err());
+        let wal = std::fs::read(dir.path().join("wal")).unwrap();
+        assert_eq!(wal, wal_record(b"\x07\x00\x00\x00\x03t1/"));
+        drop(db);
+
+        // Replay finishes rewriting the sstables
+        let check = |db: &mut Database<DirectoryStorage>| {
+            assert_eq!(db.iter_range(b"", b"z").collect::<Vec<_>>(), vec![(v(b"t2/a"), v(b"333"))]);
+        };
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        check(&mut db);
+        assert!(!dir.path().join("1-0.sst").exists());
+        drop(db);
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
         check(&mut db);
 
-        assert_eq!(db.get(b"abc").unwrap(), Some(v(b"222")));
-        assert_eq!(db.get(b"def").unwrap(), Some(v(b"777")));
-        assert_eq!(db.get(b"ghi").unwrap(), None);
-        assert_eq!(db.get(b"jkl").unwrap(), Some(v(b"666")));
-        assert_eq!(db.get(b"mno").unwrap(), Some(v(b"333")));
-        assert_eq!(db.get(b"zzz").unwrap(), None);
+        // Keys written after the drop are kept
+        db.put(b"t1/d", b"555").unwrap();
+        drop(db);
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        assert_eq!(db.get(b"t1/d").unwrap(), Some(v(b"555")));
+        assert_eq!(db.count_range(b"", b"z").unwrap(), 2);
+    }
+
+    #[test]
+    fn test_repair_ordering() {
+        use crate::{write_sstable_with_format, EntryIndex};
+
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        db.put(b"key5", b"new").unwrap();
+        db.maintain().unwrap();
+        drop(db);
+
+        // Older table, out of order and with a duplicate
+        let entries = [b"key3", b"key1", b"key5", b"key4", b"key1", b"key2"].iter()
+            .enumerate()
+            .map(|(i, k)| (k.to_vec(), Some(format!("{}", i).into_bytes())))
+            .collect::<Vec<_>>();
+        let buf = write_sstable_with_format(&entries, EntryIndex::Offsets32, 1, 0.01, None);
+        std::fs::write(dir.path().join("2-0.sst"), buf).unwrap();
+
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        assert_eq!(db.repair_ordering().unwrap(), 1);
+        assert_eq!(db.repair_ordering().unwrap(), 0);
 
+        assert_eq!(db.get(b"key1").unwrap(), Some(v(b"4")));
+        assert_eq!(db.get(b"key2").unwrap(), Some(v(b"5")));
+        assert_eq!(db.get(b"key3").unwrap(), Some(v(b"0")));
+        assert_eq!(db.get(b"key4").unwrap(), Some(v(b"3")));
+        assert_eq!(db.get(b"key5").unwrap(), Some(v(b"new")));
         assert_eq!(
-            db.iter_range(b"def", b"jkl").collect::<Vec<_>>(),
+            db.iter_range(b"", b"z").collect::<Vec<_>>(),
             vec![
-                (v(b"def"), v(b"777")),
+                (v(b"key1"), v(b"4")),
+                (v(b"key2"), v(b"5")),
+                (v(b"key3"), v(b"0")),
+                (v(b"key4"), v(b"3")),
+                (v(b"key5"), v(b"new")),
             ],
         );
+    }
+
+    #[test]
+    fn test_may_contain() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        for prefix in ["b", "d"] {
+            for i in 0..100u32 {
+                db.put(format!("{}{:02}", prefix, i).as_bytes(), b"value").unwrap();
+            }
+            db.maintain().unwrap();
+        }
+        db.put(b"m", b"value").unwrap();
+
+        for key in [&b"b00"[..], b"b50", b"d99", b"m"] {
+            assert!(db.may_contain(key).unwrap());
+        }
+        let mut false_positives = 0;
+        for prefix in ["a", "b", "c", "d", "e"] {
+            for i in 0..20u32 {
+                let key = format!("{}{:02}x", prefix, i);
+                assert_eq!(db.get(key.as_bytes()).unwrap(), None);
+                if db.may_contain(key.as_bytes()).unwrap() {
+                    false_positives += 1;
+                }
+            }
+        }
+        // Keys that are in the range of a table are ruled out by its bloom
+        // filter
+        assert_eq!(false_positives, 0);
+    }
+
+    #[test]
+    fn test_bloom_filter_skips_tables() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = CountingStorage::new(DirectoryStorage::new(dir.path()).unwrap());
+        let mut db = Database::open(storage).unwrap();
+        for table in 0..3u32 {
+            for i in 0..100u32 {
+                db.put(format!("key{:03}", i * 3 + table).as_bytes(), b"value").unwrap();
+            }
+            db.maintain().unwrap();
+        }
+        assert_eq!(db.sstables.len(), 3);
+
+        // An absent key in the range of every table reads nothing
+        db.storage.take_reads();
+        assert_eq!(db.get(b"key100x").unwrap(), None);
+        assert_eq!(db.storage.take_reads(), 0);
+
+        // Present keys are still found, reading only their table
+        assert_eq!(db.get(b"key100").unwrap(), Some(v(b"value")));
+        assert_eq!(db.get(b"key299").unwrap(), Some(v(b"value")));
+        assert!(db.storage.take_reads() > 0);
+
+        // Filters are read back when reopening
+        drop(db);
+        let storage = CountingStorage::new(DirectoryStorage::new(dir.path()).unwrap());
+        let mut db = Database::open(storage).unwrap();
+        db.storage.take_reads();
+        assert_eq!(db.get(b"key100x").unwrap(), None);
+        assert_eq!(db.storage.take_reads(), 0);
+        assert_eq!(db.get(b"key000").unwrap(), Some(v(b"value")));
+    }
+
+    #[test]
+    fn test_prefix_filter_skips_tables() {
+        fn options() -> DatabaseOptions {
+            DatabaseOptions::builder()
+                .prefix_extractor(|key| match key.iter().position(|&b| b == b'/') {
+                    Some(i) => &key[..=i],
+                    None => &[],
+                })
+                .build()
+        }
 
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = CountingStorage::new(DirectoryStorage::new(dir.path()).unwrap());
+        let mut db = Database::open_with_options(storage, options()).unwrap();
+        db.put(b"a/1", b"one").unwrap();
+        db.put(b"d/1", b"two").unwrap();
+        db.maintain().unwrap();
+        db.put(b"c/1", b"three").unwrap();
+        db.put(b"c/2", b"four").unwrap();
+        db.maintain().unwrap();
+        db.put(b"c/3", b"five").unwrap();
+
+        db.storage.take_reads();
         assert_eq!(
-            db.iter_range(b"a", b"jz").collect::<Vec<_>>(),
-            vec![
-                (v(b"abc"), v(b"222")),
-                (v(b"def"), v(b"777")),
-                (v(b"jkl"), v(b"666")),
-            ],
+            db.iter_prefix(b"c/").collect::<Result<Vec<_>, _>>().unwrap(),
+            vec![(v(b"c/1"), v(b"three")), (v(b"c/2"), v(b"four")), (v(b"c/3"), v(b"five"))],
         );
+        assert!(db.storage.take_reads() > 0);
+
+        // Both tables' key ranges contain the prefix, but their filters rule
+        // it out
+        assert_eq!(db.iter_prefix(b"b/").count(), 0);
+        assert_eq!(db.storage.take_reads(), 0);
+
+        // Not a prefix given by the extractor, the tables are searched
+        assert_eq!(db.iter_prefix(b"b").count(), 0);
+        assert!(db.storage.take_reads() > 0);
+
+        // Filters are read back when reopening
+        drop(db);
+        let storage = CountingStorage::new(DirectoryStorage::new(dir.path()).unwrap());
+        let mut db = Database::open_with_options(storage, options()).unwrap();
+        db.storage.take_reads();
+        assert_eq!(db.iter_prefix(b"b/").count(), 0);
+        assert_eq!(db.storage.take_reads(), 0);
+        assert_eq!(db.iter_prefix(b"a/").count(), 1);
+    }
+
+    #[test]
+    fn test_sstable_info() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        db.put(b"base", b"").unwrap();
+        db.flush().unwrap();
+        // 6 puts with 4-byte keys and 10-byte values, 2 deletions with
+        // 6-byte keys
+        for i in 0..6 {
+            db.put(format!("key{}", i).as_bytes(), b"0123456789").unwrap();
+        }
+        db.delete(b"delete").unwrap();
+        db.delete(b"remove").unwrap();
+        db.flush().unwrap();
+
+        let info = db.sstable_info();
+        assert_eq!(info.len(), 2);
+        assert_eq!(info[0].id, (1, 1));
+        assert_eq!(info[0].entries, 8);
+        assert_eq!(info[0].bytes, std::fs::metadata(dir.path().join("1-1.sst")).unwrap().len());
+        let stats = info[0].stats.unwrap();
+        assert_eq!(stats.tombstones, 2);
+        assert_eq!(stats.average_key_len, 4.5);
+        assert_eq!(stats.average_value_len, 10.0);
+        assert!(info[0].bloom_bytes > 0);
+        assert!(info[0].bloom_false_positive_rate > 0.001 && info[0].bloom_false_positive_rate < 0.02);
+
+        assert_eq!(info[1].stats.unwrap().tombstones, 0);
+        assert_eq!(info[1].stats.unwrap().average_value_len, 0.0);
+
+        // Without a bloom filter, or stats as in tables from older versions
+        let options = DatabaseOptions::builder().bloom_false_positive_rate(1.0).build();
+        let mut db = Database::open_with_options(MemoryStorage::new(), options).unwrap();
+        db.put(b"key", b"value").unwrap();
+        db.flush().unwrap();
+        let info = db.sstable_info();
+        assert_eq!(info[0].bloom_bytes, 0);
+        assert_eq!(info[0].bloom_false_positive_rate, 1.0);
+        db.sstables[0].1.stats = None;
+        assert_eq!(db.sstable_info()[0].stats, None);
+    }
+
+    #[test]
+    fn test_get_required() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        db.put(b"abc", b"111").unwrap();
+        db.maintain().unwrap();
+        db.put(b"def", b"222").unwrap();
+
+        assert_eq!(db.get_required(b"abc").unwrap(), v(b"111"));
+        assert_eq!(db.get_required(b"def").unwrap(), v(b"222"));
+        assert!(matches!(db.get_required(b"ghi"), Err(Error::KeyNotFound)));
+    }
+
+    #[test]
+    fn test_value_len() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = CountingStorage::new(DirectoryStorage::new(dir.path()).unwrap());
+        let mut db = Database::open(storage).unwrap();
+        db.put(b"abc", &[b'x'; 10_000]).unwrap();
+        db.put(b"def", b"111").unwrap();
+        db.maintain().unwrap();
+        db.put(b"ghi", b"22222").unwrap();
+        db.delete(b"def").unwrap();
+
+        db.storage.take_bytes_read();
+        assert_eq!(db.value_len(b"abc").unwrap(), Some(10_000));
+        // Only the offsets, keys and the value length were read
+        assert!(db.storage.take_bytes_read() < 100);
+        assert_eq!(db.value_len(b"ghi").unwrap(), Some(5));
+        assert_eq!(db.value_len(b"def").unwrap(), None);
+        assert_eq!(db.value_len(b"xyz").unwrap(), None);
+
+        // Deleted in an sstable
+        db.maintain().unwrap();
+        assert_eq!(db.value_len(b"def").unwrap(), None);
+        assert_eq!(db.value_len(b"ghi").unwrap(), Some(5));
+    }
+
+    #[test]
+    fn test_estimate_get_cost() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        for table in 0..4 {
+            db.put(format!("a{}", table).as_bytes(), b"").unwrap();
+            db.put(format!("m{}", table).as_bytes(), b"").unwrap();
+            db.maintain().unwrap();
+        }
+        db.put(b"b", b"").unwrap();
+        db.put(b"c", b"").unwrap();
+        db.maintain().unwrap();
+        db.put(b"z", b"").unwrap();
+
+        assert_eq!(db.estimate_get_cost(b"z").unwrap(), GetCost { in_memtable: true, sstables: 0 });
+        assert_eq!(db.estimate_get_cost(b"n").unwrap(), GetCost { in_memtable: false, sstables: 0 });
+        // The other tables whose range holds the key are ruled out by their
+        // bloom filter
+        assert_eq!(db.estimate_get_cost(b"b").unwrap(), GetCost { in_memtable: false, sstables: 1 });
+        assert_eq!(db.estimate_get_cost(b"d").unwrap(), GetCost { in_memtable: false, sstables: 0 });
+
+        db.compact(1).unwrap();
+        assert_eq!(db.estimate_get_cost(b"b").unwrap(), GetCost { in_memtable: false, sstables: 1 });
+        assert_eq!(db.estimate_get_cost(b"d").unwrap(), GetCost { in_memtable: false, sstables: 0 });
+
+        // Without bloom filters, only the key ranges are checked
+        drop(db);
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let options = DatabaseOptions::builder().bloom_false_positive_rate(1.0).build();
+        let mut db = Database::open_with_options(storage, options).unwrap();
+        for table in 0..4 {
+            db.put(format!("a{}", table).as_bytes(), b"").unwrap();
+            db.put(format!("m{}", table).as_bytes(), b"").unwrap();
+            db.maintain().unwrap();
+        }
+        assert_eq!(db.estimate_get_cost(b"d0").unwrap(), GetCost { in_memtable: false, sstables: 4 });
+    }
+
+    #[test]
+    fn test_get_batch_sorted() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = CountingStorage::new(DirectoryStorage::new(dir.path()).unwrap());
+        // Bloom filters would skip the absent keys in both cases
+        let options = DatabaseOptions::builder().bloom_false_positive_rate(1.0).build();
+        let mut db = Database::open_with_options(storage, options).unwrap();
+        for i in 0..1000u32 {
+            db.put(format!("key{:04}", i * 2).as_bytes(), format!("old{}", i * 2).as_bytes()).unwrap();
+        }
+        db.maintain().unwrap();
+        db.put(b"key0504", b"new").unwrap();
+        db.put(b"key0506", b"new").unwrap();
+        db.maintain().unwrap();
+        db.put(b"key0508", b"mem").unwrap();
+
+        // Clustered keys, some of them absent
+        let keys = (500..540u32).map(|i| format!("key{:04}", i).into_bytes()).collect::<Vec<_>>();
+        let keys = keys.iter().map(|k| k as &[u8]).collect::<Vec<_>>();
+        db.storage.take_reads();
+        let results = db.get_batch_sorted(&keys).unwrap();
+        let batch_reads = db.storage.take_reads();
+        let expected = keys.iter().map(|k| db.get(k).unwrap()).collect::<Vec<_>>();
+        let single_reads = db.storage.take_reads();
+        assert_eq!(results, expected);
+        assert_eq!(results[0], Some(v(b"old500")));
+        assert_eq!(results[1], None);
+        assert_eq!(results[4], Some(v(b"new")));
+        assert_eq!(results[8], Some(v(b"mem")));
+        assert!(batch_reads * 2 < single_reads);
+
+        assert!(db.get_batch_sorted(&[b"b", b"a"]).is_err());
+        assert_eq!(db.get_batch_sorted(&[]).unwrap(), Vec::<Option<Vec<u8>>>::new());
+    }
+
+    #[test]
+    fn test_get_parallel() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = CountingStorage::new(DirectoryStorage::new(dir.path()).unwrap());
+        // Without bloom filters, so the missing key, which has the same length
+        // as the others, is looked up in each table
+        let options = DatabaseOptions::builder().bloom_false_positive_rate(1.0).build();
+        let mut db = Database::open_with_options(storage, options).unwrap();
+        // The key ranges of the tables overlap
+        for table in 0..6u32 {
+            for i in 0..10u32 {
+                db.put(format!("key{}-{}", i, table).as_bytes(), b"value").unwrap();
+            }
+            db.maintain().unwrap();
+        }
+        db.put(b"key0-0", b"new").unwrap();
+        db.maintain().unwrap();
+        db.storage.read_delay = Duration::from_millis(10);
+        let mut db = Database::open(db.storage).unwrap();
+        assert_eq!(db.get_parallel(b"key9-0").unwrap(), Some(v(b"value")));
+
+        let start = Instant::now();
+        assert_eq!(db.get(b"key4-9").unwrap(), None);
+        let serial = start.elapsed();
+
+        let start = Instant::now();
+        assert_eq!(db.get_parallel(b"key4-9").unwrap(), None);
+        let parallel = start.elapsed();
+        assert!(parallel * 3 < serial);
+
+        assert_eq!(db.get_parallel(b"key0-0").unwrap(), Some(v(b"new")));
+        assert_eq!(db.get_parallel(b"key9-5").unwrap(), Some(v(b"value")));
+
+        // Tables whose key range doesn't include the key are not read
+        db.storage.take_reads();
+        assert_eq!(db.get_parallel(b"key9-9").unwrap(), None);
+        assert_eq!(db.storage.take_reads(), 0);
+
+        // With more tables than threads, the older ones are read in later
+        // batches
+        db.storage.read_delay = Duration::ZERO;
+        for table in 0..20u32 {
+            db.put(format!("new{:02}", table).as_bytes(), b"value").unwrap();
+            db.maintain().unwrap();
+        }
+        assert_eq!(db.get_parallel(b"key9-5").unwrap(), Some(v(b"value")));
+        assert_eq!(db.get_parallel(b"new00").unwrap(), Some(v(b"value")));
+        assert_eq!(db.get_parallel(b"key4-9").unwrap(), None);
+    }
+
+    #[test]
+    fn test_flush() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        let count_tables = || std::fs::read_dir(dir.path()).unwrap()
+            .filter(|e| e.as_ref().unwrap().file_name().to_str().unwrap().ends_with(".sst"))
+            .count();
+
+        db.put(b"abc", b"111").unwrap();
+        assert_eq!(db.flush().unwrap(), Some((1, 0)));
+        db.put(b"def", b"222").unwrap();
+        assert_eq!(db.flush().unwrap(), Some((1, 1)));
+        assert!(dir.path().join("1-1.sst").exists());
+        assert_eq!(count_tables(), 2);
+
+        assert_eq!(db.flush().unwrap(), None);
+        assert_eq!(count_tables(), 2);
+        assert_eq!(db.get(b"abc").unwrap(), Some(v(b"111")));
+    }
+
+    #[test]
+    fn test_delete_after_flush() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let open = || Database::open(DirectoryStorage::new(dir.path()).unwrap()).unwrap();
+        let mut db = open();
+
+        db.put(b"abc", b"111").unwrap();
+        db.put(b"def", b"222").unwrap();
+        db.flush().unwrap();
+        db.delete(b"abc").unwrap();
+        db.delete(b"xyz").unwrap();
+        assert_eq!(db.flush().unwrap(), Some((1, 1)));
+
+        let check = |db: &mut Database<DirectoryStorage>| {
+            assert_eq!(db.get(b"abc").unwrap(), None);
+            assert_eq!(db.get_parallel(b"abc").unwrap(), None);
+            assert_eq!(db.get_batch_sorted(&[b"abc", b"def"]).unwrap(), vec![None, Some(v(b"222"))]);
+            assert_eq!(db.get(b"def").unwrap(), Some(v(b"222")));
+            assert_eq!(
+                db.iter_range(b"a", b"z").collect::<Vec<_>>(),
+                vec![(v(b"def"), v(b"222"))],
+            );
+            assert_eq!(db.count_range(b"a", b"z").unwrap(), 1);
+            assert_eq!(db.count_range(b"a", b"b").unwrap(), 0);
+        };
+        check(&mut db);
+        drop(db);
+        let mut db = open();
+        check(&mut db);
+
+        // Compacting into the bottom level drops the tombstones
+        db.compact(1).unwrap();
+        check(&mut db);
+        assert_eq!(db.sstables.len(), 1);
+        assert_eq!(db.sstables[0].1.size, 1);
+        assert!(!db.sstables[0].1.tombstones);
+    }
+
+    #[test]
+    fn test_maintain_empty() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        let list = || {
+            let mut files = std::fs::read_dir(dir.path()).unwrap()
+                .map(|e| e.unwrap().file_name().into_string().unwrap())
+                .collect::<Vec<_>>();
+            files.sort();
+            files
+        };
+
+        for _ in 0..5 {
+            db.maintain().unwrap();
+        }
+        assert_eq!(list(), vec!["wal"]);
+        assert_eq!(std::fs::metadata(dir.path().join("wal")).unwrap().len(), 0);
+
+        db.put(b"abc", b"111").unwrap();
+        db.delete(b"abc").unwrap();
+        db.maintain().unwrap();
+        db.maintain().unwrap();
+        assert_eq!(list(), vec!["wal"]);
+        assert_eq!(std::fs::metadata(dir.path().join("wal")).unwrap().len(), 0);
+
+        db.put(b"abc", b"111").unwrap();
+        db.maintain().unwrap();
+        db.maintain().unwrap();
+        assert_eq!(list(), vec!["1-0.sst", "wal"]);
+    }
+
+    #[test]
+    fn test_flush_stats() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        assert_eq!(db.last_flush_stats(), None);
 
+        for round in 0..5u32 {
+            for i in 0..10u32 {
+                db.put(format!("key{}", i).as_bytes(), format!("{}", round).as_bytes()).unwrap();
+            }
+        }
+        db.delete(b"key0").unwrap();
+        db.put(b"key0", b"new").unwrap();
+        let bytes = db.estimated_flush_bytes();
+        db.maintain().unwrap();
         assert_eq!(
-            db.iter_range(b"def", b"z").collect::<Vec<_>>(),
-            vec![
-                (v(b"def"), v(b"777")),
-                (v(b"jkl"), v(b"666")),
-                (v(b"mno"), v(b"333")),
-            ],
+            db.last_flush_stats(),
+            Some(FlushStats {
+                entries: 10,
+                bytes,
+                overwrites: 40,
+            }),
         );
+
+        // Counts are per flush
+        db.put(b"key0", b"1").unwrap();
+        db.put(b"key0", b"2").unwrap();
+        db.maintain().unwrap();
+        assert_eq!(db.last_flush_stats().unwrap().entries, 1);
+        assert_eq!(db.last_flush_stats().unwrap().overwrites, 1);
+    }
+
+    #[test]
+    fn test_estimated_flush_bytes() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        for i in 0..100u32 {
+            db.put(format!("key{}", i).as_bytes(), &vec![b'x'; i as usize]).unwrap();
+        }
+        db.delete(b"key10").unwrap();
+
+        let estimate = db.estimated_flush_bytes();
+        db.maintain().unwrap();
+        let actual = std::fs::metadata(dir.path().join("1-0.sst")).unwrap().len();
+        assert_eq!(estimate, actual);
+        assert_eq!(db.estimated_flush_bytes(), crate::SSTABLE_HEADER_LEN + crate::SSTABLE_STATS_LEN);
+    }
+
+    #[test]
+    fn test_shrink_memtable() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let storage = DirectoryStorage::new(dir.path()).unwrap();
+        let mut db = Database::open(storage).unwrap();
+        for i in 0..1000u32 {
+            db.put(&i.to_be_bytes(), b"value").unwrap();
+        }
+        for i in 10..1000u32 {
+            db.delete(&i.to_be_bytes()).unwrap();
+        }
+        db.shrink_memtable();
+        assert!(db.mem_table.entries.capacity() < 1000);
+        assert_eq!(db.get(&5u32.to_be_bytes()).unwrap(), Some(v(b"value")));
+        assert_eq!(db.get(&500u32.to_be_bytes()).unwrap(), None);
+
+        // With an sstable, the tombstones are kept to hide its entries
+        db.put(b"abc", b"111").unwrap();
+        db.maintain().unwrap();
+        db.delete(b"abc").unwrap();
+        db.shrink_memtable();
+        assert_eq!(db.mem_table.entries.len(), 1);
+        assert_eq!(db.get(b"abc").unwrap(), None);
     }
 }
@@ -1,6 +1,18 @@
 #[derive(Default)]
 pub(crate) struct MemTable {
-    pub(crate) entries: Vec<(Vec<u8>, Vec<u8>)>,
+    // Deleted keys are kept with no value, so they hide older sstables
+    pub(crate) entries: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    // Total length of the keys and values
+    size: usize,
+    // Number of puts that replaced the value of an existing key
+    pub(crate) overwrites: u64,
+    // Whether deleted keys are kept as tombstones, which is only needed if
+    // there are sstables for them to hide
+    pub(crate) keep_tombstones: bool,
+}
+
+fn value_len(value: &Option<Vec<u8>>) -> usize {
+    value.as_ref().map_or(0, |v| v.len())
 }
 
 impl MemTable {
@@ -8,39 +20,108 @@ impl MemTable {
         match self.entries.binary_search_by_key(&key, |(key, _value)| key) {
             Ok(index) => {
                 // There is an element with that key, update its value
-                self.entries[index].1 = value;
+                self.size = self.size - value_len(&self.entries[index].1) + value.len();
+                if self.entries[index].1.is_some() {
+                    self.overwrites += 1;
+                }
+                self.entries[index].1 = Some(value);
             }
             Err(index) => {
                 // There is no element with that key, insert
-                self.entries.insert(index, (key.into(), value));
+                self.size += key.len() + value.len();
+                self.entries.insert(index, (key.into(), Some(value)));
             }
         }
     }
 
+    /// Record the deletion of a key, returning whether it had a value here.
     pub(crate) fn delete(&mut self, key: &[u8]) -> bool {
         match self.entries.binary_search_by_key(&key, |(key, _value)| key) {
             Ok(index) => {
-                // There is an element with that key, update its value
-                self.entries.remove(index);
-                true
+                if self.keep_tombstones {
+                    // There is an element with that key, replace it with a tombstone
+                    let value = self.entries[index].1.take();
+                    self.size -= value_len(&value);
+                    value.is_some()
+                } else {
+                    // There is an element with that key, remove it
+                    let (key, value) = self.entries.remove(index);
+                    self.size -= key.len() + value_len(&value);
+                    value.is_some()
+                }
+            }
+            Err(index) => {
+                if self.keep_tombstones {
+                    self.size += key.len();
+                    self.entries.insert(index, (key.into(), None));
+                }
+                false
             }
-            Err(_) => false,
         }
     }
 
-    pub(crate) fn get(&self, key: &[u8]) -> Option<&[u8]> {
+    /// Get the value of a key, `Some(None)` if it was deleted.
+    pub(crate) fn get(&self, key: &[u8]) -> Option<Option<&[u8]>> {
         match self.entries.binary_search_by_key(&key, |(key, _value)| key) {
-            Ok(index) => Some(&self.entries[index].1),
+            Ok(index) => Some(self.entries[index].1.as_deref()),
             Err(_) => None,
         }
     }
 
+    /// Remove a key entirely, as if it had never been written.
+    pub(crate) fn remove(&mut self, key: &[u8]) {
+        if let Ok(index) = self.entries.binary_search_by_key(&key, |(key, _value)| key) {
+            let (key, value) = self.entries.remove(index);
+            self.size -= key.len() + value_len(&value);
+        }
+    }
+
+    /// Remove the keys starting with `prefix` entirely, with their
+    /// tombstones.
+    pub(crate) fn remove_prefix(&mut self, prefix: &[u8]) {
+        let start = self.entries.partition_point(|(key, _value)| key as &[u8] < prefix);
+        let len = self.entries[start..].iter().take_while(|(key, _value)| key.starts_with(prefix)).count();
+        for (key, value) in self.entries.drain(start..start + len) {
+            self.size -= key.len() + value_len(&value);
+        }
+    }
+
+    /// Remove the tombstones, which are only needed to hide older entries.
+    pub(crate) fn drop_tombstones(&mut self) {
+        let mut size = self.size;
+        self.entries.retain(|(key, value)| {
+            if value.is_none() {
+                size -= key.len();
+            }
+            value.is_some()
+        });
+        self.size = size;
+    }
+
+    /// Total length of the keys and values.
+    pub(crate) fn size(&self) -> usize {
+        self.size
+    }
+
+    pub(crate) fn shrink_to_fit(&mut self) {
+        self.entries.shrink_to_fit();
+    }
+
     pub(crate) fn iter_range<'a>(&'a self, key_start: &'a [u8], key_end: &'a [u8]) -> MemTableRangeIterator<'a> {
         let index = self.entries.partition_point(|(key, _value)| key as &[u8] < key_start);
         MemTableRangeIterator {
             mem_table: self,
             next_index: index,
-            key_end,
+            key_end: Some(key_end),
+        }
+    }
+
+    pub(crate) fn iter_from<'a>(&'a self, key_start: &'a [u8]) -> MemTableRangeIterator<'a> {
+        let index = self.entries.partition_point(|(key, _value)| key as &[u8] < key_start);
+        MemTableRangeIterator {
+            mem_table: self,
+            next_index: index,
+            key_end: None,
         }
     }
 }
@@ -48,18 +129,18 @@ impl MemTable {
 pub(crate) struct MemTableRangeIterator<'a> {
     mem_table: &'a MemTable,
     next_index: usize,
-    key_end: &'a [u8],
+    key_end: Option<&'a [u8]>,
 }
 
 impl<'a> Iterator for MemTableRangeIterator<'a> {
-    type Item = &'a (Vec<u8>, Vec<u8>);
+    type Item = &'a (Vec<u8>, Option<Vec<u8>>);
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.next_index >= self.mem_table.entries.len() {
             None
         } else {
             let entry = &self.mem_table.entries[self.next_index];
-            if &entry.0 as &[u8] < self.key_end {
+            if self.key_end.is_none_or(|key_end| &entry.0 as &[u8] < key_end) {
                 self.next_index += 1;
                 Some(entry)
             } else {
@@ -80,7 +161,10 @@ mod tests {
 
     #[test]
     fn test_memtable() {
-        let mut mem_table: MemTable = Default::default();
+        let mut mem_table = MemTable {
+            keep_tombstones: true,
+            ..Default::default()
+        };
         assert_eq!(mem_table.entries, vec![]);
         mem_table.put(b"ghi", v(b"111"));
         mem_table.put(b"abc", v(b"222"));
@@ -90,36 +174,81 @@ mod tests {
         mem_table.put(b"jkl", v(b"666"));
         mem_table.put(b"def", v(b"777"));
         mem_table.delete(b"ghi");
+        mem_table.delete(b"pqr");
         assert_eq!(mem_table.entries, vec![
-            (v(b"abc"), v(b"222")),
-            (v(b"def"), v(b"777")),
-            (v(b"jkl"), v(b"666")),
-            (v(b"mno"), v(b"333")),
+            (v(b"abc"), Some(v(b"222"))),
+            (v(b"def"), Some(v(b"777"))),
+            (v(b"ghi"), None),
+            (v(b"jkl"), Some(v(b"666"))),
+            (v(b"mno"), Some(v(b"333"))),
+            (v(b"pqr"), None),
         ]);
+        assert_eq!(mem_table.get(b"ghi"), Some(None));
+        assert_eq!(mem_table.get(b"jkl"), Some(Some(b"666" as &[u8])));
+        assert_eq!(mem_table.get(b"xyz"), None);
 
         assert_eq!(
             mem_table.iter_range(b"def", b"jkl").collect::<Vec<_>>(),
             vec![
-                &(v(b"def"), v(b"777")),
+                &(v(b"def"), Some(v(b"777"))),
+                &(v(b"ghi"), None),
             ],
         );
 
         assert_eq!(
             mem_table.iter_range(b"a", b"jz").collect::<Vec<_>>(),
             vec![
-                &(v(b"abc"), v(b"222")),
-                &(v(b"def"), v(b"777")),
-                &(v(b"jkl"), v(b"666")),
+                &(v(b"abc"), Some(v(b"222"))),
+                &(v(b"def"), Some(v(b"777"))),
+                &(v(b"ghi"), None),
+                &(v(b"jkl"), Some(v(b"666"))),
             ],
         );
 
         assert_eq!(
             mem_table.iter_range(b"def", b"z").collect::<Vec<_>>(),
             vec![
-                &(v(b"def"), v(b"777")),
-                &(v(b"jkl"), v(b"666")),
-                &(v(b"mno"), v(b"333")),
+                &(v(b"def"), Some(v(b"777"))),
+                &(v(b"ghi"), None),
+                &(v(b"jkl"), Some(v(b"666"))),
+                &(v(b"mno"), Some(v(b"333"))),
+                &(v(b"pqr"), None),
             ],
         );
     }
+
+    #[test]
+    fn test_shrink_to_fit() {
+        let mut mem_table: MemTable = Default::default();
+        for i in 0..1000u32 {
+            mem_table.put(&i.to_be_bytes(), v(b"value"));
+        }
+        for i in 10..1000u32 {
+            mem_table.delete(&i.to_be_bytes());
+        }
+        assert!(mem_table.entries.capacity() >= 1000);
+        mem_table.shrink_to_fit();
+        assert!(mem_table.entries.capacity() < 1000);
+        assert_eq!(mem_table.entries.len(), 10);
+        assert_eq!(mem_table.get(&5u32.to_be_bytes()), Some(Some(b"value" as &[u8])));
+    }
+
+    #[test]
+    fn test_size() {
+        let mut mem_table = MemTable {
+            keep_tombstones: true,
+            ..Default::default()
+        };
+        mem_table.put(b"abc", v(b"12345"));
+        mem_table.put(b"de", v(b"1"));
+        assert_eq!(mem_table.size(), 11);
+        mem_table.put(b"abc", v(b"12"));
+        assert_eq!(mem_table.size(), 8);
+        mem_table.delete(b"de");
+        mem_table.delete(b"fgh");
+        // Tombstones keep their key
+        assert_eq!(mem_table.size(), 10);
+        mem_table.drop_tombstones();
+        assert_eq!(mem_table.size(), 5);
+    }
 }
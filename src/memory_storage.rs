@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::sync::{Arc, Mutex};
+use crate::{Append, ReadAt, Storage};
+
+type Files = Arc<Mutex<HashMap<String, Arc<Vec<u8>>>>>;
+
+/// Storage keeping everything in memory, for tests.
+///
+/// Clones share the same content, so a database can be reopened on a clone
+/// of the storage it was using. A reader sees the content at the time it was
+/// opened.
+#[derive(Clone, Default)]
+pub struct MemoryStorage {
+    files: Files,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        Default::default()
+    }
+}
+
+pub struct MemoryReader(pub(crate) Arc<Vec<u8>>);
+
+impl ReadAt for MemoryReader {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<(), IoError> {
+        let start = offset as usize;
+        match self.0.get(start..start + buf.len()) {
+            Some(data) => {
+                buf.copy_from_slice(data);
+                Ok(())
+            }
+            None => Err(IoError::new(IoErrorKind::UnexpectedEof, "Read past end")),
+        }
+    }
+
+    fn size(&self) -> Result<u64, IoError> {
+        Ok(self.0.len() as u64)
+    }
+}
+
+pub struct MemoryAppender {
+    files: Files,
+    key: String,
+}
+
+impl Append for MemoryAppender {
+    fn append(&mut self, buffer: &[u8]) -> Result<(), IoError> {
+        let mut files = self.files.lock().unwrap();
+        // Copies the content if a reader still holds it
+        Arc::make_mut(files.entry(self.key.clone()).or_default()).extend_from_slice(buffer);
+        Ok(())
+    }
+
+    fn truncate(&mut self) -> Result<(), IoError> {
+        self.files.lock().unwrap().insert(self.key.clone(), Default::default());
+        Ok(())
+    }
+}
+
+impl Storage for MemoryStorage {
+    type Reader = MemoryReader;
+    type Appender = MemoryAppender;
+
+    fn read(&self, key: &str) -> Result<MemoryReader, IoError> {
+        match self.files.lock().unwrap().get(key) {
+            Some(content) => Ok(MemoryReader(content.clone())),
+            None => Err(IoError::new(IoErrorKind::NotFound, "Key doesn't exist")),
+        }
+    }
+
+    fn write(&self, key: &str, value: &[u8]) -> Result<(), IoError> {
+        self.files.lock().unwrap().insert(key.to_owned(), Arc::new(value.to_vec()));
+        Ok(())
+    }
+
+    fn append(&self, key: &str) -> Result<MemoryAppender, IoError> {
+        self.files.lock().unwrap().entry(key.to_owned()).or_default();
+        Ok(MemoryAppender {
+            files: self.files.clone(),
+            key: key.to_owned(),
+        })
+    }
+
+    fn delete(&self, key: &str) -> Result<(), IoError> {
+        self.files.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<String>, IoError> {
+        let mut keys: Vec<String> = self.files.lock().unwrap().keys().cloned().collect();
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), IoError> {
+        let mut files = self.files.lock().unwrap();
+        match files.remove(from) {
+            Some(content) => {
+                files.insert(to.to_owned(), content);
+                Ok(())
+            }
+            None => Err(IoError::new(IoErrorKind::NotFound, "Key doesn't exist")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Database, Storage};
+    use super::MemoryStorage;
+
+    #[test]
+    fn test_memory_storage() {
+        let storage = MemoryStorage::new();
+        let mut db = Database::open(storage.clone()).unwrap();
+        db.put(b"abc", b"111").unwrap();
+        db.put(b"def", b"222").unwrap();
+        db.maintain().unwrap();
+        db.put(b"abc", b"333").unwrap();
+        db.delete(b"def").unwrap();
+        db.put(b"ghi", b"444").unwrap();
+        assert_eq!(storage.list().unwrap(), vec!["1-0.sst", "wal"]);
+
+        // Recover from the WAL
+        drop(db);
+        let mut db = Database::open(storage.clone()).unwrap();
+        assert_eq!(db.open_report().records_replayed, 3);
+        assert_eq!(db.get(b"abc").unwrap(), Some(b"333".to_vec()));
+        assert_eq!(db.get(b"def").unwrap(), None);
+        assert_eq!(db.get(b"ghi").unwrap(), Some(b"444".to_vec()));
+
+        db.maintain().unwrap();
+        db.compact(1).unwrap();
+        drop(db);
+        let mut db = Database::open(storage.clone()).unwrap();
+        assert_eq!(
+            db.iter_range(b"a", b"z").collect::<Vec<_>>(),
+            vec![(b"abc".to_vec(), b"333".to_vec()), (b"ghi".to_vec(), b"444".to_vec())],
+        );
+        assert_eq!(storage.list().unwrap(), vec!["2-0.sst", "wal"]);
+    }
+}
@@ -0,0 +1,179 @@
+use std::io::Error as IoError;
+
+/// A key and its value, or `None` for a deletion.
+pub(crate) type Entry = (Vec<u8>, Option<Vec<u8>>);
+
+/// Merges several sorted runs of entries into a single sorted run.
+///
+/// Runs must be given newest first: when the same key appears in multiple
+/// runs, the entry from the run with the lowest index is the most recent.
+///
+/// If `dedup` is set, only the most recent entry for each key is emitted,
+/// otherwise every version is emitted, newest first. If `drop_tombstones` is
+/// set, deletions are not emitted (this is what reads and compaction into the
+/// bottom level want).
+pub(crate) fn merge_sorted_runs<I>(runs: Vec<I>, dedup: bool, drop_tombstones: bool) -> MergedRuns<I>
+where
+    I: Iterator<Item = Result<Entry, IoError>>,
+{
+    MergedRuns {
+        heads: Vec::with_capacity(runs.len()),
+        runs,
+        dedup,
+        drop_tombstones,
+    }
+}
+
+pub(crate) struct MergedRuns<I: Iterator<Item = Result<Entry, IoError>>> {
+    runs: Vec<I>,
+    // Next entry of each run, filled on the first call to next()
+    heads: Vec<Option<Entry>>,
+    dedup: bool,
+    drop_tombstones: bool,
+}
+
+impl<I: Iterator<Item = Result<Entry, IoError>>> MergedRuns<I> {
+    fn advance(&mut self, run: usize) -> Result<(), IoError> {
+        self.heads[run] = self.runs[run].next().transpose()?;
+        Ok(())
+    }
+
+    /// Returns the next entry, and the index of the run it came from.
+    pub(crate) fn next_with_run(&mut self) -> Option<Result<(usize, Entry), IoError>> {
+        self.next_entry().transpose()
+    }
+
+    fn next_entry(&mut self) -> Result<Option<(usize, Entry)>, IoError> {
+        if self.heads.len() < self.runs.len() {
+            for run in &mut self.runs {
+                self.heads.push(run.next().transpose()?);
+            }
+        }
+
+        loop {
+            // Find the run whose next entry has the smallest key, favoring the
+            // most recent run on ties
+            let mut best: Option<usize> = None;
+            for (i, head) in self.heads.iter().enumerate() {
+                if let Some((key, _)) = head {
+                    match best {
+                        Some(b) if self.heads[b].as_ref().unwrap().0 <= *key => {}
+                        _ => best = Some(i),
+                    }
+                }
+            }
+            let Some(best) = best else {
+                return Ok(None);
+            };
+
+            let entry = self.heads[best].take().unwrap();
+            self.advance(best)?;
+
+            if self.dedup {
+                // Skip older versions of the same key
+                for other in best + 1..self.runs.len() {
+                    while matches!(&self.heads[other], Some((key, _)) if *key == entry.0) {
+                        self.advance(other)?;
+                    }
+                }
+            }
+
+            if self.drop_tombstones && entry.1.is_none() {
+                continue;
+            }
+            return Ok(Some((best, entry)));
+        }
+    }
+}
+
+impl<I: Iterator<Item = Result<Entry, IoError>>> Iterator for MergedRuns<I> {
+    type Item = Result<Entry, IoError>;
+
+    fn next(&mut self) -> Option<Result<Entry, IoError>> {
+        Some(self.next_entry().transpose()?.map(|(_, entry)| entry))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Error as IoError;
+
+    use super::{merge_sorted_runs, Entry};
+
+    fn run(entries: &[(&[u8], Option<&[u8]>)]) -> std::vec::IntoIter<Result<Entry, IoError>> {
+        entries
+            .iter()
+            .map(|&(k, v)| Ok((k.into(), v.map(|v| v.into()))))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    fn e(k: &[u8], v: Option<&[u8]>) -> Entry {
+        (k.into(), v.map(|v| v.into()))
+    }
+
+    #[test]
+    fn test_merge() {
+        let runs = || vec![
+            run(&[(b"b", Some(b"new")), (b"d", None), (b"f", Some(b"6"))]),
+            run(&[(b"a", Some(b"1")), (b"b", None), (b"d", Some(b"old")), (b"e", None)]),
+            run(&[(b"b", Some(b"oldest")), (b"c", Some(b"3")), (b"d", Some(b"oldest")), (b"e", Some(b"5"))]),
+        ];
+
+        // Read path
+        assert_eq!(
+            merge_sorted_runs(runs(), true, true).collect::<Result<Vec<_>, _>>().unwrap(),
+            vec![
+                e(b"a", Some(b"1")),
+                e(b"b", Some(b"new")),
+                e(b"c", Some(b"3")),
+                e(b"f", Some(b"6")),
+            ],
+        );
+
+        // Compaction into a level that is not the bottom one
+        assert_eq!(
+            merge_sorted_runs(runs(), true, false).collect::<Result<Vec<_>, _>>().unwrap(),
+            vec![
+                e(b"a", Some(b"1")),
+                e(b"b", Some(b"new")),
+                e(b"c", Some(b"3")),
+                e(b"d", None),
+                e(b"e", None),
+                e(b"f", Some(b"6")),
+            ],
+        );
+
+        // All versions
+        assert_eq!(
+            merge_sorted_runs(runs(), false, false).collect::<Result<Vec<_>, _>>().unwrap(),
+            vec![
+                e(b"a", Some(b"1")),
+                e(b"b", Some(b"new")),
+                e(b"b", None),
+                e(b"b", Some(b"oldest")),
+                e(b"c", Some(b"3")),
+                e(b"d", None),
+                e(b"d", Some(b"old")),
+                e(b"d", Some(b"oldest")),
+                e(b"e", None),
+                e(b"e", Some(b"5")),
+                e(b"f", Some(b"6")),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_merge_empty() {
+        assert_eq!(
+            merge_sorted_runs(vec![run(&[]), run(&[(b"a", None)]), run(&[])], true, true)
+                .collect::<Result<Vec<_>, _>>().unwrap(),
+            vec![],
+        );
+        assert_eq!(
+            merge_sorted_runs(Vec::<std::vec::IntoIter<_>>::new(), true, true)
+                .collect::<Result<Vec<_>, _>>().unwrap(),
+            vec![],
+        );
+    }
+}
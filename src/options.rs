@@ -0,0 +1,245 @@
+/// Progress of the WAL replay, reported while opening a database.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReplayProgress {
+    pub bytes_read: u64,
+    pub total_bytes: u64,
+}
+
+/// What to do when an sstable entry is cut short, for example by a crash
+/// during a write that was not caught at recovery.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CorruptionPolicy {
+    /// Fail the lookup with an error naming the sstable and the key.
+    #[default]
+    Error,
+    /// Log a warning and treat the entry as absent, reading older tables.
+    Skip,
+}
+
+/// Maps a key to the prefix indexed by the prefix bloom filters, see
+/// [`DatabaseOptions::prefix_extractor`].
+pub type PrefixExtractor = dyn Fn(&[u8]) -> &[u8] + Send + Sync;
+
+/// Options for opening a [`Database`](crate::Database).
+///
+/// ```
+/// # use lsmtree::{Database, DatabaseOptions, DirectoryStorage};
+/// # let dir = tempdir::TempDir::new("lsmtree-doc").unwrap();
+/// let options = DatabaseOptions::builder()
+///     .replay_progress(|p| {
+///         println!("Replayed {}/{} bytes", p.bytes_read, p.total_bytes);
+///         true
+///     })
+///     .build();
+/// let storage = DirectoryStorage::new(dir.path()).unwrap();
+/// let db = Database::open_with_options(storage, options).unwrap();
+/// ```
+#[derive(Default)]
+pub struct DatabaseOptions {
+    /// Called periodically while replaying the WAL, and once at the end.
+    ///
+    /// Returning `false` aborts the replay, and `open` fails with
+    /// [`Error::ReplayAborted`](crate::Error::ReplayAborted).
+    pub replay_progress: Option<Box<dyn FnMut(ReplayProgress) -> bool>>,
+
+    /// Maximum number of sstable levels, or `None` for no limit.
+    ///
+    /// Compacting the last level merges it into itself instead of creating a
+    /// new level, bounding read amplification at the cost of rewriting it.
+    pub max_levels: Option<u32>,
+
+    /// Size of the keys and values in the memtable above which it is written
+    /// to an sstable, or `None` for the default of 4 MiB.
+    ///
+    /// This is checked after each write, so that a long-running writer
+    /// doesn't keep everything in memory, and while replaying the WAL,
+    /// bounding the memory used when opening a database with a large WAL.
+    /// Use `u64::MAX` to only flush explicitly.
+    pub max_memtable_bytes: Option<u64>,
+
+    /// Size of the WAL above which
+    /// [`Database::flush_if_needed`](crate::Database::flush_if_needed)
+    /// flushes the memtable, or `None` for no limit.
+    ///
+    /// Unlike the memtable size, this grows with every write, including
+    /// overwrites and deletes, so it bounds the time to replay the WAL.
+    pub max_wal_bytes: Option<u64>,
+
+    /// Check that the entries are sorted before writing an sstable, failing
+    /// the write otherwise.
+    ///
+    /// Debug builds always assert this, whatever the setting.
+    pub check_sstable_order: bool,
+
+    /// Pad the entries of new sstables so that each starts on a multiple of
+    /// this many bytes, or `None` to pack them.
+    ///
+    /// This can speed up reads on storage that favors aligned accesses, at
+    /// the cost of space.
+    pub sstable_alignment: Option<u32>,
+
+    /// How lookups handle truncated sstable entries.
+    pub corruption_policy: CorruptionPolicy,
+
+    /// Names of the sstables to load, or `None` to load all of them.
+    ///
+    /// Other sstables in storage are ignored, but not deleted. This is useful
+    /// when restoring a backup, to only trust a known set of files.
+    pub sstable_allowlist: Option<Vec<String>>,
+
+    /// Number of bytes to read at once from each sstable when iterating on
+    /// entries, for scans and compaction, or `None` to read entries one by
+    /// one.
+    ///
+    /// Reading ahead issues fewer reads for long scans, at the cost of a
+    /// buffer per sstable.
+    pub scan_read_ahead: Option<u32>,
+
+    /// False-positive rate of the bloom filters written in new sstables, or
+    /// `None` for the default of 1%.
+    ///
+    /// Lookups skip the tables whose filter rules out the key. Lower rates
+    /// skip more tables but take more space. A rate of 1 writes no filter.
+    pub bloom_false_positive_rate: Option<f64>,
+
+    /// Function returning the prefix of a key, to write bloom filters of the
+    /// key prefixes in new sstables, or `None` for no prefix filters.
+    ///
+    /// [`Database::iter_prefix`](crate::Database::iter_prefix) skips the
+    /// tables whose filter rules out the prefix. It only uses the filters for
+    /// a prefix `p` that the extractor maps to itself, and every key starting
+    /// with such a `p` must map to `p` too. For example, the bytes up to the
+    /// first `/` of the key, or an empty slice if there is none. Keys mapped
+    /// to an empty slice are not in the filters.
+    pub prefix_extractor: Option<Box<PrefixExtractor>>,
+
+    /// Check the replayed WAL against the sstables when opening.
+    ///
+    /// If an sstable was written during the part of the WAL that is
+    /// replayed, for example a flush whose WAL truncation was interrupted,
+    /// the replayed entries that the sstables already hold are dropped from
+    /// the memtable instead of being written out again.
+    ///
+    /// This only removes duplicates: a replayed entry is dropped if it is
+    /// equal to what the sstables return for its key. An entry that differs
+    /// is kept, since it was written after the sstable, or was never in it,
+    /// as with a compaction that ran while the entry was in the memtable.
+    /// Letting the sstable win would lose that write.
+    pub strict_replay: bool,
+
+    /// Sync the WAL to durable storage after each write, with
+    /// [`Append::sync`](crate::Append::sync).
+    ///
+    /// New sstables are synced as well before the WAL entries they hold are
+    /// truncated. Otherwise a write is only as durable as the storage makes
+    /// appends, and the most recent writes can be lost if the machine
+    /// crashes.
+    pub sync_on_write: bool,
+}
+
+impl DatabaseOptions {
+    pub fn builder() -> DatabaseOptionsBuilder {
+        Default::default()
+    }
+}
+
+/// Builder for [`DatabaseOptions`], starting from the defaults.
+#[derive(Default)]
+pub struct DatabaseOptionsBuilder {
+    options: DatabaseOptions,
+}
+
+impl DatabaseOptionsBuilder {
+    pub fn replay_progress<F: FnMut(ReplayProgress) -> bool + 'static>(mut self, callback: F) -> DatabaseOptionsBuilder {
+        self.options.replay_progress = Some(Box::new(callback));
+        self
+    }
+
+    pub fn max_levels(mut self, max_levels: u32) -> DatabaseOptionsBuilder {
+        self.options.max_levels = Some(max_levels);
+        self
+    }
+
+    pub fn max_memtable_bytes(mut self, max_memtable_bytes: u64) -> DatabaseOptionsBuilder {
+        self.options.max_memtable_bytes = Some(max_memtable_bytes);
+        self
+    }
+
+    pub fn max_wal_bytes(mut self, max_wal_bytes: u64) -> DatabaseOptionsBuilder {
+        self.options.max_wal_bytes = Some(max_wal_bytes);
+        self
+    }
+
+    pub fn check_sstable_order(mut self, check: bool) -> DatabaseOptionsBuilder {
+        self.options.check_sstable_order = check;
+        self
+    }
+
+    pub fn sstable_alignment(mut self, alignment: u32) -> DatabaseOptionsBuilder {
+        self.options.sstable_alignment = Some(alignment);
+        self
+    }
+
+    pub fn corruption_policy(mut self, policy: CorruptionPolicy) -> DatabaseOptionsBuilder {
+        self.options.corruption_policy = policy;
+        self
+    }
+
+    pub fn sstable_allowlist<I: IntoIterator<Item = String>>(mut self, names: I) -> DatabaseOptionsBuilder {
+        self.options.sstable_allowlist = Some(names.into_iter().collect());
+        self
+    }
+
+    pub fn scan_read_ahead(mut self, bytes: u32) -> DatabaseOptionsBuilder {
+        self.options.scan_read_ahead = Some(bytes);
+        self
+    }
+
+    pub fn bloom_false_positive_rate(mut self, rate: f64) -> DatabaseOptionsBuilder {
+        self.options.bloom_false_positive_rate = Some(rate);
+        self
+    }
+
+    pub fn prefix_extractor<F: Fn(&[u8]) -> &[u8] + Send + Sync + 'static>(mut self, extractor: F) -> DatabaseOptionsBuilder {
+        self.options.prefix_extractor = Some(Box::new(extractor));
+        self
+    }
+
+    pub fn strict_replay(mut self, strict: bool) -> DatabaseOptionsBuilder {
+        self.options.strict_replay = strict;
+        self
+    }
+
+    pub fn sync_on_write(mut self, sync: bool) -> DatabaseOptionsBuilder {
+        self.options.sync_on_write = sync;
+        self
+    }
+
+    pub fn build(self) -> DatabaseOptions {
+        self.options
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DatabaseOptions;
+
+    #[test]
+    fn test_builder_defaults() {
+        let built = DatabaseOptions::builder().build();
+        let default: DatabaseOptions = Default::default();
+        assert_eq!(built.replay_progress.is_some(), default.replay_progress.is_some());
+        assert_eq!(built.max_levels, default.max_levels);
+        assert_eq!(built.max_memtable_bytes, default.max_memtable_bytes);
+        assert_eq!(built.max_wal_bytes, default.max_wal_bytes);
+        assert_eq!(built.check_sstable_order, default.check_sstable_order);
+        assert_eq!(built.sstable_alignment, default.sstable_alignment);
+        assert_eq!(built.corruption_policy, default.corruption_policy);
+        assert_eq!(built.sstable_allowlist, default.sstable_allowlist);
+        assert_eq!(built.scan_read_ahead, default.scan_read_ahead);
+        assert_eq!(built.bloom_false_positive_rate, default.bloom_false_positive_rate);
+        assert_eq!(built.prefix_extractor.is_some(), default.prefix_extractor.is_some());
+        assert_eq!(built.strict_replay, default.strict_replay);
+        assert_eq!(built.sync_on_write, default.sync_on_write);
+    }
+}
@@ -0,0 +1,714 @@
+use byteorder::{BigEndian, WriteBytesExt};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use crate::memory_storage::MemoryReader;
+use crate::{crc32, read_u32, read_u64, Append, BufferedAppender, MemoryStorage, ReadAt, Storage};
+
+const MAGIC: &[u8; 8] = b"lsmtree2";
+
+// Record header: content length (u64) and checksum (u32), followed by the
+// content
+const RECORD_HEADER_LEN: u64 = 12;
+
+// Content header: operation (u8) then key length (u32), followed by the key
+// and the data
+const CONTENT_HEADER_LEN: u64 = 5;
+
+const OP_WRITE: u8 = 0;
+const OP_APPEND: u8 = 1;
+const OP_DELETE: u8 = 2;
+const OP_RENAME: u8 = 3;
+const OP_TRUNCATE: u8 = 4;
+
+// Offset and length of data in the file
+type Region = (u64, u64);
+
+// Regions holding each key's content, in order
+type Directory = HashMap<String, Vec<Region>>;
+
+// Operation, key, data region, and the data if it is a rename
+type Record = (u8, String, Region, Vec<u8>);
+
+/// Use of the space in a storage file, see
+/// [`SingleFileStorage::space_report`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SpaceReport {
+    /// Size of the file.
+    pub total_bytes: u64,
+    /// Size of the current content of the keys.
+    pub used_bytes: u64,
+    /// The rest of the file: deleted and overwritten content, and the
+    /// headers of the records.
+    pub free_bytes: u64,
+}
+
+/// Storage keeping the WAL and all the sstables in a single file.
+///
+/// This is useful where creating many small files is expensive.
+///
+/// The file starts with the magic `lsmtree2`, followed by a log of records.
+/// Like WAL records, each is framed by the length of its content (u64) and
+/// the CRC-32 of the content (u32). The content is an operation (u8), the
+/// length of a key (u32) and the key, then some data:
+///
+/// * write (0): replace the content of the key with the data
+/// * append (1): add the data at the end of the key's content
+/// * delete (2): remove the key, with no data
+/// * rename (3): replace the key named in the data with this key
+/// * truncate (4): empty the key's content, with no data
+///
+/// Records are only ever added at the end of the file. Opening the storage
+/// replays them into a directory mapping each key to the regions of the file
+/// holding its content. This reads the whole file, to check each record
+/// against its checksum: a record cut short or only partly written by a
+/// crash is discarded, along with the rest of the file. The space of deleted
+/// and overwritten content is only reclaimed by
+/// [`compact_file`](SingleFileStorage::compact_file).
+///
+/// A reader sees the content of the key at the time it was opened.
+pub struct SingleFileStorage {
+    shared: Arc<Shared>,
+    append_buffer_size: usize,
+}
+
+struct Shared {
+    path: PathBuf,
+    state: Mutex<State>,
+}
+
+struct State {
+    // Replaced when the file is compacted, readers keep the old one
+    file: Arc<File>,
+    // End of the last complete record, where the next one is written
+    len: u64,
+    directory: Directory,
+}
+
+// Replay the records of a storage file, after checking its magic. Returns
+// the end of the last complete record and the directory
+fn load<R: ReadAt>(file: R, file_size: u64) -> Result<(u64, Directory), IoError> {
+    let mut magic = [0u8; 8];
+    file.read_exact_at(&mut magic, 0)
+        .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Not a storage file"))?;
+    if &magic != MAGIC {
+        return Err(IoError::new(IoErrorKind::InvalidData, "Not a storage file"));
+    }
+
+    let mut len = MAGIC.len() as u64;
+    let mut directory = HashMap::new();
+    while let Some((op, key, region, data)) = read_record(&file, len, file_size)? {
+        apply(&mut directory, op, &key, region, &data)?;
+        len = region.0 + region.1;
+    }
+    Ok((len, directory))
+}
+
+fn apply(directory: &mut Directory, op: u8, key: &str, region: Region, data: &[u8]) -> Result<(), IoError> {
+    match op {
+        OP_WRITE => {
+            directory.insert(key.to_owned(), non_empty(region).into_iter().collect());
+        }
+        OP_APPEND => {
+            directory.entry(key.to_owned()).or_default().extend(non_empty(region));
+        }
+        OP_DELETE => {
+            directory.remove(key);
+        }
+        OP_RENAME => {
+            let to = std::str::from_utf8(data)
+                .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Invalid key in storage file"))?;
+            let regions = directory.remove(key)
+                .ok_or_else(|| IoError::new(IoErrorKind::NotFound, "Renamed key doesn't exist"))?;
+            directory.insert(to.to_owned(), regions);
+        }
+        OP_TRUNCATE => {
+            directory.insert(key.to_owned(), Vec::new());
+        }
+        _ => return Err(IoError::new(IoErrorKind::InvalidData, "Unknown record in storage file")),
+    }
+    Ok(())
+}
+
+fn non_empty(region: Region) -> Option<Region> {
+    if region.1 > 0 { Some(region) } else { None }
+}
+
+// Frame a record with its length and checksum
+fn encode_record(op: u8, key: &str, data: &[u8]) -> Vec<u8> {
+    let content_len = CONTENT_HEADER_LEN as usize + key.len() + data.len();
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN as usize + content_len);
+    record.write_u64::<BigEndian>(content_len as u64).unwrap();
+    record.write_u32::<BigEndian>(0).unwrap();
+    record.write_u8(op).unwrap();
+    record.write_u32::<BigEndian>(key.len() as u32).unwrap();
+    record.extend_from_slice(key.as_bytes());
+    record.extend_from_slice(data);
+    let checksum = crc32(&record[RECORD_HEADER_LEN as usize..]);
+    record[8..12].copy_from_slice(&checksum.to_be_bytes());
+    record
+}
+
+impl Shared {
+    // Write a record at the end of the file and apply it
+    fn record(&self, op: u8, key: &str, data: &[u8]) -> Result<(), IoError> {
+        let record = encode_record(op, key, data);
+
+        let mut state = self.state.lock().unwrap();
+        let offset = state.len;
+        // Check before writing, so a failed record doesn't change anything
+        if op == OP_RENAME && !state.directory.contains_key(key) {
+            return Err(IoError::new(IoErrorKind::NotFound, "Renamed key doesn't exist"));
+        }
+        state.file.write_all_at(&record, offset)?;
+        state.len += record.len() as u64;
+        let data_offset = offset + (record.len() - data.len()) as u64;
+        apply(&mut state.directory, op, key, (data_offset, data.len() as u64), data)
+    }
+}
+
+// The new file written by `compact_file`, next to the storage file
+fn compact_path(path: &Path) -> PathBuf {
+    let mut compact_path = path.to_owned().into_os_string();
+    compact_path.push(".compact");
+    PathBuf::from(compact_path)
+}
+
+impl SingleFileStorage {
+    /// Open the storage file, creating it if it doesn't exist.
+    ///
+    /// A new file left by an interrupted [`compact_file`](Self::compact_file)
+    /// is deleted.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SingleFileStorage, IoError> {
+        let path = path.as_ref().to_owned();
+        match std::fs::remove_file(compact_path(&path)) {
+            Err(e) if e.kind() == IoErrorKind::NotFound => {}
+            result => result?,
+        }
+        let file = File::options().read(true).write(true).create(true).truncate(false).open(&path)?;
+        let mut file_size = file.metadata()?.len();
+        if file_size == 0 {
+            file.write_all_at(MAGIC, 0)?;
+            file_size = MAGIC.len() as u64;
+        }
+
+        let (len, directory) = load(FileAt(&file), file_size)?;
+        if len < file_size {
+            // Drop the record that was cut short
+            file.set_len(len)?;
+        }
+
+        Ok(SingleFileStorage {
+            shared: Arc::new(Shared {
+                path,
+                state: Mutex::new(State {
+                    file: Arc::new(file),
+                    len,
+                    directory,
+                }),
+            }),
+            append_buffer_size: 0,
+        })
+    }
+
+    /// Buffer appends to the WAL, writing them out in chunks of this size.
+    ///
+    /// Each append adds a record to the file, so buffering also saves space.
+    /// Buffered writes are lost if the process crashes.
+    pub fn with_append_buffer(mut self, size: usize) -> SingleFileStorage {
+        self.append_buffer_size = size;
+        self
+    }
+
+    /// Report how much of the file holds the current content of the keys.
+    pub fn space_report(&self) -> SpaceReport {
+        let state = self.shared.state.lock().unwrap();
+        let used_bytes = state.directory.values()
+            .flat_map(|regions| regions.iter().map(|&(_, len)| len))
+            .sum();
+        SpaceReport {
+            total_bytes: state.len,
+            used_bytes,
+            free_bytes: state.len - used_bytes,
+        }
+    }
+
+    /// Rewrite the file without the space of deleted and overwritten
+    /// content.
+    ///
+    /// The content of each key is written as a single record to a new file,
+    /// which then replaces the file. Other operations wait until this is
+    /// done. Readers opened before keep reading from the old file.
+    pub fn compact_file(&self) -> Result<(), IoError> {
+        let mut state = self.shared.state.lock().unwrap();
+        let temporary_path = compact_path(&self.shared.path);
+        let file = File::options().read(true).write(true).create(true).truncate(true).open(&temporary_path)?;
+        file.write_all_at(MAGIC, 0)?;
+
+        let mut len = MAGIC.len() as u64;
+        let mut directory = HashMap::new();
+        let mut keys: Vec<&String> = state.directory.keys().collect();
+        keys.sort();
+        for key in keys {
+            let mut content = Vec::new();
+            for &(offset, region_len) in &state.directory[key] {
+                let start = content.len();
+                content.resize(start + region_len as usize, 0);
+                state.file.read_exact_at(&mut content[start..], offset)?;
+            }
+            let record = encode_record(OP_WRITE, key, &content);
+            file.write_all_at(&record, len)?;
+            let data_offset = len + (record.len() - content.len()) as u64;
+            directory.insert(key.clone(), non_empty((data_offset, content.len() as u64)).into_iter().collect());
+            len += record.len() as u64;
+        }
+        file.sync_all()?;
+        std::fs::rename(&temporary_path, &self.shared.path)?;
+        // Sync the directory, so that the rename is not lost in a crash
+        let parent = match self.shared.path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        File::open(parent)?.sync_all()?;
+
+        *state = State {
+            file: Arc::new(file),
+            len,
+            directory,
+        };
+        Ok(())
+    }
+
+    /// Read a storage file into a [`MemoryStorage`], leaving the source
+    /// untouched.
+    ///
+    /// The whole content is read from the start, whatever the position of
+    /// `reader`. As when opening the file, a partial record at the end is
+    /// discarded.
+    pub(crate) fn read_into_memory<R: Read + Seek>(mut reader: R) -> Result<MemoryStorage, IoError> {
+        reader.seek(SeekFrom::Start(0))?;
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content)?;
+        let file_size = content.len() as u64;
+        let file = MemoryReader(Arc::new(content));
+        let (_, directory) = load(&file, file_size)?;
+
+        let storage = MemoryStorage::new();
+        for (key, regions) in &directory {
+            let mut value = Vec::new();
+            for &(offset, len) in regions {
+                let start = value.len();
+                value.resize(start + len as usize, 0);
+                file.read_exact_at(&mut value[start..], offset)?;
+            }
+            storage.write(key, &value)?;
+        }
+        Ok(storage)
+    }
+}
+
+// Reads from the storage file while loading it
+struct FileAt<'a>(&'a File);
+
+impl ReadAt for FileAt<'_> {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<(), IoError> {
+        self.0.read_exact_at(buf, offset)
+    }
+
+    fn size(&self) -> Result<u64, IoError> {
+        Ok(self.0.metadata()?.len())
+    }
+}
+
+// Read the record at `offset`, or None if the file ends before the record
+// does, or if the record doesn't match its checksum
+fn read_record<R: ReadAt>(file: R, offset: u64, file_size: u64) -> Result<Option<Record>, IoError> {
+    if offset + RECORD_HEADER_LEN > file_size {
+        return Ok(None);
+    }
+    let mut header = [0u8; RECORD_HEADER_LEN as usize];
+    file.read_exact_at(&mut header, offset)?;
+    let content_len = read_u64(&header[0..8]);
+    let content_offset = offset + RECORD_HEADER_LEN;
+    // A zero-filled header gives an empty content, which also matches its
+    // checksum, so check that the content is long enough for its header
+    if content_len < CONTENT_HEADER_LEN || content_len > file_size - content_offset {
+        return Ok(None);
+    }
+    let mut content = vec![0u8; content_len as usize];
+    file.read_exact_at(&mut content, content_offset)?;
+    if crc32(&content) != read_u32(&header[8..12]) {
+        return Ok(None);
+    }
+
+    let op = content[0];
+    let key_len = read_u32(&content[1..5]) as u64;
+    if CONTENT_HEADER_LEN + key_len > content_len {
+        return Err(IoError::new(IoErrorKind::InvalidData, "Invalid record in storage file"));
+    }
+    let key_end = (CONTENT_HEADER_LEN + key_len) as usize;
+    let key = String::from_utf8(content[CONTENT_HEADER_LEN as usize..key_end].to_vec())
+        .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Invalid key in storage file"))?;
+    let region = (content_offset + key_end as u64, content_len - key_end as u64);
+    let data = if op == OP_RENAME {
+        content.split_off(key_end)
+    } else {
+        Vec::new()
+    };
+    Ok(Some((op, key, region, data)))
+}
+
+pub struct SingleFileReader {
+    file: Arc<File>,
+    regions: Vec<Region>,
+    // Position of each region in the content
+    starts: Vec<u64>,
+    size: u64,
+}
+
+impl ReadAt for SingleFileReader {
+    fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> Result<(), IoError> {
+        if offset + buf.len() as u64 > self.size {
+            return Err(IoError::new(IoErrorKind::UnexpectedEof, "Read past end"));
+        }
+        let mut i = self.starts.partition_point(|&start| start <= offset).saturating_sub(1);
+        while !buf.is_empty() {
+            let (region_offset, region_len) = self.regions[i];
+            let skip = offset - self.starts[i];
+            let len = (region_len - skip).min(buf.len() as u64) as usize;
+            self.file.read_exact_at(&mut buf[..len], region_offset + skip)?;
+            buf = &mut buf[len..];
+            offset += len as u64;
+            i += 1;
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> Result<u64, IoError> {
+        Ok(self.size)
+    }
+}
+
+pub struct SingleFileAppender {
+    shared: Arc<Shared>,
+    key: String,
+}
+
+impl Append for SingleFileAppender {
+    fn append(&mut self, buffer: &[u8]) -> Result<(), IoError> {
+        if buffer.is_empty() {
+            return Ok(());
+        }
+        self.shared.record(OP_APPEND, &self.key, buffer)
+    }
+
+    fn truncate(&mut self) -> Result<(), IoError> {
+        self.shared.record(OP_TRUNCATE, &self.key, &[])
+    }
+
+    fn sync(&mut self) -> Result<(), IoError> {
+        let file = self.shared.state.lock().unwrap().file.clone();
+        file.sync_data()
+    }
+}
+
+impl Storage for SingleFileStorage {
+    type Reader = SingleFileReader;
+    type Appender = BufferedAppender<SingleFileAppender>;
+
+    fn read(&self, key: &str) -> Result<SingleFileReader, IoError> {
+        let state = self.shared.state.lock().unwrap();
+        let regions = state.directory.get(key)
+            .ok_or_else(|| IoError::new(IoErrorKind::NotFound, "Key doesn't exist"))?
+            .clone();
+        let mut starts = Vec::with_capacity(regions.len());
+        let mut size = 0;
+        for &(_, len) in &regions {
+            starts.push(size);
+            size += len;
+        }
+        Ok(SingleFileReader {
+            file: state.file.clone(),
+            regions,
+            starts,
+            size,
+        })
+    }
+
+    fn write(&self, key: &str, value: &[u8]) -> Result<(), IoError> {
+        self.shared.record(OP_WRITE, key, value)
+    }
+
+    fn append(&self, key: &str) -> Result<Self::Appender, IoError> {
+        let exists = self.shared.state.lock().unwrap().directory.contains_key(key);
+        if !exists {
+            self.shared.record(OP_WRITE, key, &[])?;
+        }
+        let appender = SingleFileAppender {
+            shared: self.shared.clone(),
+            key: key.to_owned(),
+        };
+        Ok(BufferedAppender::new(appender, self.append_buffer_size))
+    }
+
+    fn delete(&self, key: &str) -> Result<(), IoError> {
+        let exists = self.shared.state.lock().unwrap().directory.contains_key(key);
+        if exists {
+            self.shared.record(OP_DELETE, key, &[])?;
+        }
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<String>, IoError> {
+        let state = self.shared.state.lock().unwrap();
+        let mut keys: Vec<String> = state.directory.keys().cloned().collect();
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), IoError> {
+        self.shared.record(OP_RENAME, from, to.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+    use tempdir::TempDir;
+
+    use crate::{Append, Database, Error, ReadAt, Storage};
+    use super::SingleFileStorage;
+
+    fn read(storage: &SingleFileStorage, key: &str) -> Vec<u8> {
+        let reader = storage.read(key).unwrap();
+        let mut buf = vec![0u8; reader.size().unwrap() as usize];
+        reader.read_exact_at(&mut buf, 0).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_single_file_storage() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let path = dir.path().join("db");
+
+        let storage = SingleFileStorage::open(&path).unwrap();
+        storage.write("a", b"hello").unwrap();
+        storage.write("b", b"world").unwrap();
+        let mut appender = storage.append("log").unwrap();
+        appender.append(b"abc").unwrap();
+        storage.write("a", b"bye").unwrap();
+        appender.append(b"def").unwrap();
+        appender.append(b"ghi").unwrap();
+        drop(appender);
+        storage.rename("b", "c").unwrap();
+        assert_eq!(storage.rename("b", "c").unwrap_err().kind(), ErrorKind::NotFound);
+        storage.delete("nonexistent").unwrap();
+
+        let check = |storage: &SingleFileStorage| {
+            assert_eq!(storage.list().unwrap(), vec!["a", "c", "log"]);
+            assert_eq!(read(storage, "a"), b"bye");
+            assert_eq!(read(storage, "c"), b"world");
+            assert_eq!(read(storage, "log"), b"abcdefghi");
+            // Reads spanning regions
+            let reader = storage.read("log").unwrap();
+            let mut buf = [0u8; 5];
+            reader.read_exact_at(&mut buf, 2).unwrap();
+            assert_eq!(&buf, b"cdefg");
+            assert_eq!(reader.read_exact_at(&mut buf, 5).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+            assert_eq!(storage.read("b").err().unwrap().kind(), ErrorKind::NotFound);
+        };
+        check(&storage);
+        drop(storage);
+        let storage = SingleFileStorage::open(&path).unwrap();
+        check(&storage);
+
+        storage.append("log").unwrap().truncate().unwrap();
+        storage.delete("a").unwrap();
+        drop(storage);
+        let storage = SingleFileStorage::open(&path).unwrap();
+        assert_eq!(storage.list().unwrap(), vec!["c", "log"]);
+        assert_eq!(read(&storage, "log"), b"");
+        drop(storage);
+
+        // A record cut short is discarded
+        let len = std::fs::metadata(&path).unwrap().len();
+        let storage = SingleFileStorage::open(&path).unwrap();
+        storage.write("d", b"lost").unwrap();
+        drop(storage);
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(std::fs::metadata(&path).unwrap().len() - 1).unwrap();
+        drop(file);
+        let storage = SingleFileStorage::open(&path).unwrap();
+        assert_eq!(storage.list().unwrap(), vec!["c", "log"]);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+        storage.write("d", b"kept").unwrap();
+        drop(storage);
+        let storage = SingleFileStorage::open(&path).unwrap();
+        assert_eq!(read(&storage, "d"), b"kept");
+        drop(storage);
+
+        // So are a zero-filled tail, as left by a crash before the data
+        // reached the disk, and a record whose data doesn't match its
+        // checksum
+        let len = std::fs::metadata(&path).unwrap().len();
+        let storage = SingleFileStorage::open(&path).unwrap();
+        storage.write("e", b"lost").unwrap();
+        drop(storage);
+        let mut data = std::fs::read(&path).unwrap();
+        *data.last_mut().unwrap() ^= 1;
+        std::fs::write(&path, &data).unwrap();
+        let storage = SingleFileStorage::open(&path).unwrap();
+        assert_eq!(storage.list().unwrap(), vec!["c", "d", "log"]);
+        drop(storage);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len + 4096).unwrap();
+        drop(file);
+        let storage = SingleFileStorage::open(&path).unwrap();
+        assert_eq!(storage.list().unwrap(), vec!["c", "d", "log"]);
+        assert_eq!(read(&storage, "d"), b"kept");
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+    }
+
+    #[test]
+    fn test_database_on_single_file() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let path = dir.path().join("db");
+        let open = || Database::open(SingleFileStorage::open(&path).unwrap()).unwrap();
+
+        let mut db = open();
+        for i in 0..100u32 {
+            db.put(format!("key{:02}", i).as_bytes(), format!("value{}", i).as_bytes()).unwrap();
+        }
+        db.maintain().unwrap();
+        db.put(b"key05", b"new").unwrap();
+        db.delete(b"key07").unwrap();
+        db.maintain().unwrap();
+        db.put(b"key10", b"wal").unwrap();
+        db.compact(1).unwrap();
+
+        // Recover from the WAL
+        drop(db);
+        let mut db = open();
+        assert_eq!(db.get(b"key00").unwrap(), Some(b"value0".to_vec()));
+        assert_eq!(db.get(b"key05").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(db.get(b"key07").unwrap(), None);
+        assert_eq!(db.get(b"key10").unwrap(), Some(b"wal".to_vec()));
+        assert_eq!(db.count_range(b"key", b"kez").unwrap(), 99);
+        drop(db);
+
+        // Only the single file exists
+        let files = std::fs::read_dir(dir.path()).unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(files, vec!["db"]);
+        let storage = SingleFileStorage::open(&path).unwrap();
+        assert_eq!(storage.list().unwrap(), vec!["2-0.sst", "wal"]);
+    }
+
+    #[test]
+    fn test_compact_file() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let path = dir.path().join("db");
+        let file_size = || std::fs::metadata(&path).unwrap().len();
+
+        let storage = SingleFileStorage::open(&path).unwrap();
+        for i in 0..10 {
+            storage.write(&format!("key{}", i), &vec![i as u8; 1000]).unwrap();
+        }
+        let mut appender = storage.append("log").unwrap();
+        appender.append(b"abc").unwrap();
+        appender.append(b"def").unwrap();
+        drop(appender);
+        for i in 0..5 {
+            storage.delete(&format!("key{}", i)).unwrap();
+        }
+        storage.write("key5", b"overwritten").unwrap();
+
+        let report = storage.space_report();
+        assert_eq!(report.total_bytes, file_size());
+        assert_eq!(report.used_bytes, 4000 + 11 + 6);
+        assert_eq!(report.free_bytes, report.total_bytes - report.used_bytes);
+        assert!(report.free_bytes > 6000);
+
+        let old_reader = storage.read("key6").unwrap();
+        storage.compact_file().unwrap();
+        let compacted = storage.space_report();
+        assert_eq!(compacted.used_bytes, report.used_bytes);
+        assert!(compacted.free_bytes < 200);
+        assert_eq!(compacted.total_bytes, file_size());
+        assert!(file_size() < report.total_bytes - 6000);
+
+        let check = |storage: &SingleFileStorage| {
+            assert_eq!(storage.list().unwrap(), vec!["key5", "key6", "key7", "key8", "key9", "log"]);
+            assert_eq!(read(storage, "key5"), b"overwritten");
+            for i in 6..10 {
+                assert_eq!(read(storage, &format!("key{}", i)), vec![i as u8; 1000]);
+            }
+            assert_eq!(read(storage, "log"), b"abcdef");
+        };
+        check(&storage);
+        let mut buf = vec![0u8; 1000];
+        old_reader.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, vec![6u8; 1000]);
+
+        // Writes go to the new file
+        storage.append("log").unwrap().append(b"ghi").unwrap();
+        storage.write("key7", b"new").unwrap();
+        drop(storage);
+        let storage = SingleFileStorage::open(&path).unwrap();
+        assert_eq!(read(&storage, "log"), b"abcdefghi");
+        assert_eq!(read(&storage, "key7"), b"new");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        // A new file left by an interrupted compaction is deleted
+        drop(storage);
+        std::fs::write(dir.path().join("db.compact"), b"partial").unwrap();
+        let storage = SingleFileStorage::open(&path).unwrap();
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+        assert_eq!(read(&storage, "key7"), b"new");
+    }
+
+    #[test]
+    fn test_open_file() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let path = dir.path().join("db");
+
+        let mut db = Database::open_file(&path).unwrap();
+        db.put(b"abc", b"111").unwrap();
+        db.put(b"def", b"222").unwrap();
+        db.maintain().unwrap();
+        db.put(b"ghi", b"333").unwrap();
+        db.delete(b"def").unwrap();
+        db.close().unwrap();
+
+        let mut db = Database::open_file(&path).unwrap();
+        assert_eq!(db.get(b"abc").unwrap(), Some(b"111".to_vec()));
+        assert_eq!(db.get(b"def").unwrap(), None);
+        assert_eq!(db.get(b"ghi").unwrap(), Some(b"333".to_vec()));
+        db.put(b"jkl", b"444").unwrap();
+        drop(db);
+
+        // Read-only copy, including the WAL
+        let content = std::fs::read(&path).unwrap();
+        let mut reader = std::io::Cursor::new(content.clone());
+        reader.set_position(10);
+        let mut db = Database::open_reader(&mut reader).unwrap();
+        assert_eq!(db.get(b"abc").unwrap(), Some(b"111".to_vec()));
+        assert_eq!(db.get(b"def").unwrap(), None);
+        assert_eq!(db.get(b"jkl").unwrap(), Some(b"444".to_vec()));
+        db.put(b"mno", b"555").unwrap();
+        db.maintain().unwrap();
+        assert_eq!(db.get(b"mno").unwrap(), Some(b"555".to_vec()));
+        drop(db);
+        assert_eq!(reader.into_inner(), content);
+
+        match Database::open_reader(std::io::Cursor::new(b"not a database".to_vec())) {
+            Err(Error::IoError(e)) => assert_eq!(e.kind(), ErrorKind::InvalidData),
+            _ => panic!("Expected an invalid data error"),
+        }
+    }
+}
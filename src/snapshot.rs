@@ -0,0 +1,265 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use tracing::{info, warn};
+
+use crate::merge::merge_sorted_runs;
+use crate::{
+    parse_sstable_name, parse_wal_record, parse_wal_table_name, read_wal_record, recency, Corruption, Error,
+    Operation, RangeIterator, RangeSource, ReadAt, SSTableId, SSTableReader, Storage, TryRangeIterator,
+};
+
+// Number of times the tables are listed again if one disappears while the
+// snapshot is opened
+const OPEN_ATTEMPTS: usize = 5;
+
+/// Read-only view of the sstables of a database, see
+/// [`Database::open_snapshot_readonly`](crate::Database::open_snapshot_readonly).
+///
+/// Entries that are only in the writer's memtable and WAL are not visible,
+/// nor are tables that the WAL shows the writer hasn't finished.
+/// Since sstables are never modified, the view doesn't change when the
+/// writer adds or compacts tables, as long as the storage keeps deleted
+/// files readable through open readers (as local files do on Unix).
+pub struct Snapshot<S: Storage> {
+    // Ordered from most to least recent
+    sstables: Vec<(SSTableId, SSTableReader<S::Reader>)>,
+}
+
+impl<S: Storage> Snapshot<S> {
+    pub(crate) fn open(storage: S) -> Result<Snapshot<S>, Error> {
+        let mut attempt = 1;
+        loop {
+            match Self::try_open(&storage) {
+                // A compaction deleted a table after it was listed, the
+                // tables replacing it are in a new listing
+                Err(Error::IoError(e)) if e.kind() == IoErrorKind::NotFound && attempt < OPEN_ATTEMPTS => {
+                    info!("Sstable disappeared while opening snapshot, listing again: {}", e);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn try_open(storage: &S) -> Result<Snapshot<S>, Error> {
+        let names = storage.list()?;
+        let unfinished = if names.iter().any(|name| name == "wal") {
+            unfinished_sstables(storage, &names)?
+        } else {
+            HashSet::new()
+        };
+
+        let mut sstables = Vec::new();
+        for name in names {
+            if !name.ends_with(".sst") || unfinished.contains(&name) {
+                continue;
+            }
+            let Ok(id) = parse_sstable_name(&name) else {
+                continue;
+            };
+            let reader = storage.read(&name)?;
+            match SSTableReader::open(reader) {
+                Ok(table) => sstables.push((id, table)),
+                // The writer might be writing it right now
+                Err(e) if e.kind() == IoErrorKind::InvalidData || e.kind() == IoErrorKind::UnexpectedEof => {
+                    warn!("Skipping sstable '{}' in snapshot: {}", name, e);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        sstables.sort_by_key(|&(id, _)| recency(id));
+        info!("Opened read-only snapshot of {} sstables", sstables.len());
+        Ok(Snapshot { sstables })
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, IoError> {
+        for (_, sstable) in &self.sstables {
+            if let Some(value) = sstable.get(key)? {
+                return Ok(value);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Iterate on the entries with keys in `[key_start, key_end)`, in order.
+    ///
+    /// Iteration stops at the first I/O error, see
+    /// [`RangeIterator::take_error`].
+    pub fn iter_range<'a>(&'a self, key_start: &'a [u8], key_end: &'a [u8]) -> RangeIterator<'a, S> {
+        RangeIterator::new(self.try_iter_range(key_start, key_end))
+    }
+
+    /// Iterate on the entries with keys in `[key_start, key_end)`, in order,
+    /// returning I/O errors as items.
+    pub fn try_iter_range<'a>(&'a self, key_start: &'a [u8], key_end: &'a [u8]) -> TryRangeIterator<'a, S> {
+        let runs = self.sstables.iter()
+            .map(|(_, sstable)| RangeSource::SSTable(sstable.iter_range(key_start, Some(key_end))))
+            .collect();
+        TryRangeIterator {
+            merged: merge_sorted_runs(runs, true, true),
+            prefix: None,
+        }
+    }
+}
+
+// The sstables that the WAL shows are being written, reserved, or are the
+// destination of a move that didn't happen yet. `Database::open` would
+// delete or skip them
+fn unfinished_sstables<S: Storage>(storage: &S, names: &[String]) -> Result<HashSet<String>, Error> {
+    let mut unfinished = HashSet::new();
+    // Moves whose end was not logged, by destination
+    let mut pending_moves = HashMap::new();
+    let wal = storage.read("wal")?;
+    let wal_size = wal.size()?;
+    let mut offset = 0;
+    // The writer might be appending a record right now, stop at a partial
+    // one
+    while let Some(content) = read_wal_record(&wal, &mut offset, wal_size)? {
+        let (op, fields) = parse_wal_record(&content).ok_or(Error::Corruption(Corruption::InvalidWalEntry))?;
+        let mut fields = fields.into_iter();
+        match op {
+            Operation::WriteSstableStart | Operation::ReserveSstable => {
+                unfinished.insert(parse_wal_table_name(fields.next().unwrap())?);
+            }
+            Operation::WriteSstableEnd => {
+                let table_name = parse_wal_table_name(fields.next().unwrap())?;
+                pending_moves.remove(&table_name);
+                unfinished.remove(&table_name);
+            }
+            Operation::MoveSstable => {
+                let from = parse_wal_table_name(fields.next().unwrap())?;
+                let to = parse_wal_table_name(fields.next().unwrap())?;
+                unfinished.insert(to.clone());
+                pending_moves.insert(to, from);
+            }
+            Operation::Put | Operation::Delete | Operation::Truncate | Operation::DropPrefix => {}
+        }
+    }
+
+    // If the source of a move is gone, the rename happened and the
+    // destination holds the table
+    for (to, from) in pending_moves {
+        if !names.contains(&from) {
+            unfinished.remove(&to);
+        }
+    }
+    Ok(unfinished)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::io::Error as IoError;
+    use tempdir::TempDir;
+
+    use crate::{Database, DirectoryStorage, Storage};
+
+    #[test]
+    fn test_snapshot_readonly() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let mut writer = Database::open(DirectoryStorage::new(dir.path()).unwrap()).unwrap();
+        writer.put(b"abc", b"111").unwrap();
+        writer.put(b"def", b"222").unwrap();
+        writer.maintain().unwrap();
+        writer.put(b"ghi", b"333").unwrap();
+
+        // Only sees the sstables
+        let snapshot = Database::open_snapshot_readonly(DirectoryStorage::new(dir.path()).unwrap()).unwrap();
+        let expected = vec![(b"abc".to_vec(), b"111".to_vec()), (b"def".to_vec(), b"222".to_vec())];
+        assert_eq!(snapshot.get(b"ghi").unwrap(), None);
+        assert_eq!(snapshot.iter_range(b"", b"z").collect::<Vec<_>>(), expected);
+
+        // The writer adds and compacts tables, the snapshot keeps its view
+        writer.put(b"abc", b"444").unwrap();
+        writer.maintain().unwrap();
+        writer.compact(1).unwrap();
+        writer.put(b"jkl", b"555").unwrap();
+        writer.maintain().unwrap();
+        assert_eq!(writer.get(b"abc").unwrap(), Some(b"444".to_vec()));
+        assert_eq!(snapshot.get(b"abc").unwrap(), Some(b"111".to_vec()));
+        assert_eq!(snapshot.get(b"ghi").unwrap(), None);
+        assert_eq!(snapshot.get(b"jkl").unwrap(), None);
+        assert_eq!(snapshot.iter_range(b"", b"z").collect::<Vec<_>>(), expected);
+
+        // A new snapshot sees the changes
+        let snapshot = Database::open_snapshot_readonly(DirectoryStorage::new(dir.path()).unwrap()).unwrap();
+        assert_eq!(snapshot.get(b"abc").unwrap(), Some(b"444".to_vec()));
+        assert_eq!(snapshot.get(b"jkl").unwrap(), Some(b"555".to_vec()));
+    }
+
+    #[test]
+    fn test_snapshot_unfinished_tables() {
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let mut writer = Database::open(DirectoryStorage::new(dir.path()).unwrap()).unwrap();
+        writer.put(b"abc", b"111").unwrap();
+        writer.maintain().unwrap();
+        drop(writer);
+
+        // A table whose start marker has no end, and a leftover rewrite
+        let table = std::fs::read(dir.path().join("1-0.sst")).unwrap();
+        std::fs::write(dir.path().join("1-1.sst"), &table).unwrap();
+        std::fs::write(dir.path().join("1-0.sst.tmp"), &table).unwrap();
+        std::fs::write(dir.path().join("wal"), crate::tests::wal_record(b"\x02\x00\x00\x00\x071-1.sst")).unwrap();
+
+        let snapshot = Database::open_snapshot_readonly(DirectoryStorage::new(dir.path()).unwrap()).unwrap();
+        assert_eq!(snapshot.sstables.len(), 1);
+        assert_eq!(snapshot.sstables[0].0, (1, 0));
+        assert_eq!(snapshot.get(b"abc").unwrap(), Some(b"111".to_vec()));
+    }
+
+    #[test]
+    fn test_snapshot_table_deleted() {
+        // Storage listing a table that was already deleted, as if a
+        // compaction removed it right after the listing
+        struct StaleList(DirectoryStorage, AtomicUsize);
+
+        impl Storage for StaleList {
+            type Reader = <DirectoryStorage as Storage>::Reader;
+            type Appender = <DirectoryStorage as Storage>::Appender;
+
+            fn read(&self, key: &str) -> Result<Self::Reader, IoError> {
+                self.0.read(key)
+            }
+
+            fn write(&self, key: &str, value: &[u8]) -> Result<(), IoError> {
+                self.0.write(key, value)
+            }
+
+            fn append(&self, key: &str) -> Result<Self::Appender, IoError> {
+                self.0.append(key)
+            }
+
+            fn delete(&self, key: &str) -> Result<(), IoError> {
+                self.0.delete(key)
+            }
+
+            fn list(&self) -> Result<Vec<String>, IoError> {
+                let mut list = self.0.list()?;
+                if self.1.load(Ordering::Relaxed) > 0 {
+                    self.1.fetch_sub(1, Ordering::Relaxed);
+                    list.push("1-5.sst".into());
+                }
+                Ok(list)
+            }
+
+            fn rename(&self, from: &str, to: &str) -> Result<(), IoError> {
+                self.0.rename(from, to)
+            }
+        }
+
+        let dir = TempDir::new("lsmtree-test").unwrap();
+        let mut writer = Database::open(DirectoryStorage::new(dir.path()).unwrap()).unwrap();
+        writer.put(b"abc", b"111").unwrap();
+        writer.maintain().unwrap();
+        drop(writer);
+
+        // The tables are listed again
+        let storage = StaleList(DirectoryStorage::new(dir.path()).unwrap(), AtomicUsize::new(2));
+        let snapshot = Database::open_snapshot_readonly(storage).unwrap();
+        assert_eq!(snapshot.get(b"abc").unwrap(), Some(b"111".to_vec()));
+
+        // Up to a limit
+        let storage = StaleList(DirectoryStorage::new(dir.path()).unwrap(), AtomicUsize::new(100));
+        assert!(Database::open_snapshot_readonly(storage).is_err());
+    }
+}
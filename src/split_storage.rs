@@ -0,0 +1,217 @@
+use std::io::Error as IoError;
+use crate::{Append, ReadAt, Storage, WAL_TEMPORARY_NAME};
+
+const WAL: &str = "wal";
+
+// Keys stored on the WAL backend
+fn is_wal(key: &str) -> bool {
+    key == WAL || key == WAL_TEMPORARY_NAME
+}
+
+/// Storage keeping the WAL and the sstables on separate backends.
+///
+/// This allows putting the WAL, which is written on every change, on faster
+/// storage than the bulk of the data. See [`Database::open_split`](crate::Database::open_split).
+pub struct SplitStorage<W: Storage, D: Storage> {
+    wal: W,
+    data: D,
+}
+
+impl<W: Storage, D: Storage> SplitStorage<W, D> {
+    pub fn new(wal: W, data: D) -> SplitStorage<W, D> {
+        SplitStorage { wal, data }
+    }
+}
+
+pub enum SplitReader<W: ReadAt, D: ReadAt> {
+    Wal(W),
+    Data(D),
+}
+
+impl<W: ReadAt, D: ReadAt> ReadAt for SplitReader<W, D> {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<(), IoError> {
+        match self {
+            SplitReader::Wal(reader) => reader.read_exact_at(buf, offset),
+            SplitReader::Data(reader) => reader.read_exact_at(buf, offset),
+        }
+    }
+
+    fn size(&self) -> Result<u64, IoError> {
+        match self {
+            SplitReader::Wal(reader) => reader.size(),
+            SplitReader::Data(reader) => reader.size(),
+        }
+    }
+}
+
+pub enum SplitAppender<W: Append, D: Append> {
+    Wal(W),
+    Data(D),
+}
+
+impl<W: Append, D: Append> Append for SplitAppender<W, D> {
+    fn append(&mut self, buffer: &[u8]) -> Result<(), IoError> {
+        match self {
+            SplitAppender::Wal(appender) => appender.append(buffer),
+            SplitAppender::Data(appender) => appender.append(buffer),
+        }
+    }
+
+    fn truncate(&mut self) -> Result<(), IoError> {
+        match self {
+            SplitAppender::Wal(appender) => appender.truncate(),
+            SplitAppender::Data(appender) => appender.truncate(),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        match self {
+            SplitAppender::Wal(appender) => appender.flush(),
+            SplitAppender::Data(appender) => appender.flush(),
+        }
+    }
+
+    fn sync(&mut self) -> Result<(), IoError> {
+        match self {
+            SplitAppender::Wal(appender) => appender.sync(),
+            SplitAppender::Data(appender) => appender.sync(),
+        }
+    }
+}
+
+impl<W: Storage, D: Storage> Storage for SplitStorage<W, D> {
+    type Reader = SplitReader<W::Reader, D::Reader>;
+    type Appender = SplitAppender<W::Appender, D::Appender>;
+
+    fn read(&self, key: &str) -> Result<Self::Reader, IoError> {
+        if is_wal(key) {
+            Ok(SplitReader::Wal(self.wal.read(key)?))
+        } else {
+            Ok(SplitReader::Data(self.data.read(key)?))
+        }
+    }
+
+    fn write(&self, key: &str, value: &[u8]) -> Result<(), IoError> {
+        if is_wal(key) {
+            self.wal.write(key, value)
+        } else {
+            self.data.write(key, value)
+        }
+    }
+
+    fn append(&self, key: &str) -> Result<Self::Appender, IoError> {
+        if is_wal(key) {
+            Ok(SplitAppender::Wal(self.wal.append(key)?))
+        } else {
+            Ok(SplitAppender::Data(self.data.append(key)?))
+        }
+    }
+
+    fn delete(&self, key: &str) -> Result<(), IoError> {
+        if is_wal(key) {
+            self.wal.delete(key)
+        } else {
+            self.data.delete(key)
+        }
+    }
+
+    fn list(&self) -> Result<Vec<String>, IoError> {
+        let mut list: Vec<String> = self.wal.list()?.into_iter()
+            .filter(|key| is_wal(key))
+            .collect();
+        list.extend(self.data.list()?.into_iter().filter(|key| !is_wal(key)));
+        Ok(list)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), IoError> {
+        match (is_wal(from), is_wal(to)) {
+            (true, true) => self.wal.rename(from, to),
+            (false, false) => self.data.rename(from, to),
+            _ => Err(IoError::new(std::io::ErrorKind::Unsupported, "Can't rename the WAL across storages")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Error as IoError;
+    use tempdir::TempDir;
+
+    use crate::{Append, Database, DirectoryStorage};
+    use super::SplitAppender;
+
+    #[test]
+    fn test_split_storage() {
+        let wal_dir = TempDir::new("lsmtree-test").unwrap();
+        let data_dir = TempDir::new("lsmtree-test").unwrap();
+        let open = || Database::open_split(
+            DirectoryStorage::new(wal_dir.path()).unwrap(),
+            DirectoryStorage::new(data_dir.path()).unwrap(),
+        ).unwrap();
+        let list = |dir: &TempDir| {
+            let mut files = std::fs::read_dir(dir.path()).unwrap()
+                .map(|e| e.unwrap().file_name().into_string().unwrap())
+                .collect::<Vec<_>>();
+            files.sort();
+            files
+        };
+
+        let mut db = open();
+        db.put(b"abc", b"111").unwrap();
+        db.put(b"def", b"222").unwrap();
+        db.maintain().unwrap();
+        db.put(b"abc", b"333").unwrap();
+        assert_eq!(list(&wal_dir), vec!["wal"]);
+        assert_eq!(list(&data_dir), vec!["1-0.sst"]);
+        assert_eq!(db.get(b"abc").unwrap(), Some(b"333".to_vec()));
+        assert_eq!(db.get(b"def").unwrap(), Some(b"222".to_vec()));
+
+        // Recover from the WAL
+        drop(db);
+        let mut db = open();
+        assert_eq!(db.get(b"abc").unwrap(), Some(b"333".to_vec()));
+        assert_eq!(db.get(b"def").unwrap(), Some(b"222".to_vec()));
+    }
+
+    #[test]
+    fn test_split_appender_sync() {
+        // Appender recording whether it was flushed or synced
+        #[derive(Default)]
+        struct Recorder(Vec<&'static str>);
+
+        impl Append for Recorder {
+            fn append(&mut self, _buffer: &[u8]) -> Result<(), IoError> {
+                Ok(())
+            }
+
+            fn truncate(&mut self) -> Result<(), IoError> {
+                Ok(())
+            }
+
+            fn flush(&mut self) -> Result<(), IoError> {
+                self.0.push("flush");
+                Ok(())
+            }
+
+            fn sync(&mut self) -> Result<(), IoError> {
+                self.0.push("sync");
+                Ok(())
+            }
+        }
+
+        let mut wal: SplitAppender<Recorder, Recorder> = SplitAppender::Wal(Recorder::default());
+        wal.sync().unwrap();
+        wal.flush().unwrap();
+        match wal {
+            SplitAppender::Wal(recorder) => assert_eq!(recorder.0, vec!["sync", "flush"]),
+            SplitAppender::Data(_) => unreachable!(),
+        }
+
+        let mut data: SplitAppender<Recorder, Recorder> = SplitAppender::Data(Recorder::default());
+        data.sync().unwrap();
+        match data {
+            SplitAppender::Data(recorder) => assert_eq!(recorder.0, vec!["sync"]),
+            SplitAppender::Wal(_) => unreachable!(),
+        }
+    }
+}